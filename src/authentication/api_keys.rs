@@ -0,0 +1,95 @@
+//! API keys for bots
+//!
+//! Bot accounts (UserMode::Bot, made by admins) don't log in. Admins give them long lived API keys
+//! instead, which work in place of a JWT on the REST routes (`x-auth-token`) and the socket
+//! handshake until they're revoked. A key is scoped to reading and/or posting, optionally only in
+//! some channels, and can't do anything else (moderation, invites, ...). Like refresh tokens, only
+//! a hash of each key is stored and the key itself is shown once.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::authentication::random::random_token;
+use crate::database::database::ApiKey;
+
+/// every key starts with this, JWTs never do
+const API_KEY_PREFIX: &str = "trc_";
+const API_KEY_LENGTH: usize = 48;
+const KEY_ID_LENGTH: usize = 12;
+/// longest key name, it's only there to tell keys apart
+pub const MAX_KEY_NAME_LENGTH: usize = 64;
+
+/// what a token or API key lets its holder do
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scope {
+    pub channels: Option<Vec<String>>, // None for every channel
+    pub post: bool,
+    pub read: bool,
+}
+impl Scope {
+    /// a JWT, which can do anything its user can
+    pub const FULL: Scope = Scope { channels: None, post: true, read: true };
+
+    /// whether this is a JWT rather than an API key
+    pub fn is_full(&self) -> bool {
+        *self == Self::FULL
+    }
+
+    fn covers(&self, channel: &str) -> bool {
+        self.channels.as_ref().is_none_or(|channels| channels.iter().any(|c| c == channel))
+    }
+
+    pub fn can_post(&self, channel: &str) -> bool {
+        self.post && self.covers(channel)
+    }
+
+    /// `ALL` takes a key that isn't limited to some channels
+    pub fn can_read(&self, channel: &str) -> bool {
+        match channel {
+            "ALL" => self.read && self.channels.is_none(),
+            channel => self.read && self.covers(channel),
+        }
+    }
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(API_KEY_PREFIX)
+}
+
+/// keys are long and random, so a plain (fast) hash is enough
+pub fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// a new key for a bot: the key (to show once) and what's stored of it
+pub fn generate(handle: &str, name: &str, scope: Scope, created_by: &str) -> (String, ApiKey) {
+    let key = format!("{}{}", API_KEY_PREFIX, random_token(API_KEY_LENGTH));
+    let entry = ApiKey {
+        id: random_token(KEY_ID_LENGTH),
+        key_hash: hash(&key),
+        handle: handle.to_string(),
+        name: name.to_string(),
+        scope,
+        created_by: created_by.to_string(),
+        created_at: chrono::Utc::now().timestamp(),
+    };
+
+    (key, entry)
+}
+
+#[test]
+fn test_scope() {
+    let notifier = Scope { channels: Some(vec!["ci".to_string()]), post: true, read: false };
+    assert!(notifier.can_post("ci"));
+    assert!(!notifier.can_post("general"), "keys should be limited to their channels");
+    assert!(!notifier.can_read("ci"), "post only keys can't read");
+
+    let reader = Scope { channels: None, post: false, read: true };
+    assert!(reader.can_read("ALL") && reader.can_read("general"));
+    assert!(!Scope { channels: Some(vec!["ALL".to_string()]), ..reader }.can_read("ALL"), "ALL isn't a channel a key can be limited to");
+
+    let (key, entry) = generate("ci-bot", "notifier", notifier, "admin");
+    assert!(is_api_key(&key) && !is_api_key("eyJhbGciOiJIUzUxMiJ9"));
+    assert_eq!(entry.key_hash, hash(&key));
+    assert!(!entry.key_hash.contains(&key[API_KEY_PREFIX.len()..]), "the key itself shouldn't be stored");
+}
@@ -0,0 +1,67 @@
+//! Bans currently in force
+//!
+//! The Bans table is the source of truth, this keeps a copy in memory so checking a ban on every
+//! authenticated request doesn't cost a query. It's loaded at startup and kept up to date by the
+//! moderation routes.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use log::warn;
+
+use crate::database::database::{Ban, DBCalls};
+
+#[derive(Debug, Clone, Default)]
+pub struct BanList {
+    bans: Arc<RwLock<HashMap<String, Ban>>>,
+}
+impl BanList {
+    pub async fn load(db: &impl DBCalls) -> Self {
+        let list = BanList::default();
+        match db.fetch_bans().await {
+            Ok(bans) => {
+                let mut lock = list.bans.write().unwrap();
+                for ban in bans {
+                    lock.insert(ban.handle.clone(), ban);
+                }
+            },
+            // refusing to start would be worse than letting banned users in until a restart
+            Err(e) => warn!("unable to load bans, nobody is banned until the next restart: {}", e),
+        }
+
+        list
+    }
+
+    /// the user's ban, if they have one that hasn't expired
+    pub fn get(&self, handle: &str) -> Option<Ban> {
+        let ban = self.bans.read().unwrap().get(handle).cloned()?;
+        if ban.is_active() {
+            return Some(ban);
+        }
+
+        self.bans.write().unwrap().remove(handle);
+        None
+    }
+
+    pub fn insert(&self, ban: Ban) {
+        self.bans.write().unwrap().insert(ban.handle.clone(), ban);
+    }
+
+    pub fn remove(&self, handle: &str) {
+        self.bans.write().unwrap().remove(handle);
+    }
+}
+
+#[test]
+fn test_ban_expiry() {
+    let bans = BanList::default();
+    bans.insert(Ban::new("alice", "spam", "admin", None));
+    bans.insert(Ban { expires_at: Some(chrono::Utc::now().timestamp() - 1), ..Ban::new("bob", "", "admin", None) });
+
+    assert!(bans.get("alice").is_some());
+    assert!(bans.get("bob").is_none(), "expired bans shouldn't count");
+    assert!(bans.get("carol").is_none());
+
+    bans.remove("alice");
+    assert!(bans.get("alice").is_none());
+}
@@ -0,0 +1,133 @@
+//! Handle/password verification shared by every transport that lets users log in (the REST API,
+//! the line protocol, ...)
+
+use std::time::Duration;
+
+use log::{info, warn};
+
+use crate::authentication::passwords::Passwords;
+use crate::authentication::throttle::LoginThrottle;
+use crate::authentication::user::{User, UserMode};
+use crate::backend::listener::Peer;
+use crate::database::database::{Ban, DBCalls};
+
+pub const MAX_HANDLE_LENGTH: usize = 32;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// plenty for a passphrase. Passwords set while hashes were bcrypt are at most 72 bytes.
+pub const MAX_PASSWORD_BYTES: usize = 256;
+
+#[derive(Debug, PartialEq)]
+pub enum LoginError {
+    InvalidCredentials, // unknown handle or wrong password, clients can't tell which
+    Throttled(Duration), // too many failures from the ip or for the handle, see throttle.rs
+    SecondFactorRequired, // the password was right, but the user has TOTP and sent no code
+    Banned(Ban), // only after the right password, so bans don't give away who exists
+    Internal, // not the user's fault, details are logged
+}
+
+/// look a user up and compare their password, returning the User on success. Failures count
+/// towards `throttle` for both the handle and `ip`. Outdated hashes are replaced on success.
+pub async fn check_credentials(db: &impl DBCalls, passwords: &Passwords, throttle: &LoginThrottle, ip: &Peer, handle: &str, password: &str) -> Result<User, LoginError> {
+    if let Some(wait) = throttle.check(ip, handle) {
+        return Err(LoginError::Throttled(wait));
+    }
+
+    // find the user on the database. Bots use API keys (see api_keys.rs), so they can't log in, and
+    // neither can users without a password (see ssh_keys.rs) with one.
+    let user_entry = match db.fetch_user(handle).await {
+        Ok(entry) if entry.inner_user.user_type != UserMode::Bot && !entry.password_hash.is_empty() => Some(entry),
+        Ok(_) => None,
+        Err(_) => None,
+    };
+
+    // compare passwords, taking as long for unknown handles
+    let hash = user_entry.as_ref().map(|entry| entry.password_hash.as_str());
+    match passwords.verify(password, hash) {
+        Ok(true) => throttle.succeeded(handle),
+        Ok(false) => {
+            throttle.failed(ip, handle);
+            return Err(LoginError::InvalidCredentials);
+        },
+        Err(e) => {
+            warn!("error verifying password of @{}: {}", handle, e);
+            return Err(LoginError::Internal)
+        },
+    }
+    let user_entry = user_entry.expect("only known users get past the password check");
+
+    // bcrypt, or argon2 with settings that have changed since
+    if passwords.needs_rehash(&user_entry.password_hash) {
+        match passwords.hash(password) {
+            Ok(new_hash) => match db.set_password_hash(handle, &new_hash).await.map_err(|e| e.to_string()) {
+                Ok(()) => info!("rehashed the password of @{}", handle),
+                Err(e) => warn!("error storing the rehashed password of @{}: {}", handle, e),
+            },
+            Err(e) => warn!("error rehashing the password of @{}: {}", handle, e),
+        }
+    }
+
+    unless_banned(db, user_entry.inner_user).await
+}
+
+/// the user, if they aren't banned. For every way of logging in, after the user has proven who
+/// they are, so bans don't give away who exists.
+pub async fn unless_banned(db: &impl DBCalls, user: User) -> Result<User, LoginError> {
+    if !user.banned {
+        return Ok(user);
+    }
+    match db.fetch_ban(&user.handle).await {
+        Ok(Some(ban)) => Err(LoginError::Banned(ban)),
+        // expired between the two queries
+        Ok(None) => Ok(user),
+        Err(e) => {
+            warn!("error fetching ban: {}", e);
+            Err(LoginError::Internal)
+        },
+    }
+}
+
+/// handles are what people are mentioned by (@handle) and what the line protocol and IRC gateway
+/// use as nicks, so keep them to something every transport can show: a letter followed by
+/// letters, digits, `_` or `-`
+pub fn validate_handle(handle: &str) -> Result<(), String> {
+    if handle.len() < 2 || handle.len() > MAX_HANDLE_LENGTH {
+        return Err(format!("handle has to be between 2 and {} characters", MAX_HANDLE_LENGTH));
+    }
+    if !handle.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err("handle has to start with a letter".to_string());
+    }
+    if !handle.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err("handle can only contain letters, digits, `_` and `-`".to_string());
+    }
+
+    Ok(())
+}
+
+pub fn validate_password(password: &str, handle: &str) -> Result<(), String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(format!("password has to be at least {} characters", MIN_PASSWORD_LENGTH));
+    }
+    if password.len() > MAX_PASSWORD_BYTES {
+        return Err(format!("password can be at most {} bytes", MAX_PASSWORD_BYTES));
+    }
+    if password.eq_ignore_ascii_case(handle) {
+        return Err("password cannot be the same as the handle".to_string());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_registration() {
+    assert!(validate_handle("alice_2").is_ok());
+    assert!(validate_handle("a").is_err(), "one letter handles are too short");
+    assert!(validate_handle("2fast").is_err(), "handles should start with a letter");
+    assert!(validate_handle("al ice").is_err(), "spaces would break the line protocol");
+    assert!(validate_handle("alicé").is_err());
+    assert!(validate_handle(&"a".repeat(MAX_HANDLE_LENGTH + 1)).is_err());
+
+    assert!(validate_password("correct horse", "alice").is_ok());
+    assert!(validate_password("short", "alice").is_err());
+    assert!(validate_password("alice_in_chains", "Alice_In_Chains").is_err());
+    assert!(validate_password(&"é".repeat(150), "alice").is_err(), "the limit is in bytes");
+}
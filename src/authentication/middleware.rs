@@ -1,25 +1,102 @@
+use std::fmt;
+
+use log::warn;
+
 use axum::{
-    http::{HeaderMap, StatusCode}
+    http::HeaderMap
 };
-use crate::authentication::{token::validate_token, user::User};
+use crate::authentication::api_keys::{self, Scope};
+use crate::authentication::{token::validate_claims, user::User};
+use crate::backend::server::{ApiError, AppState};
+use crate::database::database::{Ban, DBCalls};
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidToken, // missing, malformed, expired or revoked
+    Banned(Ban),
+}
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::InvalidToken => write!(f, "invalid token"),
+            AuthError::Banned(ban) => write!(f, "you are {}", ban.describe()),
+        }
+    }
+}
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::InvalidToken => ApiError::Unauthorized,
+            AuthError::Banned(_) => ApiError::Forbidden(e.to_string()),
+        }
+    }
+}
 
+fn header_token(headers: &HeaderMap) -> Result<&str, AuthError> {
+    let result = match headers.get("x-auth-token") {
+        Some(token) => token.to_str(),
+        None => return Err(AuthError::InvalidToken),
+    };
+    match result {
+        Ok(token) => Ok(token),
+        Err(_) => Err(AuthError::InvalidToken),
+    }
+}
 
 /// (fake) middleware for authenticating clients on the API
 /// TODO: figure out how on earth the axum middleware api is *supposed* to work
-pub async fn authenticate(headers: HeaderMap) -> Result<User, StatusCode> {
-    let token = {
-        let result = match headers.get("x-auth-token") {
-            Some(token) => token.to_str(),
-            None => return Err(StatusCode::UNAUTHORIZED),
-        };
-        match result {
-            Ok(token) => token,
-            Err(_) => return Err(StatusCode::UNAUTHORIZED),
-        }
+pub async fn authenticate(state: &AppState, headers: HeaderMap) -> Result<User, AuthError> {
+    authenticate_token(state, header_token(&headers)?).await
+}
+
+/// authenticate(), also taking a bot's API key in place of a JWT. Only for routes that check the
+/// returned scope.
+pub async fn authenticate_scoped(state: &AppState, headers: HeaderMap) -> Result<(User, Scope, Option<String>), AuthError> {
+    authenticate_scoped_token(state, header_token(&headers)?).await
+}
+
+/// check a token and that its user isn't banned, for every transport that takes tokens. The user
+/// is looked up as they are now, not as they were when the token was issued.
+pub async fn authenticate_token(state: &AppState, token: &str) -> Result<User, AuthError> {
+    authenticate_session_token(state, token).await.map(|(user, _)| user)
+}
+
+/// authenticate_token(), also returning the session (see session.rs) the token belongs to
+pub async fn authenticate_session_token(state: &AppState, token: &str) -> Result<(User, String), AuthError> {
+    let claims = validate_claims(token).map_err(|_| AuthError::InvalidToken)?;
+    let cached = state.users.get(&state.db, &claims.sub).await.ok_or(AuthError::InvalidToken)?;
+    // the user's tokens were revoked since this one was issued
+    if cached.token_version != claims.ver {
+        return Err(AuthError::InvalidToken);
+    }
+    check_ban(state, cached.user).map(|user| (user, claims.sid))
+}
+
+/// authenticate_session_token(), also taking a bot's API key. JWTs get Scope::FULL, API keys have
+/// no session.
+pub async fn authenticate_scoped_token(state: &AppState, token: &str) -> Result<(User, Scope, Option<String>), AuthError> {
+    if !api_keys::is_api_key(token) {
+        return authenticate_session_token(state, token).await.map(|(user, session)| (user, Scope::FULL, Some(session)));
+    }
+
+    // stringify the error right away, boxed errors aren't Send
+    let key = match state.db.fetch_api_key(&api_keys::hash(token)).await.map_err(|e| e.to_string()) {
+        Ok(Some(key)) => key,
+        Ok(None) => return Err(AuthError::InvalidToken),
+        Err(e) => {
+            warn!("error fetching api key: {}", e);
+            return Err(AuthError::InvalidToken);
+        },
     };
+    let cached = state.users.get(&state.db, &key.handle).await.ok_or(AuthError::InvalidToken)?;
+
+    check_ban(state, cached.user).map(|user| (user, key.scope, None))
+}
 
-    match validate_token(token.to_string()) {
-        Ok(user_obj) => return Ok(user_obj),
-        Err(_) => return Err(StatusCode::UNAUTHORIZED),
+/// the token may be older than the ban
+fn check_ban(state: &AppState, user: User) -> Result<User, AuthError> {
+    match state.bans.get(&user.handle) {
+        Some(ban) => Err(AuthError::Banned(ban)),
+        None => Ok(user),
     }
 }
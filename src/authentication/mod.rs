@@ -2,3 +2,15 @@ pub mod token;
 pub mod user;
 pub mod middleware;
 pub mod routes;
+pub mod credentials;
+pub mod random;
+pub mod session;
+pub mod bans;
+pub mod users;
+pub mod api_keys;
+pub mod passwords;
+pub mod signing_keys;
+pub mod oidc;
+pub mod ssh_keys;
+pub mod throttle;
+pub mod two_factor;
@@ -0,0 +1,538 @@
+//! Logging in with OpenID Connect
+//!
+//! With `oidc.issuer` set, users can log in through the issuer (a company SSO, Keycloak, Google...)
+//! with the authorization code flow and PKCE. `/api/login/oidc` sends the browser to the issuer,
+//! which sends it back to `oidc.redirect_uri` with a code and the `state` it was given, and
+//! `/api/login/oidc/callback` swaps the code for an ID token and starts a session like a password
+//! would. The issuer's endpoints and keys come from its discovery document.
+//!
+//! Accounts are tied to the issuer's `sub` for someone. Users who already have an account log in to
+//! it some other way and link it at `/api/me/oidc`, which goes through the issuer the same way.
+//! Someone without a link gets a new account (without a password) whose handle is the value of the
+//! `oidc.claim` claim if `oidc.provision` is on. Claims like `preferred_username` can often be
+//! edited by the users themselves, so they only pick the handle of a new account: an existing one
+//! is only taken over by its claim with `oidc.link_existing`, and never for moderators and admins.
+//! Once tied, renaming someone at the issuer doesn't move them to another account.
+//!
+//! Starting a login also sets the `trcd_oidc` cookie (a hash of `state`) in the browser, and the
+//! callback only works with it. Otherwise someone could start a login of their own and get another
+//! person's browser to finish it, logging them in to the wrong account. Linking additionally has
+//! to be finished by the user who started it, with their access token.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::RootCertStore;
+use url::Url;
+
+use crate::authentication::credentials::validate_handle;
+use crate::authentication::random::random_token;
+use crate::authentication::user::{User, UserMode, UserPermissions};
+use crate::backend::http_client::HttpClient;
+use crate::config::OidcConfig;
+use crate::database::database::{DBCalls, HandleTaken, UserDBEntry};
+
+pub const OIDC_SCOPES: &str = "openid profile email"; // default, see config.rs
+pub const OIDC_CLAIM: &str = "preferred_username"; // default, see config.rs
+pub const OIDC_CA_FILE: &str = "/etc/ssl/certs/ca-certificates.crt"; // default, see config.rs
+/// how long the browser can take at the issuer before the login has to start over
+const AUTHORIZATION_LIFETIME: Duration = Duration::from_secs(10 * 60);
+const STATE_LENGTH: usize = 32;
+/// ties a login to the browser that started it, see the top of this file
+pub const OIDC_COOKIE: &str = "trcd_oidc";
+/// PKCE verifiers are 43 to 128 characters
+const VERIFIER_LENGTH: usize = 64;
+/// how long the discovery document and keys are used before they're fetched again. Keys are also
+/// fetched again when a token names one that isn't known yet.
+const DISCOVERY_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// what ID tokens may be signed with. Never HMAC, the client secret isn't meant to be a key.
+const ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256, Algorithm::RS384, Algorithm::RS512,
+    Algorithm::PS256, Algorithm::PS384, Algorithm::PS512,
+    Algorithm::ES256, Algorithm::ES384, Algorithm::EdDSA,
+];
+/// longest display name taken from the `name` claim, like registering
+const MAX_USERNAME_LENGTH: usize = 64;
+
+#[derive(Debug, PartialEq)]
+pub enum OidcError {
+    UnknownState, // unknown, expired or already used `state`, the login has to start over
+    OtherBrowser, // the callback came without the cookie of the browser that started the login
+    Issuer, // the issuer couldn't be reached or answered something invalid, details are logged
+    Rejected(String), // the issuer's user can't log in, says why
+    Internal, // not the user's fault, details are logged
+}
+
+/// the parts of the issuer's discovery document (`/.well-known/openid-configuration`) that are used
+#[derive(Debug, Clone, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: Url,
+    token_endpoint: Url,
+    userinfo_endpoint: Option<Url>,
+    jwks_uri: Url,
+    #[serde(default)]
+    token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+    access_token: Option<String>,
+}
+
+struct Provider {
+    discovery: Discovery,
+    keys: JwkSet,
+    fetched: Instant,
+}
+
+/// a browser that was sent to the issuer and hasn't come back yet
+struct Authorization {
+    verifier: String, // PKCE, only its hash went to the issuer
+    nonce: String, // has to come back in the ID token
+    link: Option<String>, // the logged in user who started it to link their account, see link()
+    expires: Instant,
+}
+
+#[derive(Clone)]
+pub struct Oidc {
+    config: Arc<OidcConfig>,
+    issuer: String,
+    http: HttpClient,
+    provider: Arc<tokio::sync::Mutex<Option<Provider>>>,
+    pending: Arc<Mutex<HashMap<String, Authorization>>>,
+}
+impl fmt::Debug for Oidc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Oidc").field("issuer", &self.issuer).finish_non_exhaustive()
+    }
+}
+impl Oidc {
+    /// None unless `oidc.issuer` is set
+    pub fn new(config: &OidcConfig) -> Result<Option<Self>, String> {
+        let Some(issuer) = &config.issuer else {
+            return Ok(None);
+        };
+        // an issuer on localhost over http doesn't need any
+        let http = match issuer.starts_with("https:") {
+            true => HttpClient::new(&config.ca_file)?,
+            false => HttpClient::with_roots(RootCertStore::empty()),
+        };
+
+        Ok(Some(Self::with_client(config, http)))
+    }
+
+    fn with_client(config: &OidcConfig, http: HttpClient) -> Self {
+        Oidc {
+            config: Arc::new(config.clone()),
+            issuer: config.issuer.clone().unwrap_or_default(),
+            http,
+            provider: Arc::default(),
+            pending: Arc::default(),
+        }
+    }
+
+    /// the discovery document and keys, fetched again when they're old or `refresh` is set
+    async fn provider(&self, refresh: bool) -> Result<(Discovery, JwkSet), OidcError> {
+        let mut provider = self.provider.lock().await;
+        if let Some(cached) = provider.as_ref() && !refresh && cached.fetched.elapsed() < DISCOVERY_LIFETIME {
+            return Ok((cached.discovery.clone(), cached.keys.clone()));
+        }
+
+        let fetched = self.discover().await.map_err(|e| {
+            warn!("unable to fetch the OpenID Connect configuration of {}: {}", self.issuer, e);
+            OidcError::Issuer
+        })?;
+        let result = (fetched.discovery.clone(), fetched.keys.clone());
+        *provider = Some(fetched);
+
+        Ok(result)
+    }
+
+    async fn discover(&self) -> Result<Provider, String> {
+        let url = Url::parse(&format!("{}/.well-known/openid-configuration", self.issuer.trim_end_matches('/')))
+            .map_err(|e| e.to_string())?;
+        let discovery: Discovery = self.http.get(&url, None).await?.json()?;
+        // OpenID Connect Discovery 1.0, 4.3
+        if discovery.issuer != self.issuer {
+            return Err(format!("it names itself {}, set `oidc.issuer` to exactly that", discovery.issuer));
+        }
+        let keys: JwkSet = self.http.get(&discovery.jwks_uri, None).await?.json()?;
+
+        Ok(Provider { discovery, keys, fetched: Instant::now() })
+    }
+
+    /// where to send the browser to log in, or to link the account `link` (whose user has to be
+    /// logged in already), and the `Set-Cookie` value the browser has to come back with
+    pub async fn authorization_url(&self, link: Option<&str>) -> Result<(Url, String), OidcError> {
+        let (discovery, _) = self.provider(false).await?;
+        let state = random_token(STATE_LENGTH);
+        let nonce = random_token(STATE_LENGTH);
+        let verifier = random_token(VERIFIER_LENGTH);
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        let mut url = discovery.authorization_endpoint;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        // only sent over https if the browser comes back over https
+        let secure = if self.config.redirect_uri.starts_with("https:") {"; Secure"} else {""};
+        let cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            OIDC_COOKIE, browser_binding(&state), AUTHORIZATION_LIFETIME.as_secs(), secure
+        );
+
+        let mut pending = self.pending.lock().unwrap();
+        let now = Instant::now();
+        pending.retain(|_, authorization| authorization.expires > now);
+        let link = link.map(str::to_string);
+        pending.insert(state, Authorization { verifier, nonce, link, expires: now + AUTHORIZATION_LIFETIME });
+
+        Ok((url, cookie))
+    }
+
+    /// finish a login (or linking) the issuer sent back with `code`, returning the handle of the
+    /// account. `cookie` is the browser's `trcd_oidc` cookie, `caller` whoever's access token came
+    /// along, if any.
+    pub async fn login(&self, db: &impl DBCalls, state: &str, code: &str, cookie: Option<&str>, caller: Option<&str>) -> Result<String, OidcError> {
+        // a failed attempt burns the state too, so it can't be tried again from elsewhere
+        let authorization = self.pending.lock().unwrap().remove(state)
+            .filter(|authorization| authorization.expires > Instant::now())
+            .ok_or(OidcError::UnknownState)?;
+        if cookie != Some(browser_binding(state).as_str()) {
+            return Err(OidcError::OtherBrowser);
+        }
+        if let Some(link) = &authorization.link && caller != Some(link.as_str()) {
+            return Err(OidcError::Rejected(format!("linking has to be finished logged in as @{}, who started it", link)));
+        }
+
+        let (discovery, mut keys) = self.provider(false).await?;
+        let tokens = self.exchange(&discovery, code, &authorization.verifier).await.map_err(|e| {
+            warn!("OpenID Connect token request to {} failed: {}", discovery.token_endpoint, e);
+            OidcError::Issuer
+        })?;
+
+        // the issuer may have rotated its keys since they were fetched
+        let header = jsonwebtoken::decode_header(&tokens.id_token).map_err(|_| OidcError::Issuer)?;
+        if find_key(&keys, header.kid.as_deref()).is_none() {
+            keys = self.provider(true).await?.1;
+        }
+        let claims = self.verify_id_token(&tokens.id_token, &keys, &authorization.nonce).map_err(|e| {
+            warn!("invalid ID token from {}: {}", self.issuer, e);
+            OidcError::Issuer
+        })?;
+        let subject = claims.get("sub").and_then(Value::as_str).unwrap_or_default().to_string();
+        if let Some(handle) = &authorization.link {
+            return self.link(db, &subject, handle).await;
+        }
+
+        let claimed = match claims.get(&self.config.claim).and_then(Value::as_str) {
+            Some(value) => Some(value.to_string()),
+            None => self.userinfo_claim(&discovery, tokens.access_token.as_deref(), &subject).await,
+        };
+        let name = claims.get("name").and_then(Value::as_str);
+
+        self.account(db, &subject, claimed.as_deref(), name).await
+    }
+
+    /// swap the code for tokens, authenticating the way the issuer supports if there's a secret
+    async fn exchange(&self, discovery: &Discovery, code: &str, verifier: &str) -> Result<TokenResponse, String> {
+        let methods = &discovery.token_endpoint_auth_methods_supported;
+        // client_secret_basic is the default when the issuer doesn't say
+        let post_secret = !methods.is_empty()
+            && !methods.iter().any(|method| method == "client_secret_basic")
+            && methods.iter().any(|method| method == "client_secret_post");
+        let secret = self.config.client_secret.as_deref();
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", verifier),
+        ];
+        let basic = match secret {
+            Some(secret) if post_secret => {
+                form.push(("client_secret", secret));
+                None
+            },
+            Some(secret) => Some((self.config.client_id.as_str(), secret)),
+            None => None,
+        };
+
+        self.http.post_form(&discovery.token_endpoint, &form, basic).await?.json()
+    }
+
+    fn verify_id_token(&self, token: &str, keys: &JwkSet, nonce: &str) -> Result<Map<String, Value>, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(format!("signed with {:?}, which isn't accepted", header.alg));
+        }
+        let jwk = find_key(keys, header.kid.as_deref()).ok_or("signed with a key that isn't in the issuer's key set")?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims: Map<String, Value> = jsonwebtoken::decode(token, &key, &validation).map_err(|e| e.to_string())?.claims;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err("the nonce doesn't match".to_string());
+        }
+        // OpenID Connect Core 1.0, 3.1.3.7: a token for several clients has to say it's for this one
+        if let Some(azp) = claims.get("azp") && azp.as_str() != Some(self.config.client_id.as_str()) {
+            return Err("issued to another client (azp)".to_string());
+        }
+
+        Ok(claims)
+    }
+
+    /// the claim from the userinfo endpoint, for issuers that leave it out of the ID token
+    async fn userinfo_claim(&self, discovery: &Discovery, access_token: Option<&str>, subject: &str) -> Option<String> {
+        let (endpoint, access_token) = (discovery.userinfo_endpoint.as_ref()?, access_token?);
+        let userinfo: Map<String, Value> = match self.http.get(endpoint, Some(access_token)).await.and_then(|response| response.json()) {
+            Ok(userinfo) => userinfo,
+            Err(e) => {
+                warn!("OpenID Connect userinfo request to {} failed: {}", endpoint, e);
+                return None;
+            },
+        };
+        // OpenID Connect Core 1.0, 5.3.2: it has to be about the same user
+        if userinfo.get("sub").and_then(Value::as_str) != Some(subject) {
+            warn!("userinfo from {} is about another user", self.issuer);
+            return None;
+        }
+
+        userinfo.get(&self.config.claim).and_then(Value::as_str).map(str::to_string)
+    }
+
+    /// the account the issuer's user logs in as: the one tied to them, or a new one named by their
+    /// claim (or an existing one with `oidc.link_existing`)
+    async fn account(&self, db: &impl DBCalls, subject: &str, claimed: Option<&str>, name: Option<&str>) -> Result<String, OidcError> {
+        // stringify errors right away, boxed errors aren't Send
+        match db.fetch_oidc_link(&self.issuer, subject).await.map_err(|e| e.to_string()) {
+            Ok(Some(handle)) => return Ok(handle),
+            Ok(None) => {},
+            Err(e) => {
+                warn!("error fetching OpenID Connect link: {}", e);
+                return Err(OidcError::Internal);
+            },
+        }
+
+        let Some(handle) = claimed else {
+            return Err(OidcError::Rejected(format!("the identity provider didn't say what your handle is (the `{}` claim)", self.config.claim)));
+        };
+        validate_handle(handle).map_err(|e| OidcError::Rejected(format!("your `{}` ({}) can't be a handle: {}", self.config.claim, handle, e)))?;
+
+        // anyone may be able to set the claim to any handle at the issuer, so it doesn't prove the
+        // account is theirs
+        let link_first = || OidcError::Rejected(format!("@{} already exists, log in to it and link it at /api/me/oidc", handle));
+        match db.fetch_user(handle).await.map(|entry| entry.inner_user).ok() {
+            Some(user) if user.user_type == UserMode::Bot => return Err(OidcError::Rejected(format!("@{} is a bot", handle))),
+            Some(_) if !self.config.link_existing => return Err(link_first()),
+            Some(user) if user.permission_level >= UserPermissions::Moderator => return Err(link_first()),
+            Some(_) => {},
+            None if self.config.provision => self.provision(db, handle, name).await?,
+            None => return Err(OidcError::Rejected(format!("there's no account for @{}, ask an admin for one", handle))),
+        }
+
+        self.tie(db, subject, handle).await
+    }
+
+    /// tie the issuer's user to the account of a logged in user who asked for it
+    async fn link(&self, db: &impl DBCalls, subject: &str, handle: &str) -> Result<String, OidcError> {
+        match db.fetch_oidc_link(&self.issuer, subject).await.map_err(|e| e.to_string()) {
+            Ok(Some(linked)) if linked == handle => Ok(linked),
+            Ok(Some(linked)) => Err(OidcError::Rejected(format!("that account at the identity provider is already linked to @{}", linked))),
+            Ok(None) => self.tie(db, subject, handle).await,
+            Err(e) => {
+                warn!("error fetching OpenID Connect link: {}", e);
+                Err(OidcError::Internal)
+            },
+        }
+    }
+
+    async fn tie(&self, db: &impl DBCalls, subject: &str, handle: &str) -> Result<String, OidcError> {
+        match db.add_oidc_link(&self.issuer, subject, handle).await.map_err(|e| e.to_string()) {
+            Ok(true) => {
+                info!("@{} is now tied to {} at {}", handle, subject, self.issuer);
+                Ok(handle.to_string())
+            },
+            Ok(false) => Err(OidcError::Rejected(format!("@{} belongs to someone else at the identity provider", handle))),
+            Err(e) => {
+                warn!("error adding OpenID Connect link: {}", e);
+                Err(OidcError::Internal)
+            },
+        }
+    }
+
+    /// a new account without a password, so it can only log in here (until its user sets one)
+    async fn provision(&self, db: &impl DBCalls, handle: &str, name: Option<&str>) -> Result<(), OidcError> {
+        let username = name.map(str::trim)
+            .filter(|name| !name.is_empty() && name.chars().count() <= MAX_USERNAME_LENGTH && !name.chars().any(char::is_control))
+            .unwrap_or(handle);
+        let entry = UserDBEntry {
+            password_hash: String::new(), // never matches, see check_credentials()
+            username: handle.to_string(),
+            inner_user: User {
+                user_type: UserMode::User,
+                handle: handle.to_string(),
+                username: username.to_string(),
+                permission_level: UserPermissions::User,
+                banned: false,
+                provider_site: None,
+            },
+            token_version: 0,
+        };
+
+        match db.add_user(entry).await.map_err(|e| (e.is::<HandleTaken>(), e.to_string())) {
+            Ok(_) => {
+                info!("@{} was created by logging in through {}", handle, self.issuer);
+                Ok(())
+            },
+            // registered (or provisioned for someone else) in the meantime, it isn't theirs
+            Err((true, _)) => Err(OidcError::Rejected(format!("@{} was just taken, try again", handle))),
+            Err((false, e)) => {
+                warn!("error provisioning user: {}", e);
+                Err(OidcError::Internal)
+            },
+        }
+    }
+}
+
+/// the value of the `trcd_oidc` cookie for a login
+fn browser_binding(state: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(state.as_bytes()))
+}
+
+/// the key a token's `kid` names, or the only key if it doesn't name one
+fn find_key<'a>(keys: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }
+}
+
+#[tokio::test]
+async fn test_oidc_login() {
+    use axum::{Form, Json, Router, extract::State, routing::{get, post}};
+    use serde_json::json;
+    use crate::authentication::signing_keys::{Keyring, SigningAlgorithm};
+    use crate::config::{AuthConfig, SigningKeyConfig};
+    use crate::database::{test_db, test_user};
+
+    // a mock issuer: it hands out whatever claims the test queued for a code, if the PKCE verifier
+    // matches the challenge the browser brought along
+    type Codes = Arc<Mutex<HashMap<String, (String, Value)>>>;
+    let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let file = std::env::temp_dir().join(format!("trcd-test-oidc-{}", std::process::id()));
+    std::fs::write(&file, key.serialize_pem()).unwrap();
+    let signing = SigningKeyConfig { id: "issuer".to_string(), algorithm: SigningAlgorithm::ES256, file: file.clone() };
+    let keyring = Arc::new(Keyring::load(&AuthConfig { signing_keys: vec![signing], ..AuthConfig::default() }).unwrap());
+    std::fs::remove_file(file).unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let codes = Codes::default();
+    let discovery = json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+        "jwks_uri": format!("{}/jwks", issuer),
+    });
+    let jwks = keyring.jwks();
+    let token = async |State((codes, keyring)): State<(Codes, Arc<Keyring>)>, Form(form): Form<HashMap<String, String>>| {
+        let (challenge, claims) = codes.lock().unwrap().remove(&form["code"]).ok_or(axum::http::StatusCode::BAD_REQUEST)?;
+        if URL_SAFE_NO_PAD.encode(Sha256::digest(form["code_verifier"].as_bytes())) != challenge {
+            return Err(axum::http::StatusCode::BAD_REQUEST);
+        }
+        Ok(Json(json!({"id_token": keyring.sign(&claims).unwrap(), "token_type": "Bearer"})))
+    };
+    let app = Router::new()
+        .route("/.well-known/openid-configuration", get(async move || Json(discovery)))
+        .route("/jwks", get(async move || Json(jwks)))
+        .route("/token", post(token))
+        .with_state((codes.clone(), keyring));
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let db = test_db().await;
+    let config = OidcConfig { issuer: Some(issuer.clone()), client_id: "trcd".to_string(), redirect_uri: "https://chat.example/callback".to_string(), ..OidcConfig::default() };
+    let oidc = Oidc::with_client(&config, HttpClient::with_roots(RootCertStore::empty()));
+
+    // the browser goes to the issuer and comes back with a code for these claims, and the cookie
+    // (unless it's someone else's) and token of whoever is linking their account
+    let authorize = async |oidc: &Oidc, link: Option<&str>, subject: &str, handle: &str| {
+        let (url, cookie) = oidc.authorization_url(link).await.unwrap();
+        assert!(cookie.contains("HttpOnly") && cookie.contains("SameSite=Lax"));
+        let cookie = cookie.split(';').next().unwrap().strip_prefix("trcd_oidc=").unwrap().to_string();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["code_challenge_method"], "S256");
+        let claims = json!({
+            "iss": issuer, "aud": "trcd", "sub": subject, "nonce": query["nonce"],
+            "exp": chrono::Utc::now().timestamp() + 60, "preferred_username": handle, "name": "Carol C.",
+        });
+        let code = random_token(STATE_LENGTH);
+        codes.lock().unwrap().insert(code.clone(), (query["code_challenge"].clone(), claims));
+        (query["state"].clone(), code, cookie)
+    };
+    let login = async |oidc: &Oidc, link: Option<&str>, subject: &str, handle: &str| {
+        let (state, code, cookie) = authorize(oidc, link, subject, handle).await;
+        (oidc.login(&db, &state, &code, Some(&cookie), link).await, state)
+    };
+    let rejected = |result: Result<String, OidcError>| matches!(result, Err(OidcError::Rejected(_)));
+
+    let (refused, _) = login(&oidc, None, "1", "carol").await;
+    assert!(rejected(refused), "unknown handles need provisioning");
+
+    db.add_user(test_user("carol", UserPermissions::User)).await.unwrap();
+    let (claimed, _) = login(&oidc, None, "2", "carol").await;
+    assert!(rejected(claimed), "a claim naming an account shouldn't be enough to take it over");
+    let (linked, state) = login(&oidc, Some("carol"), "1", "someone").await;
+    assert_eq!(linked, Ok("carol".to_string()), "a logged in user should be able to link their account");
+    assert_eq!(oidc.login(&db, &state, "replayed", None, None).await, Err(OidcError::UnknownState));
+
+    // a callback URL sent to someone else's browser, or a link finished without being logged in
+    let (state, code, cookie) = authorize(&oidc, None, "1", "carol").await;
+    assert_eq!(oidc.login(&db, &state, &code, None, None).await, Err(OidcError::OtherBrowser), "the callback needs the cookie");
+    assert_eq!(oidc.login(&db, &state, &code, Some(&cookie), None).await, Err(OidcError::UnknownState), "a failed callback should burn the state");
+    let (state, code, _) = authorize(&oidc, None, "1", "carol").await;
+    let (_, _, other) = authorize(&oidc, None, "1", "carol").await;
+    assert_eq!(oidc.login(&db, &state, &code, Some(&other), None).await, Err(OidcError::OtherBrowser), "the cookie should be for this login");
+    let (state, code, cookie) = authorize(&oidc, Some("carol"), "1", "someone").await;
+    assert!(rejected(oidc.login(&db, &state, &code, Some(&cookie), None).await), "linking needs the linking user's token");
+    let (renamed, _) = login(&oidc, None, "1", "carol2").await;
+    assert_eq!(renamed, Ok("carol".to_string()), "the link should win over the claim");
+    let (relinked, _) = login(&oidc, Some("carol"), "2", "carol").await;
+    assert!(rejected(relinked), "an account should only be tied to one user at the issuer");
+
+    let provisioning = Oidc::with_client(&OidcConfig { provision: true, link_existing: true, ..config }, HttpClient::with_roots(RootCertStore::empty()));
+    let (created, _) = login(&provisioning, None, "3", "dave").await;
+    assert_eq!(created, Ok("dave".to_string()));
+    let dave = db.fetch_user("dave").await.unwrap();
+    assert_eq!((dave.inner_user.username.as_str(), dave.password_hash.as_str()), ("Carol C.", ""));
+
+    db.add_user(test_user("erin", UserPermissions::User)).await.unwrap();
+    db.add_user(test_user("root", UserPermissions::Admin)).await.unwrap();
+    assert_eq!(login(&provisioning, None, "4", "erin").await.0, Ok("erin".to_string()), "link_existing should trust the claim");
+    assert!(rejected(login(&provisioning, None, "5", "root").await.0), "staff accounts should never be taken by their claim");
+}
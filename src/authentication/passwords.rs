@@ -0,0 +1,135 @@
+//! Password hashing
+//!
+//! New passwords are hashed with argon2id, with the cost set by `auth.password_memory_kib`,
+//! `auth.password_iterations` and `auth.password_parallelism`. Older accounts have bcrypt hashes,
+//! which still verify. Whenever a password is verified against a hash that's bcrypt (told apart by
+//! the `$2` prefix) or argon2 with other parameters than configured, it's rehashed, so raising the
+//! cost or moving off bcrypt never needs a password reset.
+//!
+//! Users change their own password with the old one. When they've lost it, an admin (or the
+//! console, `trcd reset-password`) resets it: the old password stops working, every session is
+//! logged out, and the user gets a one-time code to set a new password with.
+
+use std::sync::Arc;
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+
+use crate::authentication::random::random_token;
+use crate::authentication::user::UserMode;
+use crate::authentication::{api_keys, session};
+use crate::config::AuthConfig;
+use crate::database::database::DBCalls;
+
+pub const PASSWORD_MEMORY_KIB: u32 = 19 * 1024; // default, see config.rs
+pub const PASSWORD_ITERATIONS: u32 = 2; // default, see config.rs
+pub const PASSWORD_PARALLELISM: u32 = 1; // default, see config.rs
+pub const RESET_CODE_LIFETIME_HOURS: i64 = 24;
+const RESET_CODE_LENGTH: usize = 24;
+
+#[derive(Debug, Clone)]
+pub struct Passwords {
+    params: Params,
+    /// compared against when there's no password to compare, so unknown handles take as long as
+    /// wrong passwords
+    dummy_hash: Arc<String>,
+}
+impl Passwords {
+    pub fn new(auth: &AuthConfig) -> Self {
+        let params = Params::new(auth.password_memory_kib, auth.password_iterations, auth.password_parallelism, None)
+            .expect("checked with the rest of the config");
+        let mut passwords = Passwords { params, dummy_hash: Arc::default() };
+        passwords.dummy_hash = Arc::new(passwords.hash("not anyone's password").expect("hashing a constant doesn't fail"));
+
+        passwords
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, String> {
+        let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).map_err(|e| e.to_string())?;
+        self.argon2().hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    /// compare a password with a stored argon2 or bcrypt hash. Without a hash (unknown users), it
+    /// takes as long and fails.
+    pub fn verify(&self, password: &str, hash: Option<&str>) -> Result<bool, String> {
+        let Some(hash) = hash else {
+            let _ = self.verify(password, Some(&self.dummy_hash));
+            return Ok(false);
+        };
+        if hash.starts_with("$2") {
+            return bcrypt::verify(password, hash).map_err(|e| e.to_string());
+        }
+
+        let parsed = PasswordHash::new(hash).map_err(|e| e.to_string())?;
+        // the hash's own parameters, not the configured ones
+        match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// whether a (verified) hash should be replaced by a new one: it's bcrypt, or argon2 with other
+    /// parameters than configured
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(parsed) = PasswordHash::new(hash) else {
+            return true;
+        };
+        if parsed.algorithm != argon2::ARGON2ID_IDENT {
+            return true;
+        }
+
+        Params::try_from(&parsed).is_ok_and(|params| {
+            (params.m_cost(), params.t_cost(), params.p_cost()) != (self.params.m_cost(), self.params.t_cost(), self.params.p_cost())
+        })
+    }
+}
+
+/// reset codes are long and random, so a plain (fast) hash is enough
+pub fn hash_reset_code(code: &str) -> String {
+    api_keys::hash(code.trim())
+}
+
+/// reset a user's password, returning the code for them to set a new one with. Their sessions are
+/// logged out and their access tokens stop working (once a running server's user cache notices, for
+/// the console).
+pub async fn reset(db: &impl DBCalls, handle: &str, created_by: &str) -> Result<String, String> {
+    match db.fetch_user(handle).await {
+        Ok(entry) if entry.inner_user.user_type != UserMode::Bot => {},
+        _ => return Err("no such user".to_string()),
+    }
+
+    let code = random_token(RESET_CODE_LENGTH);
+    let expires_at = chrono::Utc::now().timestamp() + RESET_CODE_LIFETIME_HOURS * 60 * 60;
+    db.start_password_reset(handle, &hash_reset_code(&code), created_by, expires_at).await.map_err(|e| e.to_string())?;
+    session::end_all(db, handle).await.map_err(|_| "error logging the user out".to_string())?;
+    db.bump_token_version(handle).await.map_err(|e| e.to_string())?;
+
+    Ok(code)
+}
+
+#[test]
+fn test_password_hashes() {
+    let cheap = |memory_kib| Passwords::new(&AuthConfig { password_memory_kib: memory_kib, password_iterations: 1, ..AuthConfig::default() });
+    let passwords = cheap(1024);
+
+    let hash = passwords.hash("correct horse").unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    assert_eq!(passwords.verify("correct horse", Some(&hash)), Ok(true));
+    assert_eq!(passwords.verify("wrong horse", Some(&hash)), Ok(false));
+    assert_eq!(passwords.verify("correct horse", None), Ok(false));
+    assert!(!passwords.needs_rehash(&hash));
+    assert!(cheap(2048).needs_rehash(&hash), "raising the cost should rehash");
+    assert_eq!(cheap(2048).verify("correct horse", Some(&hash)), Ok(true), "old parameters should still verify");
+
+    let bcrypt_hash = bcrypt::hash("correct horse", 4).unwrap();
+    assert_eq!(passwords.verify("correct horse", Some(&bcrypt_hash)), Ok(true));
+    assert_eq!(passwords.verify("wrong horse", Some(&bcrypt_hash)), Ok(false));
+    assert!(passwords.needs_rehash(&bcrypt_hash), "bcrypt hashes should be upgraded");
+}
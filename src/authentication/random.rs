@@ -0,0 +1,11 @@
+//! Helpers for generating unguessable strings (resume tokens and the like)
+use rand::{Rng, distr::Alphanumeric};
+
+/// generate a random alphanumeric string from a cryptographically secure rng
+pub fn random_token(length: usize) -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
@@ -1,8 +1,28 @@
-use axum::{extract::{Json, State}, http::StatusCode};
+use std::time::Duration;
+
+use axum::{extract::{ConnectInfo, Json, Path, Query, State}, http::{HeaderMap, StatusCode, header}, response::{IntoResponse, Redirect, Response}};
 use serde::{Serialize, Deserialize};
-use crate::{backend::server::{APIResponse, APIState}, database::database::DBCalls};
+use crate::backend::listener::Peer;
+use crate::backend::server::{APIResponse, AppState};
+use crate::authentication::api_keys::{self, Scope, MAX_KEY_NAME_LENGTH};
+use crate::authentication::credentials::{check_credentials, unless_banned, validate_handle, validate_password, LoginError};
+use crate::authentication::middleware::{authenticate, AuthError};
+use crate::authentication::session::{self, Origin, SessionError, Tokens};
+use crate::authentication::oidc::{OidcError, OIDC_COOKIE};
+use crate::authentication::{passwords, ssh_keys, throttle, two_factor};
+use crate::authentication::token::validate_claims;
+use crate::authentication::user::{User, UserMode, UserPermissions};
+use crate::database::database::{DBCalls, HandleTaken, Invite, Session, UserDBEntry, INVITE_LIFETIME_MINUTES, MAX_INVITE_LIFETIME_MINUTES};
 use serde_json::json;
-use log::warn;
+use log::{info, warn};
+
+/// longest display name, they're only shown next to the handle
+const MAX_USERNAME_LENGTH: usize = 64;
+/// longest User-Agent kept for the session list
+const MAX_CLIENT_LENGTH: usize = 128;
+/// how recently a session has to have logged in to let someone else in for good without a password
+/// to check: setting one on an account without, or linking an OpenID Connect account
+const FRESH_LOGIN_WINDOW: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LoginRequest {
@@ -10,50 +30,1092 @@ pub struct LoginRequest {
     password: String,
 }
 
-/// Route to log a User in and return a JWT
+/// `{"error": false, "value": <access token>, "refresh_token": <refresh token>}`
+fn tokens_response(tokens: Tokens) -> String {
+    json!({"error": false, "value": tokens.access, "refresh_token": tokens.refresh}).to_string()
+}
+
+/// `429` with how long to wait
+fn throttled(wait: Duration) -> Response {
+    let retry_after = [(header::RETRY_AFTER, wait.as_secs_f64().ceil().to_string())];
+    (StatusCode::TOO_MANY_REQUESTS, retry_after, APIResponse::new(true, &throttle::describe(wait)).serialize()).into_response()
+}
+
+/// where a request came from, for the session list
+fn origin(ip: &Peer, headers: &HeaderMap) -> Origin {
+    let client = headers.get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .map(|agent| agent.trim().chars().filter(|c| !c.is_control()).take(MAX_CLIENT_LENGTH).collect::<String>())
+        .filter(|agent| !agent.is_empty());
+    let ip = match ip {
+        Peer::Tcp(address) => address.ip().to_string(),
+        Peer::Unix => ip.to_string(),
+    };
+
+    Origin { ip, client }
+}
+
+/// the rest of a login once the user has proven who they are: the TOTP step if they have it,
+/// otherwise a new session
+async fn finish_login(state: &AppState, user: User, origin: &Origin) -> Result<String, Response> {
+    // users with TOTP finish logging in at /api/login/2fa
+    if two_factor::is_enabled(&state.db, &user.handle).await {
+        let challenge = state.pending_logins.start(&user.handle);
+        return Ok(json!({"error": false, "two_factor": true, "challenge": challenge}).to_string());
+    }
+
+    match session::start(&state.db, user, origin).await {
+        Ok(tokens) => Ok(tokens_response(tokens)),
+        Err(_) => Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin (probably via email...)").into_response()),
+    }
+}
+
+/// the user as they are now, for the later steps of a login
+async fn current_user(state: &AppState, handle: &str) -> Result<User, Response> {
+    let entry = state.db.fetch_user(handle).await
+        .map_err(|_| reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response())?;
+    match unless_banned(&state.db, entry.inner_user).await {
+        Ok(user) => Ok(user),
+        Err(LoginError::Banned(ban)) => Err(reject(StatusCode::FORBIDDEN, &format!("you are {}", ban.describe())).into_response()),
+        Err(_) => Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response()),
+    }
+}
+
+/// Route to log a User in and return a JWT and a refresh token
 #[axum::debug_handler]
-pub async fn login(State(state): State<APIState>, Json(body): Json<LoginRequest>) -> Result<String, (StatusCode, String)> {
+pub async fn login(State(state): State<AppState>, ConnectInfo(ip): ConnectInfo<Peer>, headers: HeaderMap, Json(body): Json<LoginRequest>) -> Result<String, Response> {
     // validate fields
-    if body.handle.is_empty() {return Err((StatusCode::BAD_REQUEST, APIResponse::new(true, "field \"handle\" cannot be empty").serialize()))}
-    if body.password.is_empty() {return Err((StatusCode::BAD_REQUEST, APIResponse::new(true, "field \"password\" cannot be empty").serialize()))}
+    if body.handle.is_empty() {return Err(reject(StatusCode::BAD_REQUEST, "field \"handle\" cannot be empty").into_response())}
+    if body.password.is_empty() {return Err(reject(StatusCode::BAD_REQUEST, "field \"password\" cannot be empty").into_response())}
     
-    // find the user on the database, if they don't exist return a BAD_REQUEST and warn!
+    // find the user on the database and compare passwords
+    let user = match check_credentials(&state.db, &state.passwords, &state.login_throttle, &ip, &body.handle, &body.password).await {
+        Ok(user) => user,
+        // the same for unknown handles, so this can't be used to find out who exists
+        Err(LoginError::InvalidCredentials) => return Err(reject(StatusCode::UNAUTHORIZED, "invalid handle or password").into_response()),
+        Err(LoginError::Throttled(wait)) => return Err(throttled(wait)),
+        Err(LoginError::Banned(ban)) => return Err(reject(StatusCode::FORBIDDEN, &format!("you are {}", ban.describe())).into_response()),
+        Err(LoginError::Internal) => return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "error comparing passwords (server error, not your fault. contact an admin.)").into_response()),
+        // only check_inline() asks for the code, getting it here is a bug but not worth a crash
+        Err(LoginError::SecondFactorRequired) => {
+            warn!("check_credentials() asked @{} for a second factor", body.handle);
+            return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response())
+        },
+    };
 
-    let user_entry = match state.db.fetch_user(&body.handle).await {
-        Ok(v) => v,
-        Err(message) => {
-            warn!("{}", message);
-            return Err((
-                    StatusCode::UNAUTHORIZED,
-                    APIResponse::new(true, "No user found matching that handle").serialize()
-            ));
+    finish_login(&state, user, &origin(&ip, &headers)).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SecondFactorRequest {
+    challenge: String, // from login()
+    code: String, // from the authenticator app, or a recovery code
+}
+
+/// Route to finish logging in a user with TOTP, returning a JWT and a refresh token like login()
+pub async fn login_second_factor(State(state): State<AppState>, ConnectInfo(ip): ConnectInfo<Peer>, headers: HeaderMap, Json(body): Json<SecondFactorRequest>) -> Result<String, Response> {
+    let Some(handle) = state.pending_logins.handle(&body.challenge) else {
+        return Err(reject(StatusCode::UNAUTHORIZED, "unknown or expired challenge, log in again").into_response())
+    };
+    if let Some(wait) = state.login_throttle.check(&ip, &handle) {
+        return Err(throttled(wait))
+    }
+
+    match two_factor::verify_code(&state.db, &handle, &body.code).await {
+        Ok(true) => state.pending_logins.finish(&body.challenge),
+        Ok(false) => {
+            state.pending_logins.failed(&body.challenge);
+            state.login_throttle.failed(&ip, &handle);
+            return Err(reject(StatusCode::UNAUTHORIZED, "invalid two-factor code").into_response())
+        },
+        Err(e) => {
+            warn!("error checking a two-factor code: {}", e);
+            return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response())
         },
+    }
+
+    // they may have been banned since the password step
+    let user = current_user(&state, &handle).await?;
+    let tokens = match session::start(&state.db, user, &origin(&ip, &headers)).await {
+        Err(_) => return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin (probably via email...)").into_response()),
+        Ok(tokens) => tokens,
     };
-    
-    // destructure the user_entry object
-    let (user, password_hash) = (user_entry.inner_user, user_entry.password_hash);
-    
-    // compare passwords
-    match bcrypt::verify(body.password, &password_hash) {
-        Ok(passwords_match) => {
-            if !passwords_match {return Err((StatusCode::UNAUTHORIZED, APIResponse::new(true, "wrong password.").serialize()))}
-            // otherwise, continue because the passwords do match
+
+    Ok(tokens_response(tokens))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SshChallengeRequest {
+    handle: String,
+}
+
+/// Route to get a challenge to sign for logging in with an SSH key. There's one for any handle, so
+/// this can't be used to find out who exists.
+pub async fn ssh_challenge(State(state): State<AppState>, ConnectInfo(ip): ConnectInfo<Peer>, Json(body): Json<SshChallengeRequest>) -> Result<String, Response> {
+    if body.handle.is_empty() {return Err(reject(StatusCode::BAD_REQUEST, "field \"handle\" cannot be empty").into_response())}
+    // no point signing a challenge that can't be used yet
+    if let Some(wait) = state.login_throttle.check(&ip, &body.handle) {
+        return Err(throttled(wait))
+    }
+
+    let Some(challenge) = state.ssh_challenges.start(&body.handle, &ip) else {
+        return Err(reject(StatusCode::TOO_MANY_REQUESTS, "too many challenges waiting to be used, use one or wait for them to expire").into_response())
+    };
+    Ok(json!({"error": false, "value": challenge, "namespace": ssh_keys::NAMESPACE}).to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SshLoginRequest {
+    challenge: String, // from ssh_challenge()
+    signature: String, // the challenge signed with `ssh-keygen -Y sign -n trcd-login`
+}
+
+/// Route to log in with a signed challenge, returning the same as login()
+pub async fn login_ssh(State(state): State<AppState>, ConnectInfo(ip): ConnectInfo<Peer>, headers: HeaderMap, Json(body): Json<SshLoginRequest>) -> Result<String, Response> {
+    let Some(handle) = state.ssh_challenges.take(&body.challenge) else {
+        return Err(reject(StatusCode::UNAUTHORIZED, "unknown, expired or already used challenge, ask for a new one").into_response())
+    };
+    if let Some(wait) = state.login_throttle.check(&ip, &handle) {
+        return Err(throttled(wait))
+    }
+
+    // unknown handles have no keys, so they fail like a key that isn't registered
+    let is_user = state.db.fetch_user(&handle).await.is_ok_and(|entry| entry.inner_user.user_type != UserMode::Bot);
+    let registered = match is_user {
+        true => state.db.fetch_ssh_keys(&handle).await.map_err(|e| e.to_string()),
+        false => Ok(Vec::new()),
+    };
+    let rejection = match (ssh_keys::verify_signature(&body.signature, body.challenge.as_bytes()), registered) {
+        (Ok(key), Ok(keys)) if keys.iter().any(|k| k.key == key.openssh()) => None,
+        (Ok(_), Ok(_)) => Some("that key isn't registered for this handle".to_string()),
+        (Err(e), _) => Some(e),
+        (_, Err(e)) => {
+            warn!("error fetching ssh keys: {}", e);
+            return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response())
+        },
+    };
+    if let Some(rejection) = rejection {
+        state.login_throttle.failed(&ip, &handle);
+        return Err(reject(StatusCode::UNAUTHORIZED, &rejection).into_response())
+    }
+    state.login_throttle.succeeded(&handle);
+
+    let user = current_user(&state, &handle).await?;
+
+    finish_login(&state, user, &origin(&ip, &headers)).await
+}
+
+/// Route to send the browser to the OpenID Connect issuer, which sends it back to oidc_callback
+pub async fn oidc_login(State(state): State<AppState>) -> Result<Response, Response> {
+    let Some(oidc) = &state.oidc else {
+        return Err(reject(StatusCode::NOT_FOUND, "logging in with OpenID Connect isn't set up on this server").into_response())
+    };
+    match oidc.authorization_url(None).await {
+        Ok((url, cookie)) => Ok(([(header::SET_COOKIE, cookie)], Redirect::to(url.as_str())).into_response()),
+        Err(e) => Err(oidc_rejection(e)),
+    }
+}
+
+/// Route for a logged in user to link their account to their account at the OpenID Connect issuer,
+/// returning where to send the browser. It comes back to oidc_callback like a login.
+pub async fn link_oidc(State(state): State<AppState>, headers: HeaderMap) -> Result<Response, Response> {
+    let Some(oidc) = &state.oidc else {
+        return Err(reject(StatusCode::NOT_FOUND, "logging in with OpenID Connect isn't set up on this server").into_response())
+    };
+    let current = token_session(&headers);
+    let user = require_user(&state, headers).await.map_err(IntoResponse::into_response)?;
+    if user.user_type == UserMode::Bot {
+        return Err(reject(StatusCode::FORBIDDEN, "bots can't log in through an identity provider").into_response())
+    }
+    // a stolen access token alone shouldn't be enough to tie someone else's account to this one
+    let fresh = session::logged_in_within(&state.db, &user.handle, current.as_deref().unwrap_or_default(), FRESH_LOGIN_WINDOW).await
+        .map_err(|_| reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response())?;
+    if !fresh {
+        let message = format!("log in again to link an account, it has to be within {} minutes of logging in", FRESH_LOGIN_WINDOW.as_secs() / 60);
+        return Err(reject(StatusCode::FORBIDDEN, &message).into_response())
+    }
+
+    match oidc.authorization_url(Some(&user.handle)).await {
+        Ok((url, cookie)) => Ok(([(header::SET_COOKIE, cookie)], json!({"error": false, "value": url.as_str()}).to_string()).into_response()),
+        Err(e) => Err(oidc_rejection(e)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>, // the issuer didn't log them in, RFC 6749 4.1.2.1
+    error_description: Option<String>,
+}
+
+/// Route the issuer sends the browser back to, returns a JWT and a refresh token like /api/login
+pub async fn oidc_callback(State(state): State<AppState>, ConnectInfo(ip): ConnectInfo<Peer>, headers: HeaderMap, Query(query): Query<OidcCallbackQuery>) -> Result<String, Response> {
+    let Some(oidc) = &state.oidc else {
+        return Err(reject(StatusCode::NOT_FOUND, "logging in with OpenID Connect isn't set up on this server").into_response())
+    };
+    if let Some(error) = query.error {
+        let description = query.error_description.map(|d| format!(": {}", d)).unwrap_or_default();
+        return Err(reject(StatusCode::UNAUTHORIZED, &format!("the identity provider didn't log you in ({}{})", error, description)).into_response())
+    }
+    let (Some(code), Some(login)) = (query.code, query.state) else {
+        return Err(reject(StatusCode::BAD_REQUEST, "\"code\" and \"state\" are required").into_response())
+    };
+
+    // whoever is linking their account has to be logged in to finish it
+    let caller = match headers.contains_key("x-auth-token") {
+        true => Some(require_user(&state, headers.clone()).await.map_err(IntoResponse::into_response)?.handle),
+        false => None,
+    };
+    let browser = cookie(&headers, OIDC_COOKIE);
+
+    let handle = oidc.login(&state.db, &login, &code, browser, caller.as_deref()).await.map_err(oidc_rejection)?;
+    let user = current_user(&state, &handle).await?;
+
+    finish_login(&state, user, &origin(&ip, &headers)).await
+}
+
+/// the value of the cookie `name`, if the browser sent it
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get_all(header::COOKIE).iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+fn oidc_rejection(error: OidcError) -> Response {
+    match error {
+        OidcError::UnknownState => reject(StatusCode::UNAUTHORIZED, "unknown or expired login, start over"),
+        OidcError::OtherBrowser => reject(StatusCode::UNAUTHORIZED, "the login was started in another browser (or without cookies), start over"),
+        OidcError::Issuer => reject(StatusCode::BAD_GATEWAY, "the identity provider couldn't be reached or answered something unexpected, try again later"),
+        OidcError::Rejected(reason) => reject(StatusCode::FORBIDDEN, &reason),
+        OidcError::Internal => reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."),
+    }.into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    invite: String,
+    handle: String,
+    password: Option<String>, // optional with an ssh_key, at least one is needed to log in later
+    ssh_key: Option<String>, // a line of an `.pub` file, see ssh_keys.rs
+    username: Option<String>, // display name, defaults to the handle
+    provider_site: Option<String>,
+}
+
+fn reject(status: StatusCode, message: &str) -> (StatusCode, String) {
+    (status, APIResponse::new(true, message).serialize())
+}
+
+/// the display name to use, defaulting to the handle
+fn display_name(username: Option<&str>, handle: &str) -> Result<String, (StatusCode, String)> {
+    match username.map(str::trim) {
+        None | Some("") => Ok(handle.to_string()),
+        Some(name) if name.chars().count() > MAX_USERNAME_LENGTH || name.chars().any(char::is_control) => {
+            Err(reject(StatusCode::BAD_REQUEST, &format!("username can be at most {} characters, without control characters", MAX_USERNAME_LENGTH)))
         },
+        Some(name) => Ok(name.to_string()),
+    }
+}
+
+/// Route to create an account with an invite code and return a JWT for it
+pub async fn register(State(state): State<AppState>, ConnectInfo(ip): ConnectInfo<Peer>, headers: HeaderMap, Json(body): Json<RegisterRequest>) -> Result<(StatusCode, String), (StatusCode, String)> {
+    // validate fields
+    validate_handle(&body.handle).map_err(|e| reject(StatusCode::BAD_REQUEST, &e))?;
+    if let Some(password) = &body.password {
+        validate_password(password, &body.handle).map_err(|e| reject(StatusCode::BAD_REQUEST, &e))?;
+    }
+    let ssh_key = body.ssh_key.as_deref().map(ssh_keys::PublicKey::parse).transpose().map_err(|e| reject(StatusCode::BAD_REQUEST, &e))?;
+    if body.password.is_none() && ssh_key.is_none() {
+        return Err(reject(StatusCode::BAD_REQUEST, "needs a \"password\", an \"ssh_key\" or both"))
+    }
+    let username = display_name(body.username.as_deref(), &body.handle)?;
+    let provider_site = body.provider_site.filter(|site| !site.trim().is_empty());
+
+    // take a use of the invite first, so the handle check can't be used to find out who exists
+    match state.db.redeem_invite(&body.invite).await {
+        Ok(true) => {},
+        Ok(false) => return Err(reject(StatusCode::FORBIDDEN, "invalid invite, it may have expired, been used up or revoked. Ask an admin for a new one.")),
+        Err(e) => {
+            warn!("error redeeming invite: {}", e);
+            return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
+        },
+    }
+
+    let password_hash = match body.password.as_deref().map(|password| state.passwords.hash(password)).transpose() {
+        Ok(hash) => hash.unwrap_or_default(), // never matches, see check_credentials()
         Err(e) => {
-            warn!("bcrypt error: {}", e);
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, APIResponse::new(true, "error comparing passwords (server error, not your fault. contact an admin.)").serialize()));
+            warn!("error hashing password: {}", e);
+            let _ = state.db.release_invite(&body.invite).await;
+            return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "error hashing the password (server error, not your fault. contact an admin.)"))
         },
     };
-    
-    
-    // return a jwt
-    let token = match super::token::create_token(user.clone(), None) {
+    let new_user = UserDBEntry {
+        password_hash,
+        username: body.handle.clone(),
+        inner_user: User {
+            user_type: UserMode::User,
+            handle: body.handle.clone(),
+            username,
+            permission_level: UserPermissions::User,
+            banned: false,
+            provider_site,
+        },
+        token_version: 0,
+    };
+
+    // the error isn't Send, so only keep what it says before awaiting anything else
+    let added = state.db.add_user(new_user).await.map_err(|e| {
+        if e.is::<HandleTaken>() {
+            reject(StatusCode::CONFLICT, "that handle is already taken")
+        } else {
+            warn!("error adding user: {}", e);
+            reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.")
+        }
+    });
+    let user = match added {
+        Ok(user) => user,
+        Err(rejection) => {
+            // the invite wasn't really used, give it back
+            let _ = state.db.release_invite(&body.invite).await;
+            return Err(rejection)
+        },
+    };
+    info!("@{} registered with an invite", user.handle);
+
+    // the account exists either way, the key can be added again with /api/ssh-keys
+    if let Some((key, comment)) = ssh_key && let Err(e) = state.db.add_ssh_key(&key.entry(&user.handle, &comment)).await {
+        warn!("error adding ssh key: {}", e);
+    }
+
+    let tokens = session::start(&state.db, user, &origin(&ip, &headers)).await
+        .map_err(|_| reject(StatusCode::INTERNAL_SERVER_ERROR, "account created, but logging in failed. Try /api/login."))?;
+
+    Ok((StatusCode::CREATED, tokens_response(tokens)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+/// Route to swap a refresh token for a new JWT and refresh token
+pub async fn refresh(State(state): State<AppState>, ConnectInfo(ip): ConnectInfo<Peer>, headers: HeaderMap, Json(body): Json<RefreshRequest>) -> Result<String, (StatusCode, String)> {
+    match session::refresh(&state.db, &body.refresh_token, &origin(&ip, &headers)).await {
+        Ok((_, tokens)) => Ok(tokens_response(tokens)),
+        Err(SessionError::Invalid) => Err(reject(StatusCode::UNAUTHORIZED, "invalid refresh token, it may have expired or been logged out. Log in again.")),
+        Err(SessionError::Reused) => Err(reject(StatusCode::UNAUTHORIZED, "this refresh token was already used, so the session has been logged out in case it was stolen. Log in again.")),
+        Err(SessionError::Banned(ban)) => Err(reject(StatusCode::FORBIDDEN, &format!("you are {}", ban.describe()))),
+        Err(SessionError::Internal) => Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.")),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LogoutRequest {
+    refresh_token: Option<String>, // for when the access token already expired
+    #[serde(default)]
+    everywhere: bool, // every session of the user, not just this one
+}
+
+/// Route to end the session of the access token in `x-auth-token` (or of `refresh_token`)
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap, body: String) -> Result<String, (StatusCode, String)> {
+    let body: LogoutRequest = match body.trim().is_empty() {
+        true => LogoutRequest::default(),
+        false => serde_json::from_str(&body).map_err(|e| reject(StatusCode::BAD_REQUEST, &format!("invalid body: {}", e)))?,
+    };
+    let unauthorized = || reject(StatusCode::UNAUTHORIZED, "either missing a token (x-auth-token) or an invalid token.");
+
+    let (session, handle) = match (&body.refresh_token, headers.get("x-auth-token").and_then(|v| v.to_str().ok())) {
+        (Some(refresh_token), _) => session::session_of(&state.db, refresh_token).await.map_err(|_| unauthorized())?,
+        (None, Some(token)) => {
+            let claims = validate_claims(token).map_err(|_| unauthorized())?;
+            (claims.sid, claims.sub)
+        },
+        (None, None) => return Err(unauthorized()),
+    };
+
+    let ended = match body.everywhere {
+        // this session too, even if it has no usable refresh token left to be found by
+        true => session::end_all(&state.db, &handle).await.and(session::end(&state.db, &session).await),
+        false => session::end(&state.db, &session).await,
+    };
+    if ended.is_err() {
+        return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
+    }
+    // access tokens of every session, including ones that were never refreshed
+    if body.everywhere {
+        state.users.revoke_tokens(&state.db, &handle).await.map_err(|e| {
+            warn!("error revoking tokens: {}", e);
+            reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.")
+        })?;
+    }
+    // sockets, streams and line/IRC connections stay open otherwise, like in end_session()
+    let closed = match body.everywhere {
+        true => state.connections.disconnect(&handle, "you were logged out everywhere"),
+        false => state.connections.disconnect_session(&handle, &session, "this session was logged out"),
+    };
+    info!("@{} logged out{}, closing {} connections", handle, if body.everywhere {" everywhere"} else {""}, closed);
+
+    Ok(json!({"error": false}).to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InviteRequest {
+    #[serde(default = "default_invite_uses")]
+    uses: u32, // 0 for unlimited
+    #[serde(default = "default_invite_lifetime")]
+    expires_in_minutes: i64, // 0 for never
+}
+fn default_invite_uses() -> u32 { 1 }
+fn default_invite_lifetime() -> i64 { INVITE_LIFETIME_MINUTES }
+
+/// the user of the access token in `x-auth-token`
+async fn require_user(state: &AppState, headers: HeaderMap) -> Result<User, (StatusCode, String)> {
+    authenticate(state, headers).await.map_err(|e| match e {
+        AuthError::InvalidToken => reject(StatusCode::UNAUTHORIZED, "either missing a token (x-auth-token) or an invalid token."),
+        AuthError::Banned(_) => reject(StatusCode::FORBIDDEN, &e.to_string()),
+    })
+}
+
+/// only admins get to manage invites and bots
+async fn require_admin(state: &AppState, headers: HeaderMap, what: &str) -> Result<User, (StatusCode, String)> {
+    let user = require_user(state, headers).await?;
+    if user.permission_level != UserPermissions::Admin {
+        return Err(reject(StatusCode::FORBIDDEN, &format!("only admins can manage {}", what)));
+    }
+    two_factor::require(state, &user).await.map_err(|e| reject(StatusCode::FORBIDDEN, e))?;
+
+    Ok(user)
+}
+
+/// Route to mint an invite code
+pub async fn create_invite(State(state): State<AppState>, headers: HeaderMap, Json(body): Json<InviteRequest>) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let admin = require_admin(&state, headers, "invites").await?;
+    if !(0..=MAX_INVITE_LIFETIME_MINUTES).contains(&body.expires_in_minutes) {
+        return Err(reject(StatusCode::BAD_REQUEST, &format!("field \"expires_in_minutes\" has to be between 0 and {}", MAX_INVITE_LIFETIME_MINUTES)))
+    }
+
+    let invite = Invite::new(
+        &admin.handle,
+        (body.uses > 0).then_some(body.uses),
+        (body.expires_in_minutes > 0).then_some(body.expires_in_minutes),
+    );
+    if let Err(e) = state.db.add_invite(&invite).await {
+        warn!("error adding invite: {}", e);
+        return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
+    }
+    info!("@{} created an invite", admin.handle);
+
+    Ok((StatusCode::CREATED, json!({"error": false, "value": invite}).to_string()))
+}
+
+/// Route to list every invite, including used up and revoked ones
+pub async fn list_invites(State(state): State<AppState>, headers: HeaderMap) -> Result<String, (StatusCode, String)> {
+    require_admin(&state, headers, "invites").await?;
+
+    match state.db.fetch_invites().await {
+        Ok(invites) => Ok(json!({"error": false, "value": invites}).to_string()),
+        Err(e) => {
+            warn!("error fetching invites: {}", e);
+            Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
+        },
+    }
+}
+
+/// Route to revoke an invite so it can't be used anymore
+pub async fn revoke_invite(State(state): State<AppState>, headers: HeaderMap, Path(code): Path<String>) -> Result<String, (StatusCode, String)> {
+    let admin = require_admin(&state, headers, "invites").await?;
+
+    match state.db.revoke_invite(&code).await {
+        Ok(true) => {
+            info!("@{} revoked an invite", admin.handle);
+            Ok(json!({"error": false}).to_string())
+        },
+        Ok(false) => Err(reject(StatusCode::NOT_FOUND, "no invite with that code")),
+        Err(e) => {
+            warn!("error revoking invite: {}", e);
+            Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BotRequest {
+    handle: String,
+    username: Option<String>, // display name, defaults to the handle
+    provider_site: Option<String>, // e.g. where the bot's source lives
+}
+
+/// Route to create a bot account. Bots can't log in, they use API keys (see create_api_key())
+pub async fn create_bot(State(state): State<AppState>, headers: HeaderMap, Json(body): Json<BotRequest>) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let admin = require_admin(&state, headers, "bots").await?;
+    validate_handle(&body.handle).map_err(|e| reject(StatusCode::BAD_REQUEST, &e))?;
+    let username = display_name(body.username.as_deref(), &body.handle)?;
+
+    let bot = UserDBEntry {
+        password_hash: String::new(), // never matches, see check_credentials()
+        username: body.handle.clone(),
+        inner_user: User {
+            user_type: UserMode::Bot,
+            handle: body.handle.clone(),
+            username,
+            permission_level: UserPermissions::User,
+            banned: false,
+            provider_site: body.provider_site.filter(|site| !site.trim().is_empty()),
+        },
+        token_version: 0,
+    };
+    let bot = state.db.add_user(bot).await.map_err(|e| match e.is::<HandleTaken>() {
+        true => reject(StatusCode::CONFLICT, &e.to_string()),
+        false => {
+            warn!("error adding bot: {}", e);
+            reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.")
+        },
+    })?;
+    info!("@{} created the bot @{}", admin.handle, bot.handle);
+
+    Ok((StatusCode::CREATED, json!({"error": false, "value": bot}).to_string()))
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyAction {
+    Post,
+    Read,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyRequest {
+    name: String,
+    channels: Option<Vec<String>>, // None for every channel
+    actions: Vec<KeyAction>,
+}
+
+/// the bot with `handle`, 404 for anyone else
+async fn fetch_bot(state: &AppState, handle: &str) -> Result<User, (StatusCode, String)> {
+    match state.db.fetch_user(handle).await {
+        Ok(entry) if entry.inner_user.user_type == UserMode::Bot => Ok(entry.inner_user),
+        _ => Err(reject(StatusCode::NOT_FOUND, "no bot with that handle")),
+    }
+}
+
+/// Route to give a bot a new API key. The key is only ever in this response.
+pub async fn create_api_key(State(state): State<AppState>, headers: HeaderMap, Path(handle): Path<String>, Json(body): Json<ApiKeyRequest>) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let admin = require_admin(&state, headers, "bots").await?;
+
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_KEY_NAME_LENGTH || name.chars().any(char::is_control) {
+        return Err(reject(StatusCode::BAD_REQUEST, &format!("name has to be between 1 and {} characters, without control characters", MAX_KEY_NAME_LENGTH)))
+    }
+    if body.actions.is_empty() {
+        return Err(reject(StatusCode::BAD_REQUEST, "field \"actions\" needs \"post\", \"read\" or both"))
+    }
+    if let Some(channels) = &body.channels {
+        let max_length = state.config.limits.max_channel_name_length_bytes;
+        if channels.is_empty() || channels.iter().any(|c| c.is_empty() || c.len() > max_length || c == "ALL") {
+            return Err(reject(StatusCode::BAD_REQUEST, &format!("field \"channels\" needs channel names of at most {} bytes, leave it out for every channel", max_length)))
+        }
+    }
+    let bot = fetch_bot(&state, &handle).await?;
+
+    let scope = Scope {
+        channels: body.channels,
+        post: body.actions.contains(&KeyAction::Post),
+        read: body.actions.contains(&KeyAction::Read),
+    };
+    let (key, entry) = api_keys::generate(&bot.handle, name, scope, &admin.handle);
+    if let Err(e) = state.db.add_api_key(&entry).await {
+        warn!("error adding api key: {}", e);
+        return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
+    }
+    info!("@{} created an api key for @{}", admin.handle, bot.handle);
+
+    Ok((StatusCode::CREATED, json!({"error": false, "value": entry, "key": key}).to_string()))
+}
+
+/// Route to list a bot's API keys, without the keys themselves
+pub async fn list_api_keys(State(state): State<AppState>, headers: HeaderMap, Path(handle): Path<String>) -> Result<String, (StatusCode, String)> {
+    require_admin(&state, headers, "bots").await?;
+    let bot = fetch_bot(&state, &handle).await?;
+
+    match state.db.fetch_api_keys(&bot.handle).await {
+        Ok(keys) => Ok(json!({"error": false, "value": keys}).to_string()),
         Err(e) => {
-            warn!("error creating token: {:?}", e);
-            return Err((StatusCode::INTERNAL_SERVER_ERROR, APIResponse::new(true, "Internal Server Error. Please report to an admin (probably via email...)").serialize()))
+            warn!("error fetching api keys: {}", e);
+            Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
         },
-        Ok(token) => token,
+    }
+}
+
+/// Route to revoke an API key, it stops working straight away
+pub async fn revoke_api_key(State(state): State<AppState>, headers: HeaderMap, Path((handle, id)): Path<(String, String)>) -> Result<String, (StatusCode, String)> {
+    let admin = require_admin(&state, headers, "bots").await?;
+
+    match state.db.revoke_api_key(&handle, &id).await {
+        Ok(true) => {
+            info!("@{} revoked an api key of @{}", admin.handle, handle);
+            Ok(json!({"error": false}).to_string())
+        },
+        Ok(false) => Err(reject(StatusCode::NOT_FOUND, "no key with that id")),
+        Err(e) => {
+            warn!("error revoking api key: {}", e);
+            Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
+        },
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorCodeRequest {
+    code: String,
+}
+
+fn internal_error(what: &str) -> impl FnOnce(Box<dyn std::error::Error>) -> (StatusCode, String) + '_ {
+    move |e| {
+        warn!("error {}: {}", what, e);
+        reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.")
+    }
+}
+
+/// check that `code` is one of the user's two-factor codes, for turning it off and the like
+async fn require_code(state: &AppState, user: &User, code: &str) -> Result<(), (StatusCode, String)> {
+    match two_factor::verify_code(&state.db, &user.handle, code).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(reject(StatusCode::FORBIDDEN, "invalid two-factor code")),
+        Err(e) => {
+            warn!("error checking a two-factor code: {}", e);
+            Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
+        },
+    }
+}
+
+/// whether two-factor authentication is required for `user`'s rank
+fn two_factor_required(state: &AppState, user: &User) -> bool {
+    state.config.auth.require_two_factor.as_ref().is_some_and(|rank| user.permission_level >= *rank)
+}
+
+/// Route to show whether the user has two-factor authentication and how many recovery codes they
+/// have left
+pub async fn two_factor_status(State(state): State<AppState>, headers: HeaderMap) -> Result<String, (StatusCode, String)> {
+    let user = require_user(&state, headers).await?;
+    let enabled = two_factor::is_enabled(&state.db, &user.handle).await;
+    let recovery_codes_left = match enabled {
+        true => state.db.count_recovery_codes(&user.handle).await.map_err(internal_error("counting recovery codes"))?,
+        false => 0,
     };
-    
-    Ok(json!({"error": false, "value": token}).to_string())
+
+    Ok(json!({"error": false, "value": {
+        "enabled": enabled,
+        "recovery_codes_left": recovery_codes_left,
+        "required": two_factor_required(&state, &user),
+    }}).to_string())
+}
+
+/// Route to start setting up TOTP: a new secret to put in an authenticator app. It's only used once
+/// confirmed, see confirm_totp().
+pub async fn start_totp(State(state): State<AppState>, headers: HeaderMap) -> Result<String, (StatusCode, String)> {
+    let user = require_user(&state, headers).await?;
+    if user.user_type == UserMode::Bot {
+        return Err(reject(StatusCode::FORBIDDEN, "bots use API keys, they can't log in"))
+    }
+
+    let secret = two_factor::generate_secret();
+    if !state.db.set_totp_secret(&user.handle, &secret).await.map_err(internal_error("setting totp secret"))? {
+        return Err(reject(StatusCode::CONFLICT, "two-factor authentication is already on, turn it off first to change the secret"))
+    }
+
+    Ok(json!({"error": false, "value": {
+        "secret": secret,
+        "uri": two_factor::otpauth_uri(&user.handle, &secret),
+    }}).to_string())
+}
+
+/// Route to turn TOTP on with a code from the new secret. Returns the recovery codes, the only time
+/// they're shown.
+pub async fn confirm_totp(State(state): State<AppState>, headers: HeaderMap, Json(body): Json<TwoFactorCodeRequest>) -> Result<String, (StatusCode, String)> {
+    let user = require_user(&state, headers).await?;
+    match state.db.fetch_totp(&user.handle).await.map_err(internal_error("fetching totp"))? {
+        None => return Err(reject(StatusCode::NOT_FOUND, "no TOTP secret to confirm, start with POST /api/2fa/totp")),
+        Some(entry) if entry.enabled => return Err(reject(StatusCode::CONFLICT, "two-factor authentication is already on")),
+        Some(_) => {},
+    }
+    match two_factor::verify_code(&state.db, &user.handle, &body.code).await {
+        Ok(true) => {},
+        Ok(false) => return Err(reject(StatusCode::FORBIDDEN, "invalid two-factor code, check the authenticator app's clock")),
+        Err(e) => {
+            warn!("error checking a two-factor code: {}", e);
+            return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))
+        },
+    }
+
+    let (codes, hashes) = two_factor::generate_recovery_codes();
+    state.db.enable_totp(&user.handle, &hashes).await.map_err(internal_error("enabling totp"))?;
+    info!("@{} turned on two-factor authentication", user.handle);
+
+    Ok(json!({"error": false, "value": {"recovery_codes": codes}}).to_string())
+}
+
+/// Route to turn TOTP off, with a current code (or a recovery code)
+pub async fn disable_totp(State(state): State<AppState>, headers: HeaderMap, Json(body): Json<TwoFactorCodeRequest>) -> Result<String, (StatusCode, String)> {
+    let user = require_user(&state, headers).await?;
+    if two_factor_required(&state, &user) {
+        return Err(reject(StatusCode::FORBIDDEN, "your rank requires two-factor authentication, it can't be turned off"))
+    }
+    require_code(&state, &user, &body.code).await?;
+
+    if !state.db.disable_totp(&user.handle).await.map_err(internal_error("disabling totp"))? {
+        return Err(reject(StatusCode::NOT_FOUND, "two-factor authentication isn't on"))
+    }
+    info!("@{} turned off two-factor authentication", user.handle);
+
+    Ok(json!({"error": false}).to_string())
+}
+
+/// Route to swap the user's recovery codes for new ones, with a current code (or a recovery code)
+pub async fn regenerate_recovery_codes(State(state): State<AppState>, headers: HeaderMap, Json(body): Json<TwoFactorCodeRequest>) -> Result<String, (StatusCode, String)> {
+    let user = require_user(&state, headers).await?;
+    if !two_factor::is_enabled(&state.db, &user.handle).await {
+        return Err(reject(StatusCode::NOT_FOUND, "two-factor authentication isn't on"))
+    }
+    require_code(&state, &user, &body.code).await?;
+
+    let (codes, hashes) = two_factor::generate_recovery_codes();
+    state.db.replace_recovery_codes(&user.handle, &hashes).await.map_err(internal_error("replacing recovery codes"))?;
+    info!("@{} got new recovery codes", user.handle);
+
+    Ok(json!({"error": false, "value": {"recovery_codes": codes}}).to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SshKeyRequest {
+    key: String, // a line of an `.pub` file
+}
+
+/// Route to register an SSH key to log in with
+pub async fn add_ssh_key(State(state): State<AppState>, headers: HeaderMap, Json(body): Json<SshKeyRequest>) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let user = require_user(&state, headers).await?;
+    let (key, comment) = ssh_keys::PublicKey::parse(&body.key).map_err(|e| reject(StatusCode::BAD_REQUEST, &e))?;
+    let keys = state.db.fetch_ssh_keys(&user.handle).await.map_err(internal_error("fetching ssh keys"))?;
+    if keys.len() >= ssh_keys::MAX_SSH_KEYS {
+        return Err(reject(StatusCode::BAD_REQUEST, &format!("you can have at most {} keys, remove one first", ssh_keys::MAX_SSH_KEYS)))
+    }
+
+    let entry = key.entry(&user.handle, &comment);
+    if !state.db.add_ssh_key(&entry).await.map_err(internal_error("adding ssh key"))? {
+        return Err(reject(StatusCode::CONFLICT, "you already have that key"))
+    }
+    info!("@{} added ssh key {}", user.handle, entry.fingerprint);
+
+    Ok((StatusCode::CREATED, json!({"error": false, "value": entry}).to_string()))
+}
+
+/// Route to list the user's SSH keys
+pub async fn list_ssh_keys(State(state): State<AppState>, headers: HeaderMap) -> Result<String, (StatusCode, String)> {
+    let user = require_user(&state, headers).await?;
+    let keys = state.db.fetch_ssh_keys(&user.handle).await.map_err(internal_error("fetching ssh keys"))?;
+
+    Ok(json!({"error": false, "value": keys}).to_string())
+}
+
+/// Route to remove an SSH key, unless it's the only way the user can log in
+pub async fn remove_ssh_key(State(state): State<AppState>, headers: HeaderMap, Path(id): Path<String>) -> Result<String, (StatusCode, String)> {
+    let user = require_user(&state, headers).await?;
+    let keys = state.db.fetch_ssh_keys(&user.handle).await.map_err(internal_error("fetching ssh keys"))?;
+    if !keys.iter().any(|key| key.id == id) {
+        return Err(reject(StatusCode::NOT_FOUND, "no key with that id"))
+    }
+    let has_password = !state.db.fetch_user(&user.handle).await.map_err(internal_error("fetching user"))?.password_hash.is_empty();
+    if keys.len() == 1 && !has_password {
+        return Err(reject(StatusCode::CONFLICT, "that's your only key and you have no password, add another key first"))
+    }
+
+    if !state.db.remove_ssh_key(&user.handle, &id).await.map_err(internal_error("removing ssh key"))? {
+        return Err(reject(StatusCode::NOT_FOUND, "no key with that id"))
+    }
+    info!("@{} removed an ssh key", user.handle);
+
+    Ok(json!({"error": false}).to_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordChangeRequest {
+    old_password: Option<String>, // only optional for accounts without a password (see ssh_keys.rs and oidc.rs)
+    new_password: String,
+}
+
+/// Route to change the user's password, or set one for an account without (from a session that
+/// logged in within FRESH_LOGIN_WINDOW). Logs every other session out and closes their
+/// connections, returning new tokens for this one.
+pub async fn change_password(State(state): State<AppState>, ConnectInfo(ip): ConnectInfo<Peer>, headers: HeaderMap, Json(body): Json<PasswordChangeRequest>) -> Result<String, Response> {
+    let from = origin(&ip, &headers);
+    let current = token_session(&headers);
+    let user = require_user(&state, headers).await.map_err(IntoResponse::into_response)?;
+    let has_password = !state.db.fetch_user(&user.handle).await
+        .map_err(|e| internal_error("fetching user")(e).into_response())?
+        .password_hash.is_empty();
+
+    // the old password is checked like a login, so it can't be guessed faster through here
+    if has_password {
+        let old_password = body.old_password.as_deref().unwrap_or_default();
+        match check_credentials(&state.db, &state.passwords, &state.login_throttle, &ip, &user.handle, old_password).await {
+            Ok(_) | Err(LoginError::Banned(_)) => {}, // banned users don't get this far, see require_user()
+            Err(LoginError::InvalidCredentials) => return Err(reject(StatusCode::FORBIDDEN, "the old password is wrong").into_response()),
+            Err(LoginError::Throttled(wait)) => return Err(throttled(wait)),
+            Err(_) => return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response()),
+        }
+    } else {
+        // a stolen access token alone shouldn't be enough to give the account a password
+        let fresh = session::logged_in_within(&state.db, &user.handle, current.as_deref().unwrap_or_default(), FRESH_LOGIN_WINDOW).await
+            .map_err(|_| reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response())?;
+        if !fresh {
+            let message = format!("log in again to set a password, it has to be within {} minutes of logging in", FRESH_LOGIN_WINDOW.as_secs() / 60);
+            return Err(reject(StatusCode::FORBIDDEN, &message).into_response())
+        }
+    }
+    validate_password(&body.new_password, &user.handle).map_err(|e| reject(StatusCode::BAD_REQUEST, &e).into_response())?;
+
+    let hash = state.passwords.hash(&body.new_password).map_err(|e| {
+        warn!("error hashing password: {}", e);
+        reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response()
+    })?;
+    state.db.set_password_hash(&user.handle, &hash).await.map_err(|e| internal_error("setting password")(e).into_response())?;
+
+    // whoever else knew the old password is logged out, this client starts over
+    let logged_out = session::end_all(&state.db, &user.handle).await;
+    let revoked = state.users.revoke_tokens(&state.db, &user.handle).await;
+    if logged_out.is_err() || revoked.is_err() {
+        warn!("error logging @{} out after a password change", user.handle);
+        return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "password changed, but logging out other sessions failed. Log out everywhere with /api/logout.").into_response())
+    }
+    state.connections.disconnect_others(&user.handle, current.as_deref(), "your password was changed, log in again");
+    info!("@{} {} their password", user.handle, if has_password {"changed"} else {"set"});
+
+    match session::start(&state.db, user, &from).await {
+        Ok(tokens) => Ok(tokens_response(tokens)),
+        Err(_) => Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "password changed, but logging in failed. Try /api/login.").into_response()),
+    }
+}
+
+/// Route for an admin to reset someone's password, returning a one-time code for them to set a new
+/// one with at /api/password-reset
+pub async fn reset_password(State(state): State<AppState>, headers: HeaderMap, Path(handle): Path<String>) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let admin = require_admin(&state, headers, "passwords").await?;
+    let target = match state.db.fetch_user(&handle).await {
+        Ok(entry) if entry.inner_user.user_type != UserMode::Bot => entry.inner_user,
+        _ => return Err(reject(StatusCode::NOT_FOUND, "no user with that handle")),
+    };
+    if !admin.outranks(&target) {
+        return Err(reject(StatusCode::FORBIDDEN, "you can only reset the passwords of people of a lower rank"))
+    }
+
+    let code = passwords::reset(&state.db, &handle, &admin.handle).await.map_err(|e| {
+        warn!("error resetting password: {}", e);
+        reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.")
+    })?;
+    state.users.invalidate(&handle);
+    state.connections.disconnect(&handle, "your password was reset, set a new one with the code from an admin");
+    info!("@{} reset the password of @{}", admin.handle, handle);
+
+    Ok((StatusCode::CREATED, json!({"error": false, "value": code, "expires_in_hours": passwords::RESET_CODE_LIFETIME_HOURS}).to_string()))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordResetRequest {
+    handle: String,
+    code: String, // from an admin
+    new_password: String,
+}
+
+/// Route to set a new password with a reset code, which logs in like login()
+pub async fn complete_password_reset(State(state): State<AppState>, ConnectInfo(ip): ConnectInfo<Peer>, headers: HeaderMap, Json(body): Json<PasswordResetRequest>) -> Result<String, Response> {
+    if let Some(wait) = state.login_throttle.check(&ip, &body.handle) {
+        return Err(throttled(wait))
+    }
+    validate_password(&body.new_password, &body.handle).map_err(|e| reject(StatusCode::BAD_REQUEST, &e).into_response())?;
+
+    match state.db.take_password_reset(&body.handle, &passwords::hash_reset_code(&body.code)).await.map_err(|e| e.to_string()) {
+        Ok(true) => {},
+        Ok(false) => {
+            state.login_throttle.failed(&ip, &body.handle);
+            return Err(reject(StatusCode::UNAUTHORIZED, "invalid or expired reset code, ask an admin for a new one").into_response())
+        },
+        Err(e) => {
+            warn!("error taking password reset: {}", e);
+            return Err(reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response())
+        },
+    }
+    state.login_throttle.succeeded(&body.handle);
+
+    let hash = state.passwords.hash(&body.new_password).map_err(|e| {
+        warn!("error hashing password: {}", e);
+        reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.").into_response()
+    })?;
+    state.db.set_password_hash(&body.handle, &hash).await.map_err(|e| internal_error("setting password")(e).into_response())?;
+    info!("@{} set a new password with a reset code", body.handle);
+
+    let user = current_user(&state, &body.handle).await?;
+    finish_login(&state, user, &origin(&ip, &headers)).await
+}
+
+/// the session of the access token in `x-auth-token`, to mark it in the list
+fn token_session(headers: &HeaderMap) -> Option<String> {
+    let token = headers.get("x-auth-token").and_then(|v| v.to_str().ok())?;
+    validate_claims(token).ok().map(|claims| claims.sid)
+}
+
+/// a user's sessions, with how many connections each has open and whether it's the one asking
+fn sessions_response(state: &AppState, handle: &str, sessions: Vec<Session>, current: Option<&str>) -> String {
+    let connections = state.connections.sessions_of(handle);
+    let sessions: Vec<_> = sessions.into_iter()
+        .map(|session| {
+            let mut value = json!(session);
+            value["connections"] = json!(connections.get(&session.id).copied().unwrap_or(0));
+            value["current"] = json!(current == Some(session.id.as_str()));
+            value
+        })
+        .collect();
+
+    json!({"error": false, "value": sessions}).to_string()
+}
+
+/// log one of a user's sessions out and close the connections that logged in with it
+async fn end_session(state: &AppState, handle: &str, id: &str) -> Result<(), (StatusCode, String)> {
+    let internal = || reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin.");
+    let sessions = session::list(&state.db, handle).await.map_err(|_| internal())?;
+    if !sessions.iter().any(|session| session.id == id) {
+        return Err(reject(StatusCode::NOT_FOUND, "no session with that id, it may have been logged out already"))
+    }
+
+    session::end(&state.db, id).await.map_err(|_| internal())?;
+    state.connections.disconnect_session(handle, id, "this session was logged out");
+
+    Ok(())
+}
+
+/// Route to list the user's sessions
+pub async fn list_sessions(State(state): State<AppState>, headers: HeaderMap) -> Result<String, (StatusCode, String)> {
+    let current = token_session(&headers);
+    let user = require_user(&state, headers).await?;
+    let sessions = session::list(&state.db, &user.handle).await
+        .map_err(|_| reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))?;
+
+    Ok(sessions_response(&state, &user.handle, sessions, current.as_deref()))
+}
+
+/// Route to log one of the user's sessions out, wherever it is
+pub async fn revoke_session(State(state): State<AppState>, headers: HeaderMap, Path(id): Path<String>) -> Result<String, (StatusCode, String)> {
+    let user = require_user(&state, headers).await?;
+    end_session(&state, &user.handle, &id).await?;
+    info!("@{} logged out one of their sessions", user.handle);
+
+    Ok(json!({"error": false}).to_string())
+}
+
+/// Route for admins to list anyone's sessions
+pub async fn list_user_sessions(State(state): State<AppState>, headers: HeaderMap, Path(handle): Path<String>) -> Result<String, (StatusCode, String)> {
+    let current = token_session(&headers);
+    require_admin(&state, headers, "other people's sessions").await?;
+    if state.db.fetch_user(&handle).await.is_err() {
+        return Err(reject(StatusCode::NOT_FOUND, "no user with that handle"))
+    }
+    let sessions = session::list(&state.db, &handle).await
+        .map_err(|_| reject(StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error. Please report to an admin."))?;
+
+    Ok(sessions_response(&state, &handle, sessions, current.as_deref()))
+}
+
+/// Route for admins to log out someone's session, like a narrower kick
+pub async fn revoke_user_session(State(state): State<AppState>, headers: HeaderMap, Path((handle, id)): Path<(String, String)>) -> Result<String, (StatusCode, String)> {
+    let admin = require_admin(&state, headers, "other people's sessions").await?;
+    if handle != admin.handle {
+        let outranked = state.db.fetch_user(&handle).await.map(|entry| admin.outranks(&entry.inner_user));
+        match outranked {
+            Ok(true) => {},
+            Ok(false) => return Err(reject(StatusCode::FORBIDDEN, "you can only log out the sessions of people of a lower rank")),
+            Err(_) => return Err(reject(StatusCode::NOT_FOUND, "no user with that handle")),
+        }
+    }
+
+    end_session(&state, &handle, &id).await?;
+    info!("@{} logged out a session of @{}", admin.handle, handle);
+
+    Ok(json!({"error": false}).to_string())
+}
+
+#[tokio::test]
+async fn test_change_password() {
+    use std::collections::HashMap;
+    use crate::backend::server::Server;
+    use crate::config::Config;
+    use crate::database::{test_database_config, test_user};
+
+    let state = Server::create_state(Config { database: test_database_config(), ..Config::default() }).await;
+    let mut alice = test_user("alice", UserPermissions::User);
+    alice.password_hash = state.passwords.hash("correct horse").unwrap();
+    let mut carol = test_user("carol", UserPermissions::User);
+    carol.password_hash = String::new(); // only logs in with an SSH key
+    let (alice_user, carol_user) = (alice.inner_user.clone(), carol.inner_user.clone());
+    state.db.add_user(alice).await.unwrap();
+    state.db.add_user(carol).await.unwrap();
+
+    let login = async |user: &User| {
+        let tokens = session::start(&state.db, user.clone(), &Origin::default()).await.unwrap();
+        let session = validate_claims(&tokens.access).unwrap().sid;
+        (tokens.access, session)
+    };
+    let change = |token: &str, old_password: Option<&str>| {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-auth-token", token.parse().unwrap());
+        let body = PasswordChangeRequest { old_password: old_password.map(str::to_string), new_password: "battery staple".to_string() };
+        change_password(State(state.clone()), ConnectInfo(Peer::Unix), headers, Json(body))
+    };
+
+    let (laptop_token, laptop) = login(&alice_user).await;
+    let (_, phone) = login(&alice_user).await;
+    let _laptop_socket = state.connections.register("alice", Some(&laptop));
+    let mut phone_socket = state.connections.register("alice", Some(&phone));
+    let mut line = state.connections.register("alice", None);
+
+    assert_eq!(change(&laptop_token, Some("wrong horse")).await.unwrap_err().status(), StatusCode::FORBIDDEN);
+    assert!(state.connections.sessions_of("alice").contains_key(&phone), "a wrong old password shouldn't log anyone out");
+    assert!(change(&laptop_token, Some("correct horse")).await.is_ok());
+    assert_eq!(phone_socket.disconnected().await, "your password was changed, log in again");
+    assert_eq!(line.disconnected().await, "your password was changed, log in again", "connections that logged in with the old password should close too");
+    assert_eq!(state.connections.sessions_of("alice"), HashMap::from([(laptop, 1)]), "the session changing it should stay connected");
+
+    // without a password, having just logged in is the proof
+    let (carol_token, _) = login(&carol_user).await;
+    assert!(change(&carol_token, None).await.is_ok());
+    assert!(!state.db.fetch_user("carol").await.unwrap().password_hash.is_empty());
+}
+
+#[tokio::test]
+async fn test_logout_closes_connections() {
+    use crate::backend::server::Server;
+    use crate::config::Config;
+    use crate::database::{test_database_config, test_user};
+
+    let state = Server::create_state(Config { database: test_database_config(), ..Config::default() }).await;
+    let alice = test_user("alice", UserPermissions::User);
+    let user = alice.inner_user.clone();
+    state.db.add_user(alice).await.unwrap();
+    let login = async || session::start(&state.db, user.clone(), &Origin::default()).await.unwrap().access;
+    let logout = |token: &str, body: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-auth-token", token.parse().unwrap());
+        logout(State(state.clone()), headers, body.to_string())
+    };
+
+    let (laptop, phone) = (login().await, login().await);
+    let mut laptop_socket = state.connections.register("alice", Some(&validate_claims(&laptop).unwrap().sid));
+    let mut phone_socket = state.connections.register("alice", Some(&validate_claims(&phone).unwrap().sid));
+    let mut line = state.connections.register("alice", None);
+
+    logout(&laptop, "").await.unwrap();
+    assert_eq!(laptop_socket.disconnected().await, "this session was logged out");
+    assert_eq!(state.connections.sessions_of("alice").len(), 1, "other sessions should stay connected");
+
+    logout(&phone, r#"{"everywhere": true}"#).await.unwrap();
+    assert_eq!(phone_socket.disconnected().await, "you were logged out everywhere");
+    assert_eq!(line.disconnected().await, "you were logged out everywhere");
 }
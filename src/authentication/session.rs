@@ -0,0 +1,265 @@
+//! Login sessions
+//!
+//! Logging in starts a session: a short lived access token (the JWT, see token.rs) and a long lived
+//! refresh token. The refresh token is swapped for a new pair at `/api/token/refresh`, and every
+//! refresh token works once. Seeing one again means it was copied, so the whole session is
+//! revoked. Only a hash of each refresh token is stored, a leaked database doesn't log anyone in.
+//!
+//! Revoking a session also revokes its access tokens (they carry the session id), which
+//! validate_claims() checks against an in-memory list that's reloaded from the database at startup.
+//! To throw out every token of a user at once, bump their token version (see users.rs).
+//!
+//! Each session remembers when it started and where (IP and User-Agent) it was last logged in or
+//! refreshed from, so users can tell their sessions apart when listing them at `/api/sessions`.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::authentication::random::random_token;
+use crate::authentication::token::{self, create_token};
+use crate::authentication::user::User;
+use crate::database::database::{Ban, DBCalls, RefreshToken, Session};
+
+const SESSION_ID_LENGTH: usize = 24;
+const REFRESH_TOKEN_LENGTH: usize = 48;
+
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    Invalid, // unknown, expired or revoked refresh token
+    Reused, // a refresh token that was already used, the session has been revoked
+    Banned(Ban), // the session stays, refreshing works again once the ban is over
+    Internal, // not the user's fault, details are logged
+}
+
+/// what a client gets when logging in or refreshing
+#[derive(Debug)]
+pub struct Tokens {
+    pub access: String,
+    pub refresh: String,
+}
+
+/// where a session is being used from
+#[derive(Debug, Clone, Default)]
+pub struct Origin {
+    pub ip: String,
+    pub client: Option<String>, // the User-Agent, if any
+}
+
+/// refresh tokens are long and random, so a plain (fast) hash is enough
+fn hash(refresh_token: &str) -> String {
+    hex::encode(Sha256::digest(refresh_token.as_bytes()))
+}
+
+/// start a new session for someone who just proved who they are
+pub async fn start(db: &impl DBCalls, user: User, origin: &Origin) -> Result<Tokens, SessionError> {
+    let token_version = db.fetch_user(&user.handle).await
+        .map(|entry| entry.token_version)
+        .map_err(|e| {
+            warn!("error fetching token version: {}", e);
+            SessionError::Internal
+        })?;
+
+    let session = random_token(SESSION_ID_LENGTH);
+    let tokens = issue(db, &user.handle, token_version, &session).await?;
+    record(db, &user.handle, &session, origin).await;
+
+    Ok(tokens)
+}
+
+/// note where the session was used from. It works either way, so failing is only logged.
+async fn record(db: &impl DBCalls, handle: &str, session: &str, origin: &Origin) {
+    let now = Utc::now().timestamp();
+    let entry = Session {
+        id: session.to_string(),
+        handle: handle.to_string(),
+        created_at: Some(now), // kept when the session already exists
+        last_used_at: Some(now),
+        last_ip: Some(origin.ip.clone()),
+        client: origin.client.clone(),
+    };
+    if let Err(e) = db.record_session(&entry).await {
+        warn!("error recording session: {}", e);
+    }
+}
+
+async fn issue(db: &impl DBCalls, handle: &str, token_version: u32, session: &str) -> Result<Tokens, SessionError> {
+    let refresh = random_token(REFRESH_TOKEN_LENGTH);
+    let entry = RefreshToken {
+        token_hash: hash(&refresh),
+        session: session.to_string(),
+        handle: handle.to_string(),
+        expires_at: (Utc::now() + token::refresh_lifetime()).timestamp(),
+        used: false,
+        revoked: false,
+    };
+    db.add_refresh_token(&entry).await.map_err(|e| {
+        warn!("error storing refresh token: {}", e);
+        SessionError::Internal
+    })?;
+
+    let access = create_token(handle, token_version, session, None).map_err(|_| {
+        warn!("error creating token");
+        SessionError::Internal
+    })?;
+
+    Ok(Tokens { access, refresh })
+}
+
+/// the refresh token's entry, if it's one that could still be used
+async fn lookup(db: &impl DBCalls, refresh_token: &str) -> Result<RefreshToken, SessionError> {
+    let entry = db.fetch_refresh_token(&hash(refresh_token)).await.map_err(|e| {
+        warn!("error fetching refresh token: {}", e);
+        SessionError::Internal
+    })?;
+
+    match entry {
+        Some(entry) if !entry.revoked && entry.expires_at > Utc::now().timestamp() => Ok(entry),
+        _ => Err(SessionError::Invalid),
+    }
+}
+
+/// swap a refresh token for a new access and refresh token, with up to date user details
+pub async fn refresh(db: &impl DBCalls, refresh_token: &str, origin: &Origin) -> Result<(User, Tokens), SessionError> {
+    let entry = lookup(db, refresh_token).await?;
+    if entry.used {
+        return reused(db, &entry).await;
+    }
+
+    // the account may have been changed (or deleted) since the last refresh
+    let (user, token_version) = db.fetch_user(&entry.handle).await
+        .map(|entry| (entry.inner_user, entry.token_version))
+        .map_err(|e| {
+            warn!("refresh for a missing user: {}", e);
+            SessionError::Invalid
+        })?;
+    // checked before using the token up, so the session survives a temporary ban
+    if user.banned {
+        match db.fetch_ban(&user.handle).await {
+            Ok(Some(ban)) => return Err(SessionError::Banned(ban)),
+            Ok(None) => {}, // expired between the two queries
+            Err(e) => {
+                warn!("error fetching ban: {}", e);
+                return Err(SessionError::Internal);
+            },
+        }
+    }
+
+    let first_use = db.use_refresh_token(&entry.token_hash).await.map_err(|e| {
+        warn!("error using refresh token: {}", e);
+        SessionError::Internal
+    })?;
+    if !first_use {
+        return reused(db, &entry).await;
+    }
+
+    let tokens = issue(db, &user.handle, token_version, &entry.session).await?;
+    record(db, &user.handle, &entry.session, origin).await;
+    Ok((user, tokens))
+}
+
+/// either the client or whoever copied the token used it already, no telling which
+async fn reused<T>(db: &impl DBCalls, entry: &RefreshToken) -> Result<T, SessionError> {
+    warn!("refresh token of @{} was used twice, revoking the session", entry.handle);
+    end(db, &entry.session).await?;
+    Err(SessionError::Reused)
+}
+
+/// the session a refresh token belongs to, and whose it is
+pub async fn session_of(db: &impl DBCalls, refresh_token: &str) -> Result<(String, String), SessionError> {
+    lookup(db, refresh_token).await.map(|entry| (entry.session, entry.handle))
+}
+
+/// log a session out: its refresh tokens stop working and so do its access tokens
+pub async fn end(db: &impl DBCalls, session: &str) -> Result<(), SessionError> {
+    let until = token::revocation_deadline();
+    token::revoke(session, until);
+
+    db.revoke_session(session, until).await.map_err(|e| {
+        warn!("error revoking session: {}", e);
+        SessionError::Internal
+    })
+}
+
+/// log every session of a user out, returning how many there were
+pub async fn end_all(db: &impl DBCalls, handle: &str) -> Result<usize, SessionError> {
+    let sessions = db.fetch_sessions(handle).await.map_err(|e| {
+        warn!("error fetching sessions: {}", e);
+        SessionError::Internal
+    })?;
+
+    for session in &sessions {
+        end(db, session).await?;
+    }
+
+    Ok(sessions.len())
+}
+
+/// the sessions of a user that are still logged in, the most recently used first
+pub async fn list(db: &impl DBCalls, handle: &str) -> Result<Vec<Session>, SessionError> {
+    db.fetch_session_details(handle).await.map_err(|e| {
+        warn!("error fetching sessions: {}", e);
+        SessionError::Internal
+    })
+}
+
+/// whether a session of a user was started (logging in, not refreshing) within `window`
+pub async fn logged_in_within(db: &impl DBCalls, handle: &str, session: &str, window: Duration) -> Result<bool, SessionError> {
+    let since = Utc::now().timestamp() - window.as_secs() as i64;
+    let sessions = list(db, handle).await?;
+
+    Ok(sessions.iter().any(|s| s.id == session && s.created_at.is_some_and(|at| at > since)))
+}
+
+/// reload revocations that are still relevant, call once at startup
+pub async fn load_revocations(db: &impl DBCalls) {
+    match db.fetch_revoked_sessions().await {
+        Ok(revoked) => {
+            for (session, until) in &revoked {
+                token::revoke(session, *until);
+            }
+            if !revoked.is_empty() { info!("loaded {} revoked sessions", revoked.len()); }
+        },
+        Err(e) => warn!("unable to load revoked sessions, logged out tokens may work until they expire: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_refresh_rotation_and_reuse() {
+    use crate::authentication::token::validate_claims;
+    use crate::authentication::user::UserPermissions;
+    use crate::database::{test_db, test_user};
+
+    let db = test_db().await;
+    let entry = test_user("alice", UserPermissions::User);
+    let user = entry.inner_user.clone();
+    db.add_user(entry).await.unwrap();
+
+    let origin = Origin { ip: "127.0.0.1".to_string(), client: Some("curl/8.0".to_string()) };
+    let first = start(&db, user, &origin).await.unwrap();
+    assert!(validate_claims(&first.access).is_ok());
+
+    let (_, second) = refresh(&db, &first.refresh, &Origin { ip: "10.0.0.2".to_string(), client: None }).await.expect("a fresh refresh token should work");
+    let sessions = list(&db, "alice").await.unwrap();
+    assert_eq!(sessions.len(), 1, "refreshing should keep the session");
+    assert_eq!((sessions[0].last_ip.as_deref(), sessions[0].client.as_deref()), (Some("10.0.0.2"), Some("curl/8.0")), "refreshing should update where it was last used");
+    assert!(validate_claims(&second.access).is_ok());
+    let session = validate_claims(&second.access).unwrap().sid;
+    assert!(logged_in_within(&db, "alice", &session, Duration::from_secs(60)).await.unwrap());
+    assert!(!logged_in_within(&db, "alice", &session, Duration::ZERO).await.unwrap());
+    assert!(!logged_in_within(&db, "bob", &session, Duration::from_secs(60)).await.unwrap());
+
+    // someone replays the first refresh token
+    assert_eq!(refresh(&db, &first.refresh, &origin).await.unwrap_err(), SessionError::Reused);
+    assert_eq!(refresh(&db, &second.refresh, &origin).await.unwrap_err(), SessionError::Invalid, "reuse should revoke the whole session");
+    assert!(list(&db, "alice").await.unwrap().is_empty(), "revoked sessions shouldn't be listed");
+    assert!(validate_claims(&second.access).is_err(), "reuse should revoke the session's access tokens too");
+
+    assert_eq!(refresh(&db, "made up", &origin).await.unwrap_err(), SessionError::Invalid);
+
+    let other = start(&db, db.fetch_user("alice").await.unwrap().inner_user, &origin).await.unwrap();
+    assert_eq!(end_all(&db, "alice").await.unwrap(), 1);
+    assert!(validate_claims(&other.access).is_err(), "logging out everywhere should revoke every session");
+}
@@ -0,0 +1,220 @@
+//! Keys that sign and verify access tokens
+//!
+//! Without `auth.signing_keys` tokens are HS512 with `auth.jwt_secret`, which anything that wants
+//! to verify them has to know. With them, tokens are signed with the first private key (EdDSA or
+//! ES256) and carry its id as `kid`, and every listed key verifies the tokens with its id. Only the
+//! public halves are published, at `/.well-known/jwks.json`. To rotate, put the new key first and
+//! keep the old one (its public key is enough) until the tokens it signed have expired.
+
+use aws_lc_rs::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, Ed25519KeyPair, KeyPair};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio_rustls::rustls::pki_types::{PrivatePkcs8KeyDer, SubjectPublicKeyInfoDer, pem::PemObject};
+
+use crate::config::{AuthConfig, SigningKeyConfig};
+
+/// DER prefix of an Ed25519 SubjectPublicKeyInfo, the 32 byte key follows
+const ED25519_SPKI_PREFIX: &[u8] = &[0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+/// DER prefix of a P-256 SubjectPublicKeyInfo, the 65 byte uncompressed point follows
+const P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01,
+    0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SigningAlgorithm {
+    EdDSA,
+    ES256,
+}
+impl SigningAlgorithm {
+    fn jwt(self) -> Algorithm {
+        match self {
+            SigningAlgorithm::EdDSA => Algorithm::EdDSA,
+            SigningAlgorithm::ES256 => Algorithm::ES256,
+        }
+    }
+}
+
+struct VerifyingKey {
+    id: Option<String>, // None for the HS512 secret
+    algorithm: Algorithm,
+    key: DecodingKey,
+    jwk: Option<Value>, // None for the HS512 secret, it's never published
+}
+
+/// every key tokens are signed or verified with, checked when the server starts
+pub struct Keyring {
+    signing_id: Option<String>,
+    signing_algorithm: Algorithm,
+    signing_key: EncodingKey,
+    verifying: Vec<VerifyingKey>,
+}
+impl Keyring {
+    pub fn load(auth: &AuthConfig) -> Result<Self, String> {
+        let mut signing = None;
+        let mut verifying = Vec::new();
+        for config in &auth.signing_keys {
+            let (private, public) = load_key(config).map_err(|e| format!("signing key `{}` ({}): {}", config.id, config.file.display(), e))?;
+            if signing.is_none() && let Some(private) = private {
+                signing = Some((Some(config.id.clone()), config.algorithm.jwt(), private));
+            }
+            verifying.push(public);
+        }
+        if !auth.signing_keys.is_empty() && signing.is_none() {
+            return Err("none of `auth.signing_keys` is a private key, there's nothing to sign tokens with".to_string());
+        }
+
+        // the secret still verifies the tokens it signed while switching to signing keys
+        if let Some(secret) = &auth.jwt_secret {
+            verifying.push(VerifyingKey { id: None, algorithm: Algorithm::HS512, key: DecodingKey::from_secret(secret.as_bytes()), jwk: None });
+            signing.get_or_insert_with(|| (None, Algorithm::HS512, EncodingKey::from_secret(secret.as_bytes())));
+        }
+
+        let (signing_id, signing_algorithm, signing_key) = signing
+            .ok_or("no JWT secret or signing keys configured. Set `auth.signing_keys` or `auth.jwt_secret` in the config file, or the JWT_SECRET env variable, a quick fix is `JWT_SECRET=\">>your secret phrase here<<\" cargo run`")?;
+
+        Ok(Keyring { signing_id, signing_algorithm, signing_key, verifying })
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.signing_algorithm);
+        header.kid = self.signing_id.clone();
+
+        jsonwebtoken::encode(&header, claims, &self.signing_key)
+    }
+
+    /// check a token's signature (with the key its `kid` names) and expiry
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T, Box<dyn std::error::Error>> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = self.verifying.iter()
+            .find(|key| key.id == header.kid)
+            .ok_or("token signed with an unknown key")?;
+
+        Ok(jsonwebtoken::decode::<T>(token, &key.key, &Validation::new(key.algorithm))?.claims)
+    }
+
+    /// the public keys as a JSON Web Key Set
+    pub fn jwks(&self) -> Value {
+        json!({"keys": self.verifying.iter().filter_map(|key| key.jwk.clone()).collect::<Vec<_>>()})
+    }
+}
+
+/// a key file: a PKCS#8 private key signs and verifies, a public key only verifies
+fn load_key(config: &SigningKeyConfig) -> Result<(Option<EncodingKey>, VerifyingKey), String> {
+    let pem = std::fs::read(&config.file).map_err(|e| e.to_string())?;
+    let is_private = String::from_utf8_lossy(&pem).contains("PRIVATE KEY-----");
+
+    let (private, public) = match is_private {
+        true => {
+            let der = PrivatePkcs8KeyDer::from_pem_slice(&pem)
+                .map_err(|e| format!("expected a PKCS#8 private key ({})", e))?;
+            private_key(config.algorithm, der.secret_pkcs8_der())?
+        },
+        false => {
+            let der = SubjectPublicKeyInfoDer::from_pem_slice(&pem)
+                .map_err(|e| format!("expected a private or public key ({})", e))?;
+            (None, public_key(config.algorithm, der.as_ref())?)
+        },
+    };
+
+    let (key, mut jwk) = match config.algorithm {
+        SigningAlgorithm::EdDSA => {
+            let x = URL_SAFE_NO_PAD.encode(&public);
+            (DecodingKey::from_ed_components(&x), json!({"kty": "OKP", "crv": "Ed25519", "x": x}))
+        },
+        SigningAlgorithm::ES256 => {
+            // an uncompressed point, 0x04 then x and y
+            let x = URL_SAFE_NO_PAD.encode(&public[1..33]);
+            let y = URL_SAFE_NO_PAD.encode(&public[33..]);
+            (DecodingKey::from_ec_components(&x, &y), json!({"kty": "EC", "crv": "P-256", "x": x, "y": y}))
+        },
+    };
+    let key = key.map_err(|e| e.to_string())?;
+    jwk["kid"] = json!(config.id);
+    jwk["alg"] = json!(config.algorithm);
+    jwk["use"] = json!("sig");
+
+    Ok((private, VerifyingKey { id: Some(config.id.clone()), algorithm: config.algorithm.jwt(), key, jwk: Some(jwk) }))
+}
+
+/// the signing key and its raw public key
+fn private_key(algorithm: SigningAlgorithm, der: &[u8]) -> Result<(Option<EncodingKey>, Vec<u8>), String> {
+    let public = match algorithm {
+        SigningAlgorithm::EdDSA => Ed25519KeyPair::from_pkcs8(der)
+            .map(|pair| pair.public_key().as_ref().to_vec()),
+        SigningAlgorithm::ES256 => EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, der)
+            .map(|pair| pair.public_key().as_ref().to_vec()),
+    }
+        .map_err(|e| format!("not a valid {:?} key ({})", algorithm, e))?;
+
+    let private = match algorithm {
+        SigningAlgorithm::EdDSA => EncodingKey::from_ed_der(der),
+        SigningAlgorithm::ES256 => EncodingKey::from_ec_der(der),
+    };
+
+    Ok((Some(private), public))
+}
+
+/// the raw public key out of a SubjectPublicKeyInfo
+fn public_key(algorithm: SigningAlgorithm, der: &[u8]) -> Result<Vec<u8>, String> {
+    let (prefix, length) = match algorithm {
+        SigningAlgorithm::EdDSA => (ED25519_SPKI_PREFIX, 32),
+        SigningAlgorithm::ES256 => (P256_SPKI_PREFIX, 65),
+    };
+
+    der.strip_prefix(prefix)
+        .filter(|key| key.len() == length)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| format!("not a {:?} public key", algorithm))
+}
+
+#[test]
+fn test_key_rotation() {
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Claims { exp: usize, sub: String }
+
+    let directory = std::env::temp_dir().join(format!("trcd-test-key-rotation-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let write = |name: &str, pem: String| {
+        std::fs::write(directory.join(name), pem).unwrap();
+        SigningKeyConfig { id: name.to_string(), algorithm: SigningAlgorithm::EdDSA, file: directory.join(name) }
+    };
+
+    let old = rcgen::KeyPair::generate_for(&rcgen::PKCS_ED25519).unwrap();
+    let new = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+    let old_private = write("old", old.serialize_pem());
+    let old_public = write("old-public", old.public_key_pem());
+    let new_private = SigningKeyConfig { algorithm: SigningAlgorithm::ES256, ..write("new", new.serialize_pem()) };
+
+    let claims = Claims { exp: chrono::Utc::now().timestamp() as usize + 60, sub: "alice".to_string() };
+    let auth = |keys: Vec<SigningKeyConfig>, secret: Option<&str>| AuthConfig { signing_keys: keys, jwt_secret: secret.map(str::to_string), ..AuthConfig::default() };
+
+    let before = Keyring::load(&auth(vec![old_private.clone()], Some("secret"))).unwrap();
+    let old_token = before.sign(&claims).unwrap();
+    assert_eq!(jsonwebtoken::decode_header(&old_token).unwrap().kid.as_deref(), Some("old"));
+
+    // the public half of the old key keeps its tokens working after the new key takes over
+    let after = Keyring::load(&auth(vec![new_private.clone(), SigningKeyConfig { id: "old".to_string(), ..old_public }], None)).unwrap();
+    let new_token = after.sign(&claims).unwrap();
+    assert_eq!(after.verify::<Claims>(&old_token).unwrap(), claims);
+    assert_eq!(after.verify::<Claims>(&new_token).unwrap(), claims);
+    assert!(before.verify::<Claims>(&new_token).is_err(), "a token signed with an unknown key should be rejected");
+
+    let secret_token = Keyring::load(&auth(vec![], Some("secret"))).unwrap().sign(&claims).unwrap();
+    assert!(before.verify::<Claims>(&secret_token).is_ok(), "the secret should still verify its tokens while switching");
+    assert!(after.verify::<Claims>(&secret_token).is_err());
+
+    let jwks = after.jwks();
+    let ids: Vec<&str> = jwks["keys"].as_array().unwrap().iter().map(|k| k["kid"].as_str().unwrap()).collect();
+    assert_eq!(ids, ["new", "old"]);
+    assert!(jwks["keys"][0].get("d").is_none() && before.jwks()["keys"].as_array().unwrap().len() == 1, "only public keys should be published");
+
+    let mismatched = SigningKeyConfig { algorithm: SigningAlgorithm::ES256, ..old_private };
+    assert!(Keyring::load(&auth(vec![mismatched], None)).is_err(), "a key of the wrong type should be caught at startup");
+    assert!(Keyring::load(&auth(vec![write("public-only", new.public_key_pem())], None)).is_err(), "something has to sign");
+    assert!(Keyring::load(&auth(vec![], None)).is_err());
+}
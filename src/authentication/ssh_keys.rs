@@ -0,0 +1,246 @@
+//! Logging in with an SSH key
+//!
+//! Users register the public halves of their ed25519 SSH keys (the `ssh-ed25519 AAAA... comment`
+//! line of `~/.ssh/id_ed25519.pub`). To log in they ask for a challenge, sign it with
+//! `ssh-keygen -Y sign -n trcd-login` and send the signature back, which starts a session like a
+//! password would. Accounts can be registered with a key instead of a password, so people who live
+//! in a terminal never need one. Only the signature format (PROTOCOL.sshsig in OpenSSH) is
+//! implemented, and only for ed25519.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use aws_lc_rs::signature::{UnparsedPublicKey, ED25519};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::authentication::random::random_token;
+use crate::backend::listener::Peer;
+use crate::database::database::SshKey;
+
+/// what `ssh-keygen -Y sign -n` has to be given, so signatures made for something else don't work
+pub const NAMESPACE: &str = "trcd-login";
+const KEY_TYPE: &str = "ssh-ed25519";
+const KEY_ID_LENGTH: usize = 12;
+/// most keys a user can register
+pub const MAX_SSH_KEYS: usize = 16;
+/// longest key comment kept, it's only there to tell keys apart
+const MAX_COMMENT_LENGTH: usize = 64;
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(2 * 60);
+/// most challenges waiting to be used at once, anyone can ask for one without logging in
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+/// most challenges waiting to be used for one IP. Unix socket peers (a reverse proxy) only count
+/// towards MAX_PENDING_CHALLENGES.
+const MAX_CHALLENGES_PER_IP: usize = 16;
+const CHALLENGE_LENGTH: usize = 32;
+
+/// an ed25519 public key in SSH's wire format
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKey {
+    blob: Vec<u8>,
+}
+impl PublicKey {
+    /// a line of an `.pub` or `authorized_keys` file, returns the key and its comment
+    pub fn parse(line: &str) -> Result<(Self, String), String> {
+        let mut parts = line.trim().splitn(3, char::is_whitespace);
+        let (key_type, key) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+        if key_type != KEY_TYPE {
+            return Err(format!("only {} keys are supported (the line of your `.pub` file, starting with `{}`)", KEY_TYPE, KEY_TYPE));
+        }
+
+        let blob = STANDARD.decode(key).map_err(|_| "the key isn't valid base64".to_string())?;
+        Self::from_blob(blob).map(|key| {
+            let comment: String = parts.next().unwrap_or_default().trim().chars().take(MAX_COMMENT_LENGTH).collect();
+            (key, comment)
+        })
+    }
+
+    fn from_blob(blob: Vec<u8>) -> Result<Self, String> {
+        let mut reader = Reader(&blob);
+        if reader.string()? != KEY_TYPE.as_bytes() || reader.string()?.len() != 32 || !reader.0.is_empty() {
+            return Err(format!("not a valid {} key", KEY_TYPE));
+        }
+
+        Ok(PublicKey { blob })
+    }
+
+    /// the raw 32 byte key
+    fn raw(&self) -> &[u8] {
+        &self.blob[self.blob.len() - 32..]
+    }
+
+    /// `ssh-ed25519 AAAA...`, without a comment
+    pub fn openssh(&self) -> String {
+        format!("{} {}", KEY_TYPE, STANDARD.encode(&self.blob))
+    }
+
+    /// the same as `ssh-keygen -l` shows
+    pub fn fingerprint(&self) -> String {
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(&self.blob)))
+    }
+
+    /// what's stored of a key someone registers
+    pub fn entry(&self, handle: &str, comment: &str) -> SshKey {
+        SshKey {
+            id: random_token(KEY_ID_LENGTH),
+            handle: handle.to_string(),
+            key: self.openssh(),
+            fingerprint: self.fingerprint(),
+            comment: comment.to_string(),
+            created_at: chrono::Utc::now().timestamp(),
+        }
+    }
+}
+
+/// reads SSH wire format strings (a u32 length and that many bytes)
+struct Reader<'a>(&'a [u8]);
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < length {
+            return Err("truncated".to_string());
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().expect("4 bytes")))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], String> {
+        let length = self.u32()? as usize;
+        self.bytes(length)
+    }
+}
+
+fn put_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// check an armored `ssh-keygen -Y sign` signature of `message`, returning the key that made it.
+/// Whether that key is one the user registered is up to the caller.
+pub fn verify_signature(armored: &str, message: &[u8]) -> Result<PublicKey, String> {
+    let body: String = armored.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let blob = STANDARD.decode(body).map_err(|_| "expected the output of `ssh-keygen -Y sign`".to_string())?;
+
+    let mut reader = Reader(&blob);
+    if reader.bytes(6)? != b"SSHSIG" || reader.u32()? != 1 {
+        return Err("expected the output of `ssh-keygen -Y sign`".to_string());
+    }
+    let key = PublicKey::from_blob(reader.string()?.to_vec())?;
+    let namespace = reader.string()?;
+    let reserved = reader.string()?;
+    let hash_algorithm = reader.string()?;
+    let mut signature = Reader(reader.string()?);
+    if namespace != NAMESPACE.as_bytes() {
+        return Err(format!("the signature is for another namespace, sign with `-n {}`", NAMESPACE));
+    }
+    if signature.string()? != KEY_TYPE.as_bytes() {
+        return Err(format!("only {} signatures are supported", KEY_TYPE));
+    }
+    let signature = signature.string()?;
+
+    let hash = match hash_algorithm {
+        b"sha512" => Sha512::digest(message).to_vec(),
+        b"sha256" => Sha256::digest(message).to_vec(),
+        _ => return Err("unsupported hash algorithm".to_string()),
+    };
+    let mut signed = b"SSHSIG".to_vec();
+    for field in [NAMESPACE.as_bytes(), reserved, hash_algorithm, &hash] {
+        put_string(&mut signed, field);
+    }
+
+    UnparsedPublicKey::new(&ED25519, key.raw())
+        .verify(&signed, signature)
+        .map_err(|_| "invalid signature".to_string())?;
+
+    Ok(key)
+}
+
+/// challenges handed out and not used yet, with whose login they're for. Each works once.
+#[derive(Debug, Clone, Default)]
+pub struct SshChallenges {
+    pending: Arc<RwLock<HashMap<String, Challenge>>>,
+}
+
+#[derive(Debug)]
+struct Challenge {
+    handle: String,
+    ip: Option<IpAddr>, // who asked for it, None over the unix socket
+    expires: Instant,
+}
+
+impl SshChallenges {
+    /// a new challenge for logging in as `handle`, None if `ip` (or everyone) has too many waiting
+    pub fn start(&self, handle: &str, ip: &Peer) -> Option<String> {
+        let ip = match ip {
+            Peer::Tcp(address) => Some(address.ip()),
+            Peer::Unix => None,
+        };
+        let mut pending = self.pending.write().unwrap();
+        let now = Instant::now();
+        pending.retain(|_, challenge| challenge.expires > now);
+        let from_ip = || pending.values().filter(|challenge| ip.is_some() && challenge.ip == ip).count();
+        if pending.len() >= MAX_PENDING_CHALLENGES || from_ip() >= MAX_CHALLENGES_PER_IP {
+            return None;
+        }
+
+        let challenge = random_token(CHALLENGE_LENGTH);
+        pending.insert(challenge.clone(), Challenge { handle: handle.to_string(), ip, expires: now + CHALLENGE_LIFETIME });
+        Some(challenge)
+    }
+
+    /// whose login the challenge is for, None if it's unknown, expired or already used
+    pub fn take(&self, challenge: &str) -> Option<String> {
+        self.pending.write().unwrap().remove(challenge)
+            .filter(|challenge| challenge.expires > Instant::now())
+            .map(|challenge| challenge.handle)
+    }
+}
+
+#[test]
+fn test_ssh_signature() {
+    // made with `ssh-keygen -t ed25519` and `printf 'trcd test challenge' | ssh-keygen -Y sign -f k -n trcd-login`
+    let (key, comment) = PublicKey::parse("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGa3kpgca6hKqc/VkCRhJZUn5ipVhFKVSikqG55WjZQz alice@laptop").unwrap();
+    let signature = "-----BEGIN SSH SIGNATURE-----
+U1NIU0lHAAAAAQAAADMAAAALc3NoLWVkMjU1MTkAAAAgZreSmBxrqEqpz9WQJGEllSfmKl
+WEUpVKKSobnlaNlDMAAAAKdHJjZC1sb2dpbgAAAAAAAAAGc2hhNTEyAAAAUwAAAAtzc2gt
+ZWQyNTUxOQAAAECMKyBKIkp3zgDmHf2ZEMI8Cu6Eqc4DUzYQwG+s+pEbyLgJcNw08dA8r6
+iP9KJ/PLWKNdfE1G1uJDTucgF5148A
+-----END SSH SIGNATURE-----
+";
+    assert_eq!(comment, "alice@laptop");
+    assert_eq!(key.fingerprint(), "SHA256:C1Hynvyhg7nnuQrXvJQZP36huoSq6psZJWfbOcZUGec", "fingerprints should match ssh-keygen -l");
+    assert_eq!(verify_signature(signature, b"trcd test challenge").unwrap(), key);
+    assert!(verify_signature(signature, b"another challenge").is_err());
+    assert!(verify_signature(&signature.replace("AAAAKdHJjZC1sb2dpbg", "AAAAKdHJjZC1sb2dpbh"), b"trcd test challenge").is_err(), "the namespace is signed");
+
+    assert!(PublicKey::parse("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQ").is_err());
+    assert!(PublicKey::parse("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGa3kpgca6hKqc").is_err(), "truncated keys should be refused");
+}
+
+#[test]
+fn test_challenge_limits() {
+    let challenges = SshChallenges::default();
+    let (ip, other) = (Peer::Tcp(([10, 0, 0, 1], 4000).into()), Peer::Tcp(([10, 0, 0, 2], 4000).into()));
+
+    let first = challenges.start("alice", &ip).unwrap();
+    for _ in 1..MAX_CHALLENGES_PER_IP {
+        challenges.start("alice", &ip).unwrap();
+    }
+    assert_eq!(challenges.start("bob", &ip), None, "an IP shouldn't be able to pile up challenges");
+    assert!(challenges.start("bob", &other).is_some());
+    assert!(challenges.start("bob", &Peer::Unix).is_some());
+
+    assert_eq!(challenges.take(&first).as_deref(), Some("alice"));
+    assert!(challenges.start("bob", &ip).is_some(), "using a challenge should make room for another");
+    assert_eq!(challenges.take(&first), None);
+}
@@ -0,0 +1,150 @@
+//! Slowing down password guessing
+//!
+//! Failed logins are counted per IP and per handle (whether or not the handle exists, so throttling
+//! doesn't give away who does). After `auth.login_free_attempts` failures every further attempt has
+//! to wait, starting at a second and doubling each time, until the wait reaches
+//! `auth.login_lockout_minutes` and the IP or account is locked out for that long. Failures are
+//! forgotten once there haven't been any for that long, and a successful login clears its account
+//! (but not its IP, or one valid account would reset the IP's count). Connections over the unix
+//! socket all come from the same (reverse proxy) peer, so they're only throttled per account.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::backend::listener::Peer;
+use crate::config::AuthConfig;
+
+pub const LOGIN_FREE_ATTEMPTS: u32 = 5; // default, see config.rs
+pub const LOGIN_LOCKOUT_MINUTES: u64 = 15; // default, see config.rs
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+enum Key {
+    Ip(IpAddr),
+    Account(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoginThrottle {
+    failures: Arc<RwLock<HashMap<Key, Failures>>>,
+    free_attempts: u32,
+    lockout: Duration,
+}
+impl LoginThrottle {
+    pub fn new(auth: &AuthConfig) -> Self {
+        LoginThrottle {
+            failures: Default::default(),
+            free_attempts: auth.login_free_attempts,
+            lockout: Duration::from_secs(auth.login_lockout_minutes * 60),
+        }
+    }
+
+    fn keys(ip: &Peer, handle: &str) -> Vec<Key> {
+        let mut keys = vec![Key::Account(handle.to_string())];
+        if let Peer::Tcp(address) = ip {
+            keys.push(Key::Ip(address.ip()));
+        }
+        keys
+    }
+
+    /// how long to wait after `count` failures in a row
+    fn wait(&self, count: u32) -> Duration {
+        match count.checked_sub(self.free_attempts) {
+            None => Duration::ZERO,
+            // 2^20 seconds is far past any lockout, and doesn't overflow
+            Some(over) => Duration::from_secs(1 << over.min(20)).min(self.lockout),
+        }
+    }
+
+    /// how much longer `ip` has to wait before trying `handle`, if at all
+    pub fn check(&self, ip: &Peer, handle: &str) -> Option<Duration> {
+        self.check_at(ip, handle, Instant::now())
+    }
+
+    fn check_at(&self, ip: &Peer, handle: &str, now: Instant) -> Option<Duration> {
+        let failures = self.failures.read().unwrap();
+        Self::keys(ip, handle).iter()
+            .filter_map(|key| failures.get(key))
+            .map(|f| self.wait(f.count).saturating_sub(now.duration_since(f.last)))
+            .filter(|remaining| !remaining.is_zero())
+            .max()
+    }
+
+    pub fn failed(&self, ip: &Peer, handle: &str) {
+        self.failed_at(ip, handle, Instant::now())
+    }
+
+    fn failed_at(&self, ip: &Peer, handle: &str, now: Instant) {
+        let mut failures = self.failures.write().unwrap();
+        // forget whoever has been quiet for a lockout
+        failures.retain(|_, f| now.duration_since(f.last) < self.lockout);
+
+        for key in Self::keys(ip, handle) {
+            let entry = failures.entry(key.clone()).or_insert(Failures { count: 0, last: now });
+            entry.count += 1;
+            entry.last = now;
+
+            // tell the admins once when throttling starts and once when it locks the account
+            if let Key::Account(handle) = key {
+                if entry.count == self.free_attempts {
+                    warn!("{} failed logins in a row for @{} (latest from ip: {}), throttling further attempts", entry.count, handle, ip);
+                } else if self.wait(entry.count) == self.lockout && self.wait(entry.count - 1) < self.lockout {
+                    warn!("@{} is locked out for {} minutes after {} failed logins (latest from ip: {})", handle, self.lockout.as_secs() / 60, entry.count, ip);
+                }
+            }
+        }
+    }
+
+    pub fn succeeded(&self, handle: &str) {
+        self.failures.write().unwrap().remove(&Key::Account(handle.to_string()));
+    }
+}
+
+/// what to tell a throttled client, the same on every transport
+pub fn describe(wait: Duration) -> String {
+    match wait.as_secs_f64().ceil() as u64 {
+        1 => "too many failed logins, try again in a second".to_string(),
+        seconds => format!("too many failed logins, try again in {} seconds", seconds),
+    }
+}
+
+#[test]
+fn test_login_throttle() {
+    let throttle = LoginThrottle::new(&AuthConfig { login_free_attempts: 2, login_lockout_minutes: 1, ..AuthConfig::default() });
+    let ip = Peer::Tcp("203.0.113.7:4000".parse().unwrap());
+    let other_ip = Peer::Tcp("198.51.100.1:4000".parse().unwrap());
+    let start = Instant::now();
+    let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+    throttle.failed_at(&ip, "alice", at(0));
+    assert_eq!(throttle.check_at(&ip, "alice", at(0)), None, "the first few attempts are free");
+    throttle.failed_at(&ip, "alice", at(0));
+    assert_eq!(throttle.check_at(&ip, "alice", at(0)), Some(Duration::from_secs(1)));
+    assert_eq!(throttle.check_at(&other_ip, "alice", at(0)), Some(Duration::from_secs(1)), "accounts are throttled from every ip");
+    assert_eq!(throttle.check_at(&ip, "bob", at(0)), Some(Duration::from_secs(1)), "ips are throttled for every account");
+    assert_eq!(throttle.check_at(&ip, "alice", at(1)), None);
+
+    throttle.failed_at(&ip, "alice", at(1));
+    assert_eq!(throttle.check_at(&ip, "alice", at(1)), Some(Duration::from_secs(2)), "waits should double");
+    for second in 2..10 {
+        throttle.failed_at(&other_ip, "alice", at(second));
+    }
+    assert_eq!(throttle.check_at(&Peer::Unix, "alice", at(10)), Some(Duration::from_secs(59)), "waits should stop at the lockout");
+    assert_eq!(throttle.check_at(&Peer::Unix, "alice", at(70)), None, "lockouts should run out");
+
+    throttle.succeeded("alice");
+    assert_eq!(throttle.check_at(&other_ip, "alice", at(10)), Some(Duration::from_secs(59)), "logging in shouldn't clear the ip");
+    assert_eq!(throttle.check_at(&Peer::Unix, "alice", at(10)), None);
+
+    throttle.failed_at(&Peer::Unix, "carol", at(200));
+    assert_eq!(throttle.failures.read().unwrap().len(), 1, "quiet entries should be forgotten");
+}
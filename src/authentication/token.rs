@@ -1,29 +1,103 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
 use log::{warn}; 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use jsonwebtoken::{encode, decode, Header, Algorithm, Validation, EncodingKey, DecodingKey};
-use crate::authentication::{
-    user,
-};
+use jsonwebtoken::Validation;
+use serde_json::Value;
+use crate::authentication::random::random_token;
+use crate::authentication::signing_keys::Keyring;
+use crate::config::AuthConfig;
+
+pub const JWT_LIFE_MINUTES: i64 = 30; // default, see config.rs
+pub const REFRESH_LIFE_DAYS: i64 = 30; // default, see config.rs
+
+struct TokenSettings {
+    keys: Keyring,
+    lifetime_minutes: i64,
+    refresh_lifetime_days: i64,
+}
+
+/// revoked sessions and token ids, and when the last token they cover expires. Checked by
+/// validate_claims(), filled by session.rs.
+static REVOKED: LazyLock<RwLock<HashMap<String, i64>>> = LazyLock::new(Default::default);
+
+static TOKEN_SETTINGS: std::sync::OnceLock<TokenSettings> = std::sync::OnceLock::new();
+
+/// load the signing keys (see signing_keys.rs) and token lifetime from the config. Call this once
+/// at startup, so that missing or broken keys are reported before anyone tries to log in.
+pub fn configure(auth: &AuthConfig) -> Result<(), String> {
+    let keys = Keyring::load(auth)?;
+
+    TOKEN_SETTINGS.set(TokenSettings {
+        keys,
+        lifetime_minutes: auth.token_lifetime_minutes,
+        refresh_lifetime_days: auth.refresh_token_lifetime_days,
+    })
+        .map_err(|_| "token settings were already configured".to_string())
+}
+
+#[cfg(not(test))]
+fn settings() -> &'static TokenSettings {
+    TOKEN_SETTINGS.get().expect("token settings are configured at startup, see configure()")
+}
+
+#[cfg(test)]
+fn settings() -> &'static TokenSettings {
+    // the tests skip configure() and fall back to the JWT_SECRET env variable
+    TOKEN_SETTINGS.get_or_init(|| TokenSettings {
+        keys: Keyring::load(&AuthConfig { jwt_secret: std::env::var("JWT_SECRET").ok(), ..AuthConfig::default() })
+            .expect("the tests need the JWT_SECRET env variable"),
+        lifetime_minutes: JWT_LIFE_MINUTES,
+        refresh_lifetime_days: REFRESH_LIFE_DAYS,
+    })
+}
+
+/// the public signing keys, for `/.well-known/jwks.json`
+pub fn jwks() -> Value {
+    settings().keys.jwks()
+}
+
+/// how long a refresh token lasts
+pub fn refresh_lifetime() -> chrono::Duration {
+    chrono::Duration::days(settings().refresh_lifetime_days)
+}
 
-const JWT_LIFE_MINUTES: i64 = 30;
-const HASHING_ALGORITHM: Algorithm = Algorithm::HS512;
+/// reject every token with this session or token id until `until` (unix timestamp)
+pub fn revoke(id: &str, until: i64) {
+    let mut revoked = REVOKED.write().unwrap();
+    let now = Utc::now().timestamp();
+    revoked.retain(|_, until| *until > now);
+    revoked.insert(id.to_string(), until);
+}
 
-static JWT_SECRET: std::sync::LazyLock<String> = std::sync::LazyLock::new(|| {
-    std::env::var("JWT_SECRET")
-        .expect("Unable to retrieve JWT_SECRET env variable. A quick fix is `JWT_SECRET=\">>your secret phrase here<<\" cargo run`")
-});
+/// when every access token issued right now will have expired, so revocations can be forgotten
+pub fn revocation_deadline() -> i64 {
+    let leeway = Validation::default().leeway as i64;
+    Utc::now().timestamp() + settings().lifetime_minutes * 60 + leeway
+}
 
+fn is_revoked(id: &str) -> bool {
+    REVOKED.read().unwrap().get(id).is_some_and(|until| *until > Utc::now().timestamp())
+}
+
+/// who a token belongs to, but nothing else about them: the user is looked up when the token is
+/// used (see users.rs), so changes to them apply right away
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
    pub exp: usize, // shorthand for expiration, gets auto validated 
    pub iat: usize, // needed for validation, shorthand for issued_at
-   pub user: user::User
-} // no subject. I'm dumb.
+   pub jti: String, // unique id of this token
+   pub sid: String, // the session (see session.rs) it belongs to, logging out revokes it
+   pub sub: String, // the user's handle
+   pub ver: u32, // the user's token version when it was issued, tokens of older versions are invalid
+}
 
-/// function to create a new Json Web Token from a User struct, pass None to the creation_time
-/// argument (creation_time is used only for testing purposes and can cause security problems).
-pub fn create_token(user: user::User, creation_time: Option<DateTime<Utc>>) -> Result<String, ()> {
+/// function to create a new Json Web Token for a user (by handle and token version) and session,
+/// pass None to the creation_time argument (creation_time is used only for testing purposes and
+/// can cause security problems).
+pub fn create_token(handle: &str, token_version: u32, session: &str, creation_time: Option<DateTime<Utc>>) -> Result<String, ()> {
     let now: DateTime<Utc> = match creation_time {
         Some(time) => { 
             warn!("create_token() called with a creation time, this is meant to only be used in testing and may cause unexpected problems!"); 
@@ -33,94 +107,74 @@ pub fn create_token(user: user::User, creation_time: Option<DateTime<Utc>>) -> R
     };
 
     let expiration_time = now
-        .checked_add_signed(chrono::Duration::minutes(JWT_LIFE_MINUTES))
+        .checked_add_signed(chrono::Duration::minutes(settings().lifetime_minutes))
         .expect("Invalid Timestamp")
         .timestamp();
 
     let claims = Claims {
         exp: expiration_time as usize,
         iat: now.timestamp() as usize,
-        user: user,
+        jti: random_token(16),
+        sid: session.to_string(),
+        sub: handle.to_string(),
+        ver: token_version,
     };
 
-    let header = Header::new(HASHING_ALGORITHM);
+    settings().keys.sign(&claims)
+        .map_err(|_| ())
 
-    encode(&header, &claims, &EncodingKey::from_secret(JWT_SECRET.as_bytes()))
-        .map_err(|_| {return ()})
-    
 }
 
-/// Validate a jwt signed with the same JWT_SECRET as the one active.
-pub fn validate_token(token: String) -> Result<user::User, Box<dyn std::error::Error>> {
+/// Validate a jwt signed with one of the configured keys, that hasn't been revoked. The token
+/// version is up to the caller, see middleware.rs.
+pub fn validate_claims(token: &str) -> Result<Claims, Box<dyn std::error::Error>> {
     // exp (expiration appears to be auto validated)
-    let result = decode::<Claims>(&token, &DecodingKey::from_secret(JWT_SECRET.as_bytes()), &Validation::new(HASHING_ALGORITHM))?;
-    let user = result.claims.user; // make it owned
-    
-    Ok(user)
+    let claims = settings().keys.verify::<Claims>(token)?;
+    if is_revoked(&claims.sid) || is_revoked(&claims.jti) {
+        return Err("token has been revoked".into());
+    }
+
+    Ok(claims)
 }
 
 // tests
 #[test]
 fn test_create_valid_jwt() {
-    use crate::authentication::user::{User, UserMode, UserPermissions};
-    
-    // create a dummy user to test on
-    let dummy_user = User {
-        user_type: UserMode::User,
-        username: "dummy test user".to_string(),
-        permission_level: UserPermissions::User,
-        handle: "test_user".to_string(),
-        provider_site: None,
-        banned: false,
-    };
-
-    let result = create_token(dummy_user.clone(), None); // cloning because we need to validate it later
-                                                   // on with the original result.
+    let result = create_token("test_user", 3, "test session", None);
     
     // unwrap the result assuming it's ok
     assert!(result.is_ok(), "Expected no errors with a user this simple");
     let result = result.unwrap();
     
-    let validation = validate_token(result);
+    let validation = validate_claims(&result);
 
     // unwrap the validation assuming it's ok
     assert!(validation.is_ok(), "Expected the JWT to be valid; this could trip if your computer takes 30 minutes to complete one test.");
-    let final_user = validation.unwrap();
+    let claims = validation.unwrap();
 
-    assert_eq!(final_user, dummy_user, "Expected the JWT result to be the same as the initial User.");
+    assert_eq!((claims.sub.as_str(), claims.ver, claims.sid.as_str()), ("test_user", 3, "test session"), "Expected the JWT to keep who it was issued to.");
 }
 
 #[test]
 fn test_invalid_jwt() {
-    use crate::authentication::user::{User, UserMode, UserPermissions};
     // test to make sure that a strait up invalid jwt doesn't work at all
-    assert!(validate_token("not a valid jwt".to_string()).is_err(), "Expected an invalid jwt to not work");
+    assert!(validate_claims("not a valid jwt").is_err(), "Expected an invalid jwt to not work");
     
     /* test to make sure that an expired jwt doesn't work */
 
-    let alg_leeway = Validation::new(HASHING_ALGORITHM).leeway;
+    let alg_leeway = Validation::default().leeway;
     
     let expired_time = Utc::now()
         .checked_sub_signed(chrono::Duration::minutes(JWT_LIFE_MINUTES + (alg_leeway as i64))) // notice how it's sub not
                                                                                                // add, it's in the past. 
         .expect("Invalid Timestamp");
 
-    // create a dummy user to test on
-    let dummy_user = User {
-        user_type: UserMode::User,
-        username: "dummy test user".to_string(),
-        permission_level: UserPermissions::User,
-        handle: "test_user".to_string(),
-        provider_site: None,
-        banned: false,
-    };
-
-    let expired_token = create_token(dummy_user, Some(expired_time)); // a token created in a
+    let expired_token = create_token("test_user", 0, "test session", Some(expired_time)); // a token created in a
                                                                       // simulated past, just
                                                                       // barely past the expiration
                                                                       // time
     assert!(expired_token.is_ok(), "Simulated tokens shouldn't crash.");
     let expired_token = expired_token.unwrap(); // unwrap the result
 
-    assert!(validate_token(expired_token).is_err(), "an expired token should not pass validation")
+    assert!(validate_claims(&expired_token).is_err(), "an expired token should not pass validation")
 }
@@ -0,0 +1,238 @@
+//! Two-factor authentication with TOTP (RFC 6238)
+//!
+//! Users opt in by getting a secret (and an `otpauth://` URI to paste into their authenticator app,
+//! there's no QR code) and confirming it with a code. From then on logging in takes a code too:
+//! over REST as a second step after the password (see PendingLogins), over the line protocol and
+//! IRC after the password, separated by a space. Confirming hands out one-time recovery codes,
+//! stored hashed, which work in place of a code when the authenticator is lost. `auth.require_two_factor`
+//! makes it mandatory for moderators and/or admins: until they set it up they can log in (to set it
+//! up) but not use their rank.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use aws_lc_rs::constant_time::verify_slices_are_equal;
+use aws_lc_rs::hmac;
+use data_encoding::BASE32_NOPAD;
+use log::warn;
+
+use crate::authentication::api_keys;
+use crate::authentication::credentials::{check_credentials, LoginError};
+use crate::authentication::random::random_token;
+use crate::authentication::user::User;
+use crate::backend::listener::Peer;
+use crate::backend::server::AppState;
+use crate::database::database::DBCalls;
+
+const ISSUER: &str = "TRCd";
+const SECRET_BYTES: usize = 20; // 160 bits, as RFC 4226 recommends
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// steps either side of now a code is accepted for, for clocks that are a little off
+const ALLOWED_DRIFT: i64 = 1;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// how long the second step of a REST login can wait after the password
+const CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+const CHALLENGE_LENGTH: usize = 32;
+const CHALLENGE_ATTEMPTS: u8 = 5;
+
+/// a new secret, base32 like authenticator apps expect it
+pub fn generate_secret() -> String {
+    let secret: [u8; SECRET_BYTES] = rand::random();
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// for authenticator apps, most take it pasted in as well as scanned
+pub fn otpauth_uri(handle: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        ISSUER, handle, secret, ISSUER, DIGITS, STEP_SECONDS
+    )
+}
+
+/// the HOTP (RFC 4226) code for one counter value
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let digest = hmac::sign(&key, &counter.to_be_bytes());
+    let digest = digest.as_ref();
+
+    // dynamic truncation
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    code % 10u32.pow(DIGITS)
+}
+
+/// the time step `code` is valid for at `now` (unix timestamp), if any
+fn matching_step(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = now / STEP_SECONDS;
+    (current - ALLOWED_DRIFT..=current + ALLOWED_DRIFT).find(|&step| {
+        let expected = format!("{:0width$}", hotp(&secret, step as u64), width = DIGITS as usize);
+        verify_slices_are_equal(expected.as_bytes(), code.as_bytes()).is_ok()
+    })
+}
+
+/// fresh recovery codes (to show once) and their hashes (to store)
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = random_token(16).to_lowercase();
+            format!("{}-{}-{}-{}", &code[..4], &code[4..8], &code[8..12], &code[12..])
+        })
+        .collect();
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    (codes, hashes)
+}
+
+/// codes are compared without their dashes and case, so they can be typed in however
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    api_keys::hash(&normalized)
+}
+
+/// whether the user has finished setting up TOTP, false if the database can't say
+pub async fn is_enabled(db: &impl DBCalls, handle: &str) -> bool {
+    // stringify the error right away, boxed errors aren't Send
+    match db.fetch_totp(handle).await.map_err(|e| e.to_string()) {
+        Ok(entry) => entry.is_some_and(|entry| entry.enabled),
+        Err(e) => {
+            warn!("error fetching totp: {}", e);
+            false
+        },
+    }
+}
+
+/// check a TOTP code (each one works once) or use up a recovery code. Also used to confirm a
+/// secret that isn't enabled yet.
+pub async fn verify_code(db: &impl DBCalls, handle: &str, code: &str) -> Result<bool, String> {
+    let code = code.trim();
+    let Some(entry) = db.fetch_totp(handle).await.map_err(|e| e.to_string())? else {
+        return Ok(false);
+    };
+
+    match matching_step(&entry.secret, code, chrono::Utc::now().timestamp()) {
+        Some(step) => db.use_totp_step(handle, step).await.map_err(|e| e.to_string()),
+        None if entry.enabled && code.len() > DIGITS as usize => {
+            db.use_recovery_code(handle, &hash_recovery_code(code)).await.map_err(|e| e.to_string())
+        },
+        None => Ok(false),
+    }
+}
+
+/// a password login with the code after the password (`<password> <code>`) for users with TOTP,
+/// for the transports that only have room for one secret (the line protocol and IRC)
+pub async fn check_inline(state: &AppState, ip: &Peer, handle: &str, secret: &str) -> Result<User, LoginError> {
+    let enabled = is_enabled(&state.db, handle).await;
+    let (password, code) = match secret.rsplit_once(' ') {
+        Some((password, code)) if enabled => (password, Some(code)),
+        _ => (secret, None),
+    };
+
+    let user = check_credentials(&state.db, &state.passwords, &state.login_throttle, ip, handle, password).await?;
+    if !enabled {
+        return Ok(user);
+    }
+    let Some(code) = code else {
+        return Err(LoginError::SecondFactorRequired);
+    };
+
+    match verify_code(&state.db, handle, code).await {
+        Ok(true) => Ok(user),
+        Ok(false) => {
+            state.login_throttle.failed(ip, handle);
+            Err(LoginError::InvalidCredentials)
+        },
+        Err(e) => {
+            warn!("error checking a two-factor code: {}", e);
+            Err(LoginError::Internal)
+        },
+    }
+}
+
+/// refuse users whose rank needs two-factor authentication (`auth.require_two_factor`) but who
+/// haven't set it up
+pub async fn require(state: &AppState, user: &User) -> Result<(), &'static str> {
+    match &state.config.auth.require_two_factor {
+        Some(rank) if user.permission_level >= *rank && !is_enabled(&state.db, &user.handle).await => {
+            Err("your rank requires two-factor authentication, set it up first (see /api/2fa)")
+        },
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PendingLogin {
+    handle: String,
+    expires: Instant,
+    attempts: u8,
+}
+
+/// REST logins of users with TOTP that got the password right and are waiting on the code, by
+/// challenge
+#[derive(Debug, Clone, Default)]
+pub struct PendingLogins {
+    pending: Arc<RwLock<HashMap<String, PendingLogin>>>,
+}
+impl PendingLogins {
+    /// the challenge the second step has to name
+    pub fn start(&self, handle: &str) -> String {
+        let challenge = random_token(CHALLENGE_LENGTH);
+        let mut pending = self.pending.write().unwrap();
+        let now = Instant::now();
+        pending.retain(|_, login| login.expires > now);
+        pending.insert(challenge.clone(), PendingLogin { handle: handle.to_string(), expires: now + CHALLENGE_LIFETIME, attempts: 0 });
+
+        challenge
+    }
+
+    /// whose login this is, None if it's unknown or expired
+    pub fn handle(&self, challenge: &str) -> Option<String> {
+        self.pending.read().unwrap().get(challenge)
+            .filter(|login| login.expires > Instant::now())
+            .map(|login| login.handle.clone())
+    }
+
+    /// count a wrong code, the login has to start over after a few
+    pub fn failed(&self, challenge: &str) {
+        let mut pending = self.pending.write().unwrap();
+        if let Some(login) = pending.get_mut(challenge) {
+            login.attempts += 1;
+            if login.attempts >= CHALLENGE_ATTEMPTS {
+                pending.remove(challenge);
+            }
+        }
+    }
+
+    pub fn finish(&self, challenge: &str) {
+        self.pending.write().unwrap().remove(challenge);
+    }
+}
+
+#[test]
+fn test_totp() {
+    // RFC 6238's SHA1 test vectors, cut down to 6 digits
+    let secret = BASE32_NOPAD.encode(b"12345678901234567890");
+    assert_eq!(matching_step(&secret, "287082", 59), Some(1));
+    assert_eq!(matching_step(&secret, "081804", 1111111109), Some(1111111109 / 30));
+    assert_eq!(matching_step(&secret, "005924", 1234567890), Some(1234567890 / 30));
+    assert_eq!(matching_step(&secret, "005924", 1234567890 + 30), Some(1234567890 / 30), "codes should survive a little clock drift");
+    assert_eq!(matching_step(&secret, "005924", 1234567890 + 90), None);
+    assert_eq!(matching_step(&secret, "5924", 1234567890), None);
+
+    let uri = otpauth_uri("alice", &secret);
+    assert!(uri.starts_with("otpauth://totp/TRCd:alice?secret=") && uri.contains("issuer=TRCd"));
+    assert_eq!(BASE32_NOPAD.decode(generate_secret().as_bytes()).unwrap().len(), SECRET_BYTES);
+
+    let (codes, hashes) = generate_recovery_codes();
+    assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+    assert_eq!(hash_recovery_code(&codes[0].to_uppercase().replace('-', " ")), hashes[0], "recovery codes should be forgiving about how they're typed");
+}
@@ -10,10 +10,11 @@ pub enum UserMode {
     Bot
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+/// ordered from lowest to highest rank, moderation compares these
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub enum UserPermissions {
     User, // basic things: join channels, read/write to those channels
-    Moderator, // `/kick` people, `/ban` people of lower ranks
+    Moderator, // kick, mute and ban people of lower ranks, see moderation.rs
     Admin, // highest permission. Assumed owner or extremely trusted member 
 }
 
@@ -30,4 +31,23 @@ pub struct User {
     pub provider_site: Option<String>, // this is so people can know how to DM them
     pub banned: bool, // for while the user is stored in memory
 }
-impl User {}
+impl std::str::FromStr for UserPermissions {
+    type Err = String;
+
+    /// `user`, `moderator` or `admin`, in any case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "user" => Ok(UserPermissions::User),
+            "moderator" => Ok(UserPermissions::Moderator),
+            "admin" => Ok(UserPermissions::Admin),
+            _ => Err("expected user, moderator or admin".to_string()),
+        }
+    }
+}
+
+impl User {
+    /// whether this user may moderate `other`
+    pub fn outranks(&self, other: &User) -> bool {
+        self.permission_level > other.permission_level
+    }
+}
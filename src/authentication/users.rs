@@ -0,0 +1,99 @@
+//! The users behind tokens
+//!
+//! Tokens only say whose they are and which token version they were issued under (see token.rs).
+//! Everything else about the user (rank, ban) is looked up when a token is used, so promotions,
+//! demotions and bans apply to tokens handed out before them. Lookups are cached for
+//! `auth.user_cache_seconds`, and dropped straight away when the server changes the user itself.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::authentication::user::User;
+use crate::database::database::DBCalls;
+
+pub const USER_CACHE_SECONDS: u64 = 30; // default, see config.rs
+
+#[derive(Debug, Clone)]
+pub struct CachedUser {
+    pub user: User,
+    pub token_version: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct UserCache {
+    entries: Arc<RwLock<HashMap<String, (CachedUser, Instant)>>>,
+    lifetime: Duration,
+}
+impl UserCache {
+    pub fn new(lifetime: Duration) -> Self {
+        UserCache { entries: Default::default(), lifetime }
+    }
+
+    /// the user with `handle`, None if there's no such user (or the database is unreachable)
+    pub async fn get(&self, db: &impl DBCalls, handle: &str) -> Option<CachedUser> {
+        let fresh = self.entries.read().unwrap().get(handle)
+            .filter(|(_, at)| at.elapsed() < self.lifetime)
+            .map(|(cached, _)| cached.clone());
+        if fresh.is_some() {
+            return fresh;
+        }
+
+        // stringify the error right away, boxed errors aren't Send
+        let entry = match db.fetch_user(handle).await.map_err(|e| e.to_string()) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("unable to look up the user of a token: {}", e);
+                self.invalidate(handle);
+                return None;
+            },
+        };
+        let cached = CachedUser { user: entry.inner_user, token_version: entry.token_version };
+
+        if !self.lifetime.is_zero() {
+            let mut entries = self.entries.write().unwrap();
+            // forget users that haven't been around for a while
+            entries.retain(|_, (_, at)| at.elapsed() < self.lifetime);
+            entries.insert(handle.to_string(), (cached.clone(), Instant::now()));
+        }
+
+        Some(cached)
+    }
+
+    /// look the user up again next time, call after changing them
+    pub fn invalidate(&self, handle: &str) {
+        self.entries.write().unwrap().remove(handle);
+    }
+
+    /// make every token of a user stop working right away
+    pub async fn revoke_tokens(&self, db: &impl DBCalls, handle: &str) -> Result<u32, String> {
+        let version = db.bump_token_version(handle).await.map_err(|e| e.to_string());
+        self.invalidate(handle);
+
+        version
+    }
+}
+
+#[tokio::test]
+async fn test_user_cache() {
+    use crate::authentication::user::UserPermissions;
+    use crate::database::{test_db, test_user};
+
+    let db = test_db().await;
+    db.add_user(test_user("alice", UserPermissions::User)).await.unwrap();
+
+    let cache = UserCache::new(Duration::from_secs(60));
+    assert_eq!(cache.get(&db, "alice").await.unwrap().token_version, 0);
+    assert!(cache.get(&db, "nobody").await.is_none());
+
+    db.bump_token_version("alice").await.unwrap();
+    assert_eq!(cache.get(&db, "alice").await.unwrap().token_version, 0, "changes outside the cache wait for it to run out");
+    cache.invalidate("alice");
+    assert_eq!(cache.get(&db, "alice").await.unwrap().token_version, 1);
+
+    assert_eq!(cache.revoke_tokens(&db, "alice").await, Ok(2));
+    assert_eq!(cache.get(&db, "alice").await.unwrap().token_version, 2, "revoking should skip the cache");
+    assert!(cache.revoke_tokens(&db, "nobody").await.is_err());
+}
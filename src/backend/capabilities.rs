@@ -0,0 +1,147 @@
+//! Capability negotiation for sockets (loosely modeled after IRCv3 `CAP`)
+//!
+//! After authenticating, a client may send `CAP LS` to see what the server supports, or
+//! `CAP REQ <capability> <capability> ...` to turn features on for its connection. Clients that
+//! never send a `CAP` line get the original protocol, so old terminal clients keep working.
+
+use serde::Serialize;
+
+use crate::backend::encoding::Encoding;
+use crate::config::LimitsConfig;
+
+/// Every protocol feature a client can ask for. Only add a variant here once the socket server
+/// actually implements it, the server advertises all of these in `CAP LS`.
+#[derive(Debug, Serialize, PartialEq, Eq, Clone, Copy)]
+pub enum Capability {
+    /// attach a server assigned `id` to every MESSAGE event
+    #[serde(rename = "message-ids")]
+    MessageIds,
+    /// send and receive MessagePack binary frames instead of JSON text
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    /// send and receive CBOR binary frames instead of JSON text
+    #[serde(rename = "cbor")]
+    Cbor,
+}
+impl Capability {
+    pub const ALL: &'static [Capability] = &[Capability::MessageIds, Capability::MessagePack, Capability::Cbor];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::MessageIds => "message-ids",
+            Capability::MessagePack => "msgpack",
+            Capability::Cbor => "cbor",
+        }
+    }
+
+    /// the wire encoding this capability switches to, if any
+    fn encoding(&self) -> Option<Encoding> {
+        match self {
+            Capability::MessagePack => Some(Encoding::MessagePack),
+            Capability::Cbor => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|cap| cap.name() == name)
+    }
+}
+
+/// The set of capabilities enabled on a single connection
+#[derive(Debug, Default, Clone)]
+pub struct Capabilities {
+    enabled: Vec<Capability>,
+}
+impl Capabilities {
+    pub fn has(&self, cap: Capability) -> bool {
+        self.enabled.contains(&cap)
+    }
+
+    pub fn enabled(&self) -> &[Capability] {
+        &self.enabled
+    }
+
+    /// the encoding every event on this connection should use
+    pub fn encoding(&self) -> Encoding {
+        self.enabled.iter()
+            .find_map(Capability::encoding)
+            .unwrap_or_default()
+    }
+
+    /// replace the enabled set with whatever was requested that the server supports. Unknown
+    /// names are dropped silently so newer clients can talk to older servers. If more than one
+    /// binary encoding is requested only the first one is enabled.
+    pub fn request<'a>(&mut self, names: impl Iterator<Item = &'a str>) {
+        self.enabled.clear();
+        for cap in names.filter_map(Capability::from_name) {
+            if self.has(cap) || (cap.encoding().is_some() && self.encoding().is_binary()) {
+                continue;
+            }
+            self.enabled.push(cap);
+        }
+    }
+}
+
+/// limits a client should respect, sent along with every `CAP` response
+#[derive(Debug, Serialize)]
+pub struct ServerLimits {
+    pub max_channel_name_length_bytes: usize,
+    pub max_unsupported_frames: u8,
+}
+impl From<&LimitsConfig> for ServerLimits {
+    fn from(limits: &LimitsConfig) -> Self {
+        ServerLimits {
+            max_channel_name_length_bytes: limits.max_channel_name_length_bytes,
+            max_unsupported_frames: limits.max_unsupported_frames,
+        }
+    }
+}
+
+/// A parsed `CAP` line from a client
+#[derive(Debug, PartialEq)]
+pub enum CapCommand<'a> {
+    List,
+    Request(Vec<&'a str>),
+}
+impl<'a> CapCommand<'a> {
+    /// returns None if the text isn't a `CAP` line at all (i.e. it's a channel switch)
+    pub fn parse(text: &'a str) -> Option<Result<Self, &'static str>> {
+        let mut words = text.split_whitespace();
+        if words.next() != Some("CAP") {
+            return None;
+        }
+
+        Some(match words.next() {
+            Some("LS") => Ok(CapCommand::List),
+            Some("REQ") => Ok(CapCommand::Request(words.collect())),
+            _ => Err("unknown CAP subcommand, expected `CAP LS` or `CAP REQ <capabilities>`"),
+        })
+    }
+}
+
+#[test]
+fn test_cap_parse() {
+    assert_eq!(CapCommand::parse("general"), None, "channel names should not be parsed as CAP lines");
+    assert_eq!(CapCommand::parse("CAP LS"), Some(Ok(CapCommand::List)));
+    assert_eq!(
+        CapCommand::parse("CAP REQ message-ids typing"),
+        Some(Ok(CapCommand::Request(vec!["message-ids", "typing"])))
+    );
+    assert!(matches!(CapCommand::parse("CAP NOPE"), Some(Err(_))));
+}
+
+#[test]
+fn test_cap_request_drops_unknown() {
+    let mut caps = Capabilities::default();
+    caps.request(["typing", "message-ids", "message-ids"].into_iter());
+
+    assert_eq!(caps.enabled(), &[Capability::MessageIds], "unknown or duplicate capabilities should be dropped");
+
+    caps.request(["cbor", "msgpack"].into_iter());
+    assert_eq!(caps.encoding(), Encoding::Cbor, "only the first binary encoding should be enabled");
+    assert!(!caps.has(Capability::MessagePack));
+
+    caps.request(std::iter::empty());
+    assert!(!caps.has(Capability::MessageIds), "a new REQ should replace the enabled set");
+}
@@ -0,0 +1,222 @@
+//! Who is connected right now
+//!
+//! Every live connection (websocket, SSE stream, line protocol, IRC) registers itself under its
+//! user's handle for as long as it's open. Moderation uses this to throw a user out straight away,
+//! instead of waiting for their token to expire. Connections also say which channels they're in,
+//! so moderation knows where to announce what happened to someone. Connections that logged in with
+//! an access token also remember its session, so logging a session out closes them too.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Default)]
+pub struct Connections {
+    inner: Arc<Mutex<Inner>>,
+}
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    by_handle: HashMap<String, Vec<Entry>>,
+}
+#[derive(Debug)]
+struct Entry {
+    id: u64,
+    tx: mpsc::UnboundedSender<String>,
+    channels: HashSet<String>,
+    session: Option<String>, // None for password logins (line protocol, IRC) and API keys
+}
+impl Connections {
+    /// keep track of a connection until the returned registration is dropped
+    pub fn register(&self, handle: &str, session: Option<&str>) -> Registration {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        let entry = Entry { id, tx, channels: HashSet::new(), session: session.map(str::to_string) };
+        inner.by_handle.entry(handle.to_string()).or_default().push(entry);
+
+        Registration {
+            presence: Presence { connections: self.clone(), handle: handle.to_string(), id },
+            rx,
+        }
+    }
+
+    /// close every connection of a user, telling them `reason`. Returns how many there were.
+    pub fn disconnect(&self, handle: &str, reason: &str) -> usize {
+        let connections = self.inner.lock().unwrap().by_handle.remove(handle).unwrap_or_default();
+        for entry in &connections {
+            let _ = entry.tx.send(reason.to_string());
+        }
+
+        connections.len()
+    }
+
+    /// close the connections of one of a user's sessions, telling them `reason`. Returns how many
+    /// there were.
+    pub fn disconnect_session(&self, handle: &str, session: &str, reason: &str) -> usize {
+        self.disconnect_where(handle, |entry| entry.session.as_deref() == Some(session), reason)
+    }
+
+    /// close every connection of a user except the ones of session `keep`, including those that
+    /// didn't log in with a session. Returns how many there were.
+    pub fn disconnect_others(&self, handle: &str, keep: Option<&str>, reason: &str) -> usize {
+        self.disconnect_where(handle, |entry| keep.is_none() || entry.session.as_deref() != keep, reason)
+    }
+
+    fn disconnect_where(&self, handle: &str, close: impl Fn(&Entry) -> bool, reason: &str) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entries) = inner.by_handle.get_mut(handle) else {
+            return 0;
+        };
+        let (closed, open): (Vec<Entry>, Vec<Entry>) = entries.drain(..).partition(close);
+        *entries = open;
+        if entries.is_empty() {
+            inner.by_handle.remove(handle);
+        }
+        for entry in &closed {
+            let _ = entry.tx.send(reason.to_string());
+        }
+
+        closed.len()
+    }
+
+    /// how many connections each session of a user has open
+    pub fn sessions_of(&self, handle: &str) -> HashMap<String, usize> {
+        let inner = self.inner.lock().unwrap();
+        let mut sessions = HashMap::new();
+        for session in inner.by_handle.get(handle).into_iter().flatten().filter_map(|entry| entry.session.clone()) {
+            *sessions.entry(session).or_default() += 1;
+        }
+
+        sessions
+    }
+
+    /// every channel any connection of a user is in
+    pub fn channels_of(&self, handle: &str) -> BTreeSet<String> {
+        let inner = self.inner.lock().unwrap();
+        inner.by_handle.get(handle)
+            .map(|entries| entries.iter().flat_map(|entry| entry.channels.iter().cloned()).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// a registered connection, unregistered on drop
+#[derive(Debug)]
+pub struct Registration {
+    presence: Presence,
+    rx: mpsc::UnboundedReceiver<String>,
+}
+impl Registration {
+    /// resolves with the reason once the connection should be closed
+    pub async fn disconnected(&mut self) -> String {
+        match self.rx.recv().await {
+            Some(reason) => reason,
+            // the sender is only dropped after sending, but never resolve without a reason
+            None => std::future::pending().await,
+        }
+    }
+
+    /// a handle for keeping the connection's channels up to date, while disconnected() is awaited
+    pub fn presence(&self) -> Presence {
+        self.presence.clone()
+    }
+}
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut inner = self.presence.connections.inner.lock().unwrap();
+        if let Some(list) = inner.by_handle.get_mut(&self.presence.handle) {
+            list.retain(|entry| entry.id != self.presence.id);
+            if list.is_empty() {
+                inner.by_handle.remove(&self.presence.handle);
+            }
+        }
+    }
+}
+
+/// which channels a registered connection is in
+#[derive(Debug, Clone)]
+pub struct Presence {
+    connections: Connections,
+    handle: String,
+    id: u64,
+}
+impl Presence {
+    pub fn join(&self, channel: &str) {
+        self.update(|channels| { channels.insert(channel.to_string()); });
+    }
+
+    pub fn part(&self, channel: &str) {
+        self.update(|channels| { channels.remove(channel); });
+    }
+
+    /// for connections that are in one channel at a time, None for no channel in particular
+    pub fn switch(&self, channel: Option<&str>) {
+        self.update(|channels| {
+            channels.clear();
+            channels.extend(channel.map(str::to_string));
+        });
+    }
+
+    fn update(&self, change: impl FnOnce(&mut HashSet<String>)) {
+        let mut inner = self.connections.inner.lock().unwrap();
+        // gone after a disconnect, nobody asks about its channels anymore
+        let entry = inner.by_handle.get_mut(&self.handle)
+            .and_then(|entries| entries.iter_mut().find(|entry| entry.id == self.id));
+        if let Some(entry) = entry {
+            change(&mut entry.channels);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_disconnect() {
+    let connections = Connections::default();
+    let mut first = connections.register("alice", None);
+    let mut second = connections.register("alice", Some("laptop"));
+    let bob = connections.register("bob", None);
+
+    drop(bob);
+    assert_eq!(connections.disconnect("bob", "banned"), 0, "dropping a registration should unregister it");
+
+    assert_eq!(connections.disconnect("alice", "banned"), 2);
+    assert_eq!(first.disconnected().await, "banned");
+    assert_eq!(second.disconnected().await, "banned");
+    assert_eq!(connections.disconnect("alice", "banned"), 0);
+}
+
+#[test]
+fn test_channels_of() {
+    let connections = Connections::default();
+    let socket = connections.register("alice", None);
+    let irc = connections.register("alice", None);
+
+    socket.presence().switch(Some("general"));
+    irc.presence().join("general");
+    irc.presence().join("random");
+    irc.presence().part("general");
+    assert_eq!(connections.channels_of("alice").into_iter().collect::<Vec<_>>(), ["general", "random"]);
+
+    socket.presence().switch(None);
+    drop(irc);
+    assert!(connections.channels_of("alice").is_empty());
+}
+
+#[tokio::test]
+async fn test_disconnect_session() {
+    let connections = Connections::default();
+    let mut laptop = connections.register("alice", Some("laptop"));
+    let phone = connections.register("alice", Some("phone"));
+    let _irc = connections.register("alice", None);
+    let _other_socket = connections.register("alice", Some("phone"));
+
+    assert_eq!(connections.sessions_of("alice"), HashMap::from([("laptop".to_string(), 1), ("phone".to_string(), 2)]));
+    assert_eq!(connections.disconnect_session("alice", "laptop", "logged out"), 1);
+    assert_eq!(laptop.disconnected().await, "logged out");
+    assert_eq!(connections.disconnect_session("bob", "laptop", "logged out"), 0, "sessions should only close their own user's connections");
+
+    drop(phone);
+    assert_eq!(connections.sessions_of("alice"), HashMap::from([("phone".to_string(), 1)]));
+    assert_eq!(connections.disconnect("alice", "banned"), 2, "the rest should stay connected");
+}
@@ -0,0 +1,60 @@
+//! Wire encodings for socket traffic
+//!
+//! JSON text frames are the default. Clients that negotiate the `msgpack` or `cbor` capability get
+//! every event as a binary frame instead, and may send their commands as binary frames holding a
+//! single encoded string.
+
+use std::error::Error;
+
+use axum::extract::ws::Message;
+use serde::Serialize;
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+impl Encoding {
+    pub fn is_binary(&self) -> bool {
+        *self != Encoding::Json
+    }
+
+    /// encode an event into a websocket frame. MessagePack uses named fields so that every
+    /// encoding has the same shape as the JSON one.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Message, Box<dyn Error>> {
+        Ok(match self {
+            Encoding::Json => Message::Text(serde_json::to_string(value)?.into()),
+            Encoding::MessagePack => Message::Binary(rmp_serde::to_vec_named(value)?.into()),
+            Encoding::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::into_writer(value, &mut buffer)?;
+                Message::Binary(buffer.into())
+            },
+        })
+    }
+
+    /// decode a binary frame from a client into the same command string a text frame would hold
+    pub fn decode_command(&self, payload: &[u8]) -> Result<String, Box<dyn Error>> {
+        match self {
+            Encoding::Json => Err("binary frames require the `msgpack` or `cbor` capability".into()),
+            Encoding::MessagePack => Ok(rmp_serde::from_slice(payload)?),
+            Encoding::Cbor => Ok(ciborium::from_reader(payload)?),
+        }
+    }
+}
+
+#[test]
+fn test_binary_round_trip() {
+    for encoding in [Encoding::MessagePack, Encoding::Cbor] {
+        let frame = encoding.encode(&"general").expect("a string should always encode");
+        let Message::Binary(payload) = frame else {
+            panic!("{:?} should produce binary frames", encoding);
+        };
+
+        assert_eq!(encoding.decode_command(&payload).unwrap(), "general", "{:?} should round trip", encoding);
+    }
+
+    assert!(Encoding::Json.decode_command(b"general").is_err(), "JSON connections should reject binary frames");
+}
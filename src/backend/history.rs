@@ -0,0 +1,100 @@
+//! Short term message history
+//!
+//! TRCd doesn't store messages, but it does keep the most recent ones in memory so that clients
+//! that drop off for a moment can be caught up when they come back (see resume.rs).
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+
+use crate::backend::socket_server::ChannelMessage;
+
+/// how many messages (across all channels) are kept for replaying
+pub const HISTORY_CAPACITY: usize = 1024; // default, see config.rs
+
+#[derive(Debug, Clone)]
+pub struct MessageHistory {
+    capacity: usize,
+    messages: Arc<Mutex<VecDeque<ChannelMessage>>>,
+}
+impl MessageHistory {
+    pub fn with_capacity(capacity: usize) -> Self {
+        MessageHistory {
+            capacity,
+            messages: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    pub fn push(&self, message: ChannelMessage) {
+        let mut messages = self.messages.lock().expect("history lock poisoned");
+        if messages.len() >= self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    /// spawn a task that records everything sent on the broadcast channel
+    pub fn record(&self, mut rx: Receiver<ChannelMessage>) {
+        let history = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match rx.recv().await {
+                    Ok(message) => history.push(message),
+                    Err(RecvError::Lagged(skipped)) => warn!("message history fell behind, {} messages will not be replayable", skipped),
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+
+    /// every remembered message newer than `last_seen` that passes `filter`, oldest first. The bool
+    /// is false if messages newer than `last_seen` have already been forgotten.
+    pub fn since(&self, last_seen: u64, filter: impl Fn(&ChannelMessage) -> bool) -> (Vec<ChannelMessage>, bool) {
+        let messages = self.messages.lock().expect("history lock poisoned");
+        let complete = messages.front().is_none_or(|oldest| oldest.id <= last_seen.saturating_add(1));
+
+        let missed = messages.iter()
+            .filter(|m| m.id > last_seen && filter(m))
+            .cloned()
+            .collect();
+
+        (missed, complete)
+    }
+}
+
+#[test]
+fn test_history_since() {
+    use crate::authentication::user::{User, UserMode, UserPermissions};
+
+    let dummy_user = User {
+        user_type: UserMode::User,
+        username: "dummy test user".to_string(),
+        permission_level: UserPermissions::User,
+        handle: "test_user".to_string(),
+        provider_site: None,
+        banned: false,
+    };
+
+    let history = MessageHistory::with_capacity(3);
+    let ids: Vec<u64> = (0..4).map(|i| {
+        let message = ChannelMessage::new(if i % 2 == 0 {"even"} else {"odd"}.to_string(), i.to_string(), dummy_user.clone());
+        let id = message.id;
+        history.push(message);
+        id
+    }).collect();
+
+    // the first message was pushed out, so asking from before it is incomplete
+    let (missed, complete) = history.since(ids[0] - 1, |_| true);
+    assert!(!complete, "the oldest message was dropped, replay should be incomplete");
+    assert_eq!(missed.len(), 3);
+
+    let (missed, complete) = history.since(ids[1], |m| m.channel == "even");
+    assert!(complete);
+    assert_eq!(missed.iter().map(|m| m.id).collect::<Vec<_>>(), vec![ids[2]], "only newer messages in the channel should replay");
+
+    // a client can claim to have seen any id, this mustn't overflow
+    let (missed, complete) = history.since(u64::MAX, |_| true);
+    assert!(complete && missed.is_empty());
+}
@@ -0,0 +1,162 @@
+//! A small HTTP/1.1 client, for talking to OpenID Connect issuers (see oidc.rs)
+//!
+//! One request per connection, no redirects, no compression: discovery documents, key sets and
+//! token endpoints don't need more. https trusts the CA certificates of `oidc.ca_file`, plain http
+//! only works with loopback addresses, for trying things out against an issuer on the same machine.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Bytes;
+use hyper::header;
+use hyper::{Method, Request};
+use hyper_util::rt::TokioIo;
+use serde::de::DeserializeOwned;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, pem::PemObject};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, crypto::aws_lc_rs};
+use url::{Host, Url};
+
+use crate::backend::listener::Stream;
+
+/// how long a whole request (connecting, TLS, sending and reading the response) may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// largest response body read, key sets and tokens are a few KiB
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: Bytes,
+}
+impl Response {
+    /// the body as JSON, if the request succeeded
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, String> {
+        if !(200..300).contains(&self.status) {
+            return Err(format!("status {}: {}", self.status, String::from_utf8_lossy(&self.body[..self.body.len().min(200)])));
+        }
+        serde_json::from_slice(&self.body).map_err(|e| format!("invalid JSON: {}", e))
+    }
+}
+
+#[derive(Clone)]
+pub struct HttpClient {
+    tls: TlsConnector,
+}
+impl HttpClient {
+    /// trusting the CA certificates of a PEM bundle, like `/etc/ssl/certs/ca-certificates.crt`
+    pub fn new(ca_file: &Path) -> Result<Self, String> {
+        let mut roots = RootCertStore::empty();
+        let certificates = CertificateDer::pem_file_iter(ca_file)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("unable to read CA certificates from {}: {}", ca_file.display(), e))?;
+        let (added, _) = roots.add_parsable_certificates(certificates);
+        if added == 0 {
+            return Err(format!("no CA certificates in {}", ca_file.display()));
+        }
+
+        Ok(Self::with_roots(roots))
+    }
+
+    pub fn with_roots(roots: RootCertStore) -> Self {
+        let mut config = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the default provider supports the default TLS versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        HttpClient { tls: TlsConnector::from(Arc::new(config)) }
+    }
+
+    /// GET, with a bearer token if there is one
+    pub async fn get(&self, url: &Url, bearer: Option<&str>) -> Result<Response, String> {
+        let mut request = Request::builder().method(Method::GET);
+        if let Some(token) = bearer {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        self.send(url, request, Bytes::new()).await
+    }
+
+    /// POST an `application/x-www-form-urlencoded` body, with HTTP basic authentication if there's
+    /// a user and password
+    pub async fn post_form(&self, url: &Url, form: &[(&str, &str)], basic: Option<(&str, &str)>) -> Result<Response, String> {
+        let body = url::form_urlencoded::Serializer::new(String::new()).extend_pairs(form).finish();
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+        if let Some((user, password)) = basic {
+            // RFC 6749 form-encodes both halves before joining them
+            let encode = |part: &str| url::form_urlencoded::byte_serialize(part.as_bytes()).collect::<String>();
+            let credentials = STANDARD.encode(format!("{}:{}", encode(user), encode(password)));
+            request = request.header(header::AUTHORIZATION, format!("Basic {}", credentials));
+        }
+        self.send(url, request, Bytes::from(body)).await
+    }
+
+    async fn send(&self, url: &Url, request: hyper::http::request::Builder, body: Bytes) -> Result<Response, String> {
+        tokio::time::timeout(REQUEST_TIMEOUT, self.send_now(url, request, body)).await
+            .unwrap_or_else(|_| Err(format!("{} took longer than {} seconds", url, REQUEST_TIMEOUT.as_secs())))
+    }
+
+    async fn send_now(&self, url: &Url, request: hyper::http::request::Builder, body: Bytes) -> Result<Response, String> {
+        let host = url.host().ok_or_else(|| format!("{} has no host", url))?;
+        let port = url.port_or_known_default().ok_or_else(|| format!("{} has no port", url))?;
+        let tcp = match &host {
+            Host::Domain(domain) => TcpStream::connect((*domain, port)).await,
+            Host::Ipv4(ip) => TcpStream::connect((*ip, port)).await,
+            Host::Ipv6(ip) => TcpStream::connect((*ip, port)).await,
+        }.map_err(|e| format!("unable to connect to {}: {}", url, e))?;
+
+        let stream: Box<dyn Stream> = match url.scheme() {
+            "https" => {
+                let name = ServerName::try_from(host.to_string().trim_matches(['[', ']']).to_string())
+                    .map_err(|e| format!("invalid host in {}: {}", url, e))?;
+                Box::new(self.tls.connect(name, tcp).await.map_err(|e| format!("TLS with {} failed: {}", url, e))?)
+            },
+            "http" if is_loopback(&host) => Box::new(tcp),
+            _ => return Err(format!("{} has to be https", url)),
+        };
+
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await
+            .map_err(|e| format!("HTTP with {} failed: {}", url, e))?;
+        // drives the connection until the response has been read and the sender dropped
+        tokio::spawn(connection);
+
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let path = &url[url::Position::BeforePath..url::Position::AfterQuery];
+        let request = request
+            .uri(path)
+            .header(header::HOST, authority)
+            .header(header::ACCEPT, "application/json")
+            .header(header::USER_AGENT, concat!("trcd/", env!("CARGO_PKG_VERSION")))
+            .body(Full::new(body))
+            .map_err(|e| e.to_string())?;
+
+        let response = sender.send_request(request).await.map_err(|e| format!("HTTP with {} failed: {}", url, e))?;
+        let status = response.status().as_u16();
+        let body = Limited::new(response.into_body(), MAX_BODY_BYTES).collect().await
+            .map_err(|e| format!("reading the response of {} failed: {}", url, e))?
+            .to_bytes();
+
+        Ok(Response { status, body })
+    }
+}
+
+/// plain http is only allowed to these, so nothing on the network can read or tamper with it
+pub(crate) fn is_loopback(host: &Host<&str>) -> bool {
+    match host {
+        Host::Domain(domain) => *domain == "localhost",
+        Host::Ipv4(ip) => IpAddr::V4(*ip).is_loopback(),
+        Host::Ipv6(ip) => IpAddr::V6(*ip).is_loopback(),
+    }
+}
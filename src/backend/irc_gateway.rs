@@ -0,0 +1,447 @@
+//! IRC gateway, so stock IRC clients (irssi, weechat, ...) can join TRC channels (if
+//! `server.irc_bind` is set, see config.rs)
+//!
+//! This implements just enough of RFC 1459/2812 for a client to register and chat: PASS/NICK/USER
+//! registration (checked like `/api/login`), JOIN, PART, PRIVMSG, NOTICE, TOPIC, NAMES, WHO,
+//! PING/PONG and QUIT. TRC channel `general` is IRC channel `#general`. Traffic goes through the
+//! same broadcast channel as the socket server.
+//!
+//! NAMES and WHO only know about people connected through this gateway, and TRC channels don't
+//! have topics.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::Arc;
+
+use log::{info, trace, warn};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::sync::{Mutex, broadcast::Receiver};
+
+use crate::authentication::credentials::LoginError;
+use crate::authentication::{throttle, two_factor};
+use crate::authentication::user::User;
+use crate::backend::line_server::read_line;
+use crate::backend::connections::Presence;
+use crate::backend::socket_server::ChannelMessage;
+use crate::backend::listener::{Listener, Peer, ReadHalf, Stream, WriteHalf};
+use crate::backend::server::AppState;
+use crate::backend::shutdown::SHUTDOWN_REASON;
+
+const SERVER_NAME: &str = "trcd";
+/// RFC 1459 says 512, but message tags and long utf-8 lines are common
+const MAX_LINE_BYTES: usize = 8192;
+
+type GatewayResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+/// which handles (connected through the gateway) are in which channel
+type Members = Arc<Mutex<HashMap<String, HashSet<String>>>>;
+
+/// A parsed IRC line, tags and prefix are ignored because clients have no business sending them
+#[derive(Debug, PartialEq)]
+struct IrcMessage {
+    command: String,
+    params: Vec<String>,
+}
+impl IrcMessage {
+    fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_start();
+        // skip message tags and the prefix
+        for marker in ['@', ':'] {
+            if rest.starts_with(marker) {
+                rest = rest.split_once(' ')?.1.trim_start();
+            }
+        }
+
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+
+        let mut words = middle.split_whitespace();
+        let command = words.next()?.to_uppercase();
+        let mut params: Vec<String> = words.map(str::to_string).collect();
+        if let Some(trailing) = trailing {
+            params.push(trailing.to_string());
+        }
+
+        Some(IrcMessage { command, params })
+    }
+}
+
+pub struct IrcGateway {
+    listener: Listener,
+}
+impl IrcGateway {
+    pub fn new(listener: Listener) -> Self {
+        IrcGateway {
+            listener,
+        }
+    }
+
+    /// accept clients until the server shuts down
+    pub async fn run(mut self, state: AppState) {
+        let members: Members = Arc::default();
+
+        let scheme = if self.listener.is_tls() {"ircs"} else {"irc"};
+        info!("IRC gateway bound to {}://{}", scheme, self.listener.local_addr());
+
+        loop {
+            let (stream, ip) = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = state.shutdown.wait() => return,
+            };
+
+            let (state, members) = (state.clone(), members.clone());
+            tokio::spawn(async move {
+                let _connection = state.shutdown.connection();
+                info!("IRC client connected from ip: {}", ip);
+                if let Err(e) = Self::handle_connection(stream, ip, &state, members).await {
+                    warn!("{:?}", e);
+                }
+                info!("IRC client disconnected (ip: {})", ip);
+            });
+        }
+    }
+
+    async fn handle_connection(stream: Box<dyn Stream>, ip: Peer, state: &AppState, members: Members) -> GatewayResult {
+        let (tx, limits) = (&state.tx, &state.config.limits);
+        let (reader, writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+        let writer = Arc::new(Mutex::new(writer));
+        let goodbye = format!("ERROR :Closing Link: {}", SHUTDOWN_REASON);
+
+        let registered = tokio::select! {
+            registered = Self::register(&mut reader, &writer, &ip, state) => registered?,
+            _ = state.shutdown.wait() => return send_line(&writer, &goodbye).await,
+        };
+        let Some(user) = registered else { return Ok(()) };
+        let nick = user.handle.clone();
+        // so moderation can close it, see connections.rs
+        let mut registration = state.connections.register(&nick, None);
+
+        let welcome = [
+            format!("001 {} :Welcome to TRC through the IRC gateway, {}", nick, nick),
+            format!("002 {} :Your host is {}, running TRCd", nick, SERVER_NAME),
+            format!("003 {} :This server has no creation date, it's a gateway", nick),
+            format!("004 {} {} trcd-{} o o", nick, SERVER_NAME, env!("CARGO_PKG_VERSION")),
+            format!("005 {} CHANTYPES=# CHANNELLEN={} CASEMAPPING=ascii :are supported by this server", nick, limits.max_channel_name_length_bytes + 1),
+            format!("422 {} :MOTD File is missing", nick),
+        ];
+        for line in welcome {
+            send_numeric(&writer, &line).await?;
+        }
+
+        // ids of messages this connection sent, IRC clients don't expect their own messages back
+        let sent_ids = Arc::new(Mutex::new(HashSet::<u64>::new()));
+        let joined = Arc::new(Mutex::new(HashSet::<String>::new()));
+
+        // subscribe to the broadcast channel
+        let rx = tx.subscribe();
+
+        let result = tokio::select! {
+            res = Self::handle_commands(reader, writer.clone(), &ip, &user, state, &members, joined.clone(), sent_ids.clone(), registration.presence()) => res,
+            res = Self::handle_broadcast(writer.clone(), rx, joined.clone(), sent_ids.clone()) => res,
+            _ = state.shutdown.wait() => send_line(&writer, &goodbye).await,
+            reason = registration.disconnected() => send_line(&writer, &format!("ERROR :Closing Link: {}", reason)).await,
+        };
+
+        // forget this client everywhere it joined
+        let mut members = members.lock().await;
+        for channel in joined.lock().await.iter() {
+            if let Some(handles) = members.get_mut(channel) {
+                handles.remove(&nick);
+                if handles.is_empty() {
+                    members.remove(channel);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// PASS/NICK/USER registration, returns None if the client left or failed to log in
+    async fn register(reader: &mut BufReader<ReadHalf>, writer: &Mutex<WriteHalf>, ip: &Peer, state: &AppState) -> Result<Option<User>, Box<dyn Error + Send + Sync>> {
+        let (mut password, mut nick, mut registered_user) = (None, None, false);
+        let mut negotiating = false; // registration waits for `CAP END` once a client sends `CAP LS`
+
+        while nick.is_none() || !registered_user || negotiating {
+            let Some(line) = read_line(reader, MAX_LINE_BYTES).await? else { return Ok(None) };
+            let Some(message) = IrcMessage::parse(&line) else { continue };
+
+            match message.command.as_str() {
+                // no IRCv3 capabilities, but answering keeps modern clients from waiting on us
+                "CAP" => match message.params.first().map(String::as_str) {
+                    Some("LS") => {
+                        negotiating = true;
+                        send_line(writer, &format!(":{} CAP * LS :", SERVER_NAME)).await?;
+                    },
+                    Some("END") => negotiating = false,
+                    _ => {},
+                },
+                "PASS" => password = message.params.into_iter().next(),
+                "NICK" => nick = message.params.into_iter().next(),
+                "USER" => registered_user = true,
+                "PING" => send_line(writer, &format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, message.params.join(" "))).await?,
+                "QUIT" => return Ok(None),
+                _ => send_numeric(writer, "451 * :You have not registered").await?,
+            }
+        }
+        let nick = nick.expect("loop only exits with a nick");
+
+        let Some(password) = password else {
+            send_numeric(writer, &format!("464 {} :A password (your TRC password, sent with PASS) is required", nick)).await?;
+            send_line(writer, "ERROR :Closing Link: no password").await?;
+            return Ok(None);
+        };
+
+        match two_factor::check_inline(state, ip, &nick, &password).await {
+            Ok(user) => Ok(Some(user)),
+            Err(LoginError::Throttled(wait)) => {
+                send_numeric(writer, &format!("464 {} :Password incorrect", nick)).await?;
+                send_line(writer, &format!("ERROR :Closing Link: {}", throttle::describe(wait))).await?;
+                Ok(None)
+            },
+            Err(LoginError::Banned(ban)) => {
+                send_numeric(writer, &format!("465 {} :You are banned from this server", nick)).await?;
+                send_line(writer, &format!("ERROR :Closing Link: you are {}", ban.describe())).await?;
+                Ok(None)
+            },
+            Err(LoginError::SecondFactorRequired) => {
+                send_numeric(writer, &format!("464 {} :Password incorrect", nick)).await?;
+                send_line(writer, "ERROR :Closing Link: send your two-factor code after the password (PASS :<password> <code>)").await?;
+                Ok(None)
+            },
+            Err(_) => {
+                warn!("failed IRC gateway login for {} from ip: {}", nick, ip);
+                send_numeric(writer, &format!("464 {} :Password incorrect", nick)).await?;
+                send_line(writer, "ERROR :Closing Link: invalid handle or password").await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// commands from a registered client
+    #[allow(clippy::too_many_arguments)]
+    async fn handle_commands(
+        mut reader: BufReader<ReadHalf>,
+        writer: Arc<Mutex<WriteHalf>>,
+        ip: &Peer,
+        user: &User,
+        state: &AppState,
+        members: &Members,
+        joined: Arc<Mutex<HashSet<String>>>,
+        sent_ids: Arc<Mutex<HashSet<u64>>>,
+        presence: Presence
+    ) -> GatewayResult {
+        let (tx, limits) = (&state.tx, &state.config.limits);
+        let nick = &user.handle;
+        let max_length = limits.max_channel_name_length_bytes;
+        let mut stupid_message_counter: u8 = 0; // prevent useless message abuse
+
+        loop {
+            let Some(line) = read_line(&mut reader, MAX_LINE_BYTES).await? else { return Ok(()) };
+            let Some(message) = IrcMessage::parse(&line) else { continue };
+            trace!("IRC client sent {}", message.command);
+            let params = &message.params;
+
+            match message.command.as_str() {
+                "PING" => send_line(&writer, &format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, params.join(" "))).await?,
+                "PONG" => {},
+                "QUIT" => {
+                    send_line(&writer, "ERROR :Closing Link: bye").await?;
+                    return Ok(());
+                },
+                "JOIN" => {
+                    let Some(targets) = params.first() else {
+                        send_numeric(&writer, &format!("461 {} JOIN :Not enough parameters", nick)).await?;
+                        continue;
+                    };
+                    for target in targets.split(',') {
+                        let Some(channel) = trc_channel(target, max_length) else {
+                            send_numeric(&writer, &format!("403 {} {} :No such channel", nick, target)).await?;
+                            continue;
+                        };
+                        if !joined.lock().await.insert(channel.to_string()) { continue; }
+                        presence.join(channel);
+                        members.lock().await.entry(channel.to_string()).or_default().insert(nick.clone());
+
+                        send_line(&writer, &format!(":{} JOIN #{}", user_prefix(nick), channel)).await?;
+                        send_numeric(&writer, &format!("331 {} #{} :No topic is set", nick, channel)).await?;
+                        Self::send_names(&writer, nick, channel, members).await?;
+                    }
+                },
+                "PART" => {
+                    let Some(targets) = params.first() else {
+                        send_numeric(&writer, &format!("461 {} PART :Not enough parameters", nick)).await?;
+                        continue;
+                    };
+                    for target in targets.split(',') {
+                        let channel = trc_channel(target, max_length).unwrap_or(target);
+                        if !joined.lock().await.remove(channel) {
+                            send_numeric(&writer, &format!("442 {} {} :You're not on that channel", nick, target)).await?;
+                            continue;
+                        }
+                        presence.part(channel);
+                        if let Some(handles) = members.lock().await.get_mut(channel) {
+                            handles.remove(nick);
+                        }
+                        send_line(&writer, &format!(":{} PART #{}", user_prefix(nick), channel)).await?;
+                    }
+                },
+                command @ ("PRIVMSG" | "NOTICE") => {
+                    let (Some(target), Some(content)) = (params.first(), params.get(1)) else {
+                        if command == "PRIVMSG" {
+                            send_numeric(&writer, &format!("412 {} :No text to send", nick)).await?;
+                        }
+                        continue;
+                    };
+                    let Some(channel) = trc_channel(target, max_length) else {
+                        // TRC has no direct messages, and NOTICE must never trigger a reply
+                        if command == "PRIVMSG" {
+                            send_numeric(&writer, &format!("401 {} {} :TRC only supports channel messages", nick, target)).await?;
+                        }
+                        continue;
+                    };
+
+                    if let Some(muted) = state.mutes.check(nick) {
+                        if command == "PRIVMSG" {
+                            send_numeric(&writer, &format!("404 {} #{} :Cannot send to channel ({})", nick, channel, muted)).await?;
+                        }
+                        continue;
+                    }
+
+                    let message = ChannelMessage::new(channel.to_string(), content.clone(), user.clone());
+                    sent_ids.lock().await.insert(message.id);
+                    if tx.send(message).is_err() {
+                        warn!("IRC gateway sent a message with nobody listening");
+                    }
+                },
+                "TOPIC" => {
+                    let Some(channel) = params.first().and_then(|t| trc_channel(t, max_length)) else {
+                        send_numeric(&writer, &format!("461 {} TOPIC :Not enough parameters", nick)).await?;
+                        continue;
+                    };
+                    if params.len() > 1 {
+                        send_numeric(&writer, &format!("482 {} #{} :TRC channels don't have topics", nick, channel)).await?;
+                    } else {
+                        send_numeric(&writer, &format!("331 {} #{} :No topic is set", nick, channel)).await?;
+                    }
+                },
+                "NAMES" => {
+                    for channel in params.first().map(|t| t.split(',').filter_map(|t| trc_channel(t, max_length)).collect()).unwrap_or_else(Vec::new) {
+                        Self::send_names(&writer, nick, channel, members).await?;
+                    }
+                },
+                "WHO" => {
+                    let mask = params.first().cloned().unwrap_or_else(|| "*".to_string());
+                    if let Some(channel) = trc_channel(&mask, max_length) {
+                        let handles: Vec<String> = members.lock().await.get(channel)
+                            .map(|handles| handles.iter().cloned().collect())
+                            .unwrap_or_default();
+                        for handle in handles {
+                            send_numeric(&writer, &format!("352 {} #{} {} {} {} {} H :0 {}", nick, channel, handle, SERVER_NAME, SERVER_NAME, handle, handle)).await?;
+                        }
+                    }
+                    send_numeric(&writer, &format!("315 {} {} :End of WHO list", nick, mask)).await?;
+                },
+                // clients ask for modes on join, there aren't any
+                "MODE" => match params.first() {
+                    Some(target) if trc_channel(target, max_length).is_some() => send_numeric(&writer, &format!("324 {} {} +", nick, target)).await?,
+                    Some(_) => send_numeric(&writer, &format!("221 {} +", nick)).await?,
+                    None => send_numeric(&writer, &format!("461 {} MODE :Not enough parameters", nick)).await?,
+                },
+                "CAP" | "USER" | "PASS" => send_numeric(&writer, &format!("462 {} :You may not reregister", nick)).await?,
+                "NICK" => send_line(&writer, &format!(":{} NOTICE {} :Your nick is your TRC handle and can't be changed", SERVER_NAME, nick)).await?,
+                command => {
+                    stupid_message_counter += 1;
+                    if stupid_message_counter > limits.max_unsupported_frames {
+                        warn!("Weird data exceeded threshold from ip: {}", *ip);
+                        send_line(&writer, "ERROR :Closing Link: too many unknown commands").await?;
+                        return Ok(());
+                    }
+                    send_numeric(&writer, &format!("421 {} {} :Unknown command", nick, command)).await?;
+                },
+            }
+        }
+    }
+
+    /// messages from the broadcast channel for the channels the client joined
+    async fn handle_broadcast(
+        writer: Arc<Mutex<WriteHalf>>,
+        mut rx: Receiver<ChannelMessage>,
+        joined: Arc<Mutex<HashSet<String>>>,
+        sent_ids: Arc<Mutex<HashSet<u64>>>
+    ) -> GatewayResult {
+        loop {
+            let m = match rx.recv().await {
+                Ok(m) => m,
+                Err(_) => {
+                    warn!("caught a channel recv error. Closing connection to reduce load.");
+                    send_line(&writer, "ERROR :Closing Link: fell too far behind").await?;
+                    return Err("caught a channel recv error".into());
+                }
+            };
+
+            if !joined.lock().await.contains(&m.channel) { continue; }
+            if sent_ids.lock().await.remove(&m.id) { continue; }
+
+            // IRC lines can't contain newlines, so multi-line messages become several PRIVMSGs
+            for content in m.content.lines().filter(|l| !l.is_empty()) {
+                match m.system {
+                    true => send_line(&writer, &format!(":{} NOTICE #{} :{}", SERVER_NAME, m.channel, content)).await?,
+                    false => send_line(&writer, &format!(":{} PRIVMSG #{} :{}", user_prefix(&m.sender.handle), m.channel, content)).await?,
+                }
+            }
+        }
+    }
+
+    async fn send_names(writer: &Mutex<WriteHalf>, nick: &str, channel: &str, members: &Members) -> GatewayResult {
+        let names = members.lock().await.get(channel)
+            .map(|handles| handles.iter().cloned().collect::<Vec<_>>().join(" "))
+            .unwrap_or_default();
+
+        send_numeric(writer, &format!("353 {} = #{} :{}", nick, channel, names)).await?;
+        send_numeric(writer, &format!("366 {} #{} :End of /NAMES list.", nick, channel)).await
+    }
+}
+
+/// `#general` -> `general`, None for anything that isn't a valid channel
+fn trc_channel(target: &str, max_length: usize) -> Option<&str> {
+    let channel = target.strip_prefix('#')?;
+    if channel.is_empty() || channel.len() > max_length || channel.contains([' ', ',', '\x07']) {
+        return None;
+    }
+
+    Some(channel)
+}
+
+fn user_prefix(handle: &str) -> String {
+    format!("{}!{}@{}", handle, handle, SERVER_NAME)
+}
+
+async fn send_line(writer: &Mutex<WriteHalf>, line: &str) -> GatewayResult {
+    let line: String = line.chars().filter(|c| *c != '\r' && *c != '\n').collect();
+    writer.lock().await.write_all(format!("{}\r\n", line).as_bytes()).await?;
+    Ok(())
+}
+
+async fn send_numeric(writer: &Mutex<WriteHalf>, line: &str) -> GatewayResult {
+    send_line(writer, &format!(":{} {}", SERVER_NAME, line)).await
+}
+
+#[test]
+fn test_irc_parse() {
+    assert_eq!(
+        IrcMessage::parse(":nick!user@host PRIVMSG #general :hello there :)"),
+        Some(IrcMessage { command: "PRIVMSG".to_string(), params: vec!["#general".to_string(), "hello there :)".to_string()] })
+    );
+    assert_eq!(
+        IrcMessage::parse("@time=now user alice 0 * :Alice A"),
+        Some(IrcMessage { command: "USER".to_string(), params: vec!["alice", "0", "*", "Alice A"].into_iter().map(String::from).collect() })
+    );
+    assert_eq!(IrcMessage::parse("   "), None);
+
+    assert_eq!(trc_channel("#general", 30), Some("general"));
+    assert_eq!(trc_channel("general", 30), None, "IRC channels need a # prefix");
+    assert_eq!(trc_channel("#general", 4), None, "channel names over the configured limit should be rejected");
+}
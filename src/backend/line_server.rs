@@ -0,0 +1,279 @@
+//! Plain TCP line protocol, so `nc example.com 3002` is a working TRC client (if `server.line_bind`
+//! is set, see config.rs)
+//!
+//! Every command is one line: authenticate with `LOGIN <handle> <password>` or `TOKEN <jwt>`, then
+//! `JOIN`/`PART` channels and `SAY` things in them. Messages from joined channels are written back
+//! one per line. It shares the broadcast channel with the socket server, so everyone sees
+//! everyone regardless of transport.
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::Arc;
+
+use log::{info, trace, warn};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{Mutex, broadcast::Receiver};
+
+use crate::authentication::credentials::LoginError;
+use crate::authentication::{throttle, two_factor};
+use crate::authentication::middleware::authenticate_session_token;
+use crate::authentication::user::User;
+use crate::backend::connections::Presence;
+use crate::backend::socket_server::ChannelMessage;
+use crate::backend::listener::{Listener, Peer, ReadHalf, Stream, WriteHalf};
+use crate::backend::server::AppState;
+use crate::backend::shutdown::SHUTDOWN_REASON;
+
+/// longest line a client may send, anything longer gets the connection closed
+const MAX_LINE_BYTES: usize = 4096;
+const MAX_LOGIN_ATTEMPTS: u8 = 3;
+
+const HELP: &str = "commands: LOGIN <handle> <password> | TOKEN <jwt> | JOIN <channel> | PART <channel> | SAY <channel> <message> | CHANNELS | HELP | QUIT";
+
+pub struct LineServer {
+    listener: Listener,
+}
+impl LineServer {
+    pub fn new(listener: Listener) -> Self {
+        LineServer {
+            listener,
+        }
+    }
+
+    /// accept clients until the server shuts down
+    pub async fn run(mut self, state: AppState) {
+        let scheme = if self.listener.is_tls() {"tls"} else {"tcp"};
+        info!("Line server bound to {}://{}", scheme, self.listener.local_addr());
+
+        loop {
+            let (stream, ip) = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = state.shutdown.wait() => return,
+            };
+
+            let state = state.clone();
+            tokio::spawn(async move {
+                let _connection = state.shutdown.connection();
+                info!("Line client connected from ip: {}", ip);
+                if let Err(e) = Self::handle_connection(stream, ip, &state).await {
+                    warn!("{:?}", e);
+                }
+                info!("line client disconnected (ip: {})", ip);
+            });
+        }
+    }
+
+    async fn handle_connection(stream: Box<dyn Stream>, ip: Peer, state: &AppState) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let tx = &state.tx;
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+
+        writer.write_all(format!("TRCd line protocol. {}\r\n", HELP).as_bytes()).await?;
+
+        // log in before anything else
+        let mut attempts: u8 = 0;
+        let (user, session) = loop {
+            let line = tokio::select! {
+                line = read_line(&mut reader, MAX_LINE_BYTES) => line?,
+                _ = state.shutdown.wait() => {
+                    writer.write_all(format!("ERR {}, bye\r\n", SHUTDOWN_REASON).as_bytes()).await?;
+                    return Ok(());
+                },
+            };
+            let Some(line) = line else { return Ok(()) };
+            let (command, rest) = split_command(&line);
+
+            let result = match command.as_str() {
+                "LOGIN" => {
+                    let (handle, password) = rest.split_once(' ').unwrap_or((rest, ""));
+                    two_factor::check_inline(state, &ip, handle, password).await.map(|user| (user, None)).map_err(|e| match e {
+                        LoginError::Banned(ban) => format!("you are {}", ban.describe()),
+                        LoginError::Throttled(wait) => throttle::describe(wait),
+                        LoginError::SecondFactorRequired => "send your two-factor code after the password: LOGIN <handle> <password> <code>".to_string(),
+                        _ => "invalid handle or password".to_string(),
+                    })
+                },
+                "TOKEN" => authenticate_session_token(state, rest).await.map(|(user, session)| (user, Some(session))).map_err(|e| e.to_string()),
+                "HELP" => { writer.write_all(format!("OK {}\r\n", HELP).as_bytes()).await?; continue; },
+                "QUIT" => { writer.write_all(b"OK bye\r\n").await?; return Ok(()) },
+                _ => Err("log in first with LOGIN <handle> <password> or TOKEN <jwt>".to_string()),
+            };
+
+            match result {
+                Ok(user) => break user,
+                Err(e) => {
+                    writer.write_all(format!("ERR {}\r\n", e).as_bytes()).await?;
+                    attempts += 1;
+                    if attempts >= MAX_LOGIN_ATTEMPTS {
+                        warn!("too many failed line protocol logins from ip: {}", ip);
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        writer.write_all(format!("OK welcome @{}\r\n", user.handle).as_bytes()).await?;
+        // so moderation can close it, see connections.rs
+        let mut registration = state.connections.register(&user.handle, session.as_deref());
+
+        // subscribe to the broadcast channel
+        let rx = tx.subscribe();
+        let joined = Arc::new(Mutex::new(HashSet::<String>::new()));
+        let writer = Arc::new(Mutex::new(writer));
+
+        tokio::select! {
+            res = Self::handle_commands(reader, writer.clone(), &ip, &user, state, joined.clone(), registration.presence()) => res,
+            res = Self::handle_broadcast(writer.clone(), rx, joined.clone()) => res,
+            _ = state.shutdown.wait() => {
+                writer.lock().await.write_all(format!("ERR {}, bye\r\n", SHUTDOWN_REASON).as_bytes()).await?;
+                Ok(())
+            },
+            reason = registration.disconnected() => {
+                writer.lock().await.write_all(format!("ERR {}, bye\r\n", reason).as_bytes()).await?;
+                Ok(())
+            },
+        }
+    }
+
+    /// commands from an authenticated client
+    async fn handle_commands(
+        mut reader: BufReader<ReadHalf>,
+        writer: Arc<Mutex<WriteHalf>>,
+        ip: &Peer,
+        user: &User,
+        state: &AppState,
+        joined: Arc<Mutex<HashSet<String>>>,
+        presence: Presence
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let limits = &state.config.limits;
+        let mut stupid_message_counter: u8 = 0; // prevent useless message abuse
+        let max_length = limits.max_channel_name_length_bytes;
+
+        loop {
+            let Some(line) = read_line(&mut reader, MAX_LINE_BYTES).await? else { return Ok(()) };
+            let (command, rest) = split_command(&line);
+
+            let response = match command.as_str() {
+                "JOIN" | "PART" if rest.is_empty() || rest.contains(' ') => "ERR expected a single channel name".to_string(),
+                "JOIN" | "PART" if rest.len() > max_length => {
+                    format!("ERR Channel name too long in bytes. Max is {}", max_length)
+                },
+                "JOIN" => {
+                    joined.lock().await.insert(rest.to_string());
+                    presence.join(rest);
+                    format!("OK joined {}", rest)
+                },
+                "PART" => {
+                    match joined.lock().await.remove(rest) {
+                        true => {
+                            presence.part(rest);
+                            format!("OK left {}", rest)
+                        },
+                        false => format!("ERR not in {}", rest),
+                    }
+                },
+                "SAY" => {
+                    match rest.split_once(' ') {
+                        Some((channel, content)) if !content.trim().is_empty() && channel.len() <= max_length => {
+                            if let Some(muted) = state.mutes.check(&user.handle) {
+                                writer.lock().await.write_all(format!("ERR {}\r\n", muted).as_bytes()).await?;
+                                continue;
+                            }
+                            let _ = state.tx.send(ChannelMessage::new(channel.to_string(), content.to_string(), user.clone()));
+                            "OK".to_string()
+                        },
+                        _ => "ERR expected SAY <channel> <message>".to_string(),
+                    }
+                },
+                "CHANNELS" => {
+                    let joined = joined.lock().await;
+                    let mut channels: Vec<&str> = joined.iter().map(String::as_str).collect();
+                    channels.sort();
+                    format!("OK {}", channels.join(" "))
+                },
+                "HELP" => format!("OK {}", HELP),
+                "QUIT" => {
+                    writer.lock().await.write_all(b"OK bye\r\n").await?;
+                    return Ok(());
+                },
+                _ => {
+                    stupid_message_counter += 1;
+                    if stupid_message_counter > limits.max_unsupported_frames {
+                        warn!("Weird data exceeded threshold from ip: {}", *ip);
+                        writer.lock().await.write_all(b"ERR too many unknown commands, bye\r\n").await?;
+                        return Ok(());
+                    }
+                    "ERR unknown command, try HELP".to_string()
+                },
+            };
+
+            trace!("line client sent {}", command);
+            writer.lock().await.write_all(format!("{}\r\n", response).as_bytes()).await?;
+        }
+    }
+
+    /// messages from the broadcast channel for the channels the client joined
+    async fn handle_broadcast(
+        writer: Arc<Mutex<WriteHalf>>,
+        mut rx: Receiver<ChannelMessage>,
+        joined: Arc<Mutex<HashSet<String>>>
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let m = match rx.recv().await {
+                Ok(m) => m,
+                Err(_) => {
+                    warn!("caught a channel recv error. Closing connection to reduce load.");
+                    return Err("caught a channel recv error".into());
+                }
+            };
+
+            {
+                let joined = joined.lock().await;
+                if !joined.contains("ALL") && !joined.contains(&m.channel) { continue; }
+            }
+
+            let line = format_message(&m);
+            writer.lock().await.write_all(line.as_bytes()).await?;
+        }
+    }
+}
+
+/// read one line, None once the client hangs up. Lines longer than `max_bytes` are an error.
+pub async fn read_line(reader: &mut BufReader<ReadHalf>, max_bytes: usize) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    let mut buffer = Vec::new();
+    let read = reader.take(max_bytes as u64 + 1).read_until(b'\n', &mut buffer).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if buffer.len() > max_bytes {
+        return Err("line too long".into());
+    }
+
+    let line = String::from_utf8_lossy(&buffer);
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// split a line into an uppercased command and the rest of the line
+fn split_command(line: &str) -> (String, &str) {
+    let (command, rest) = line.trim_start().split_once(' ').unwrap_or((line.trim(), ""));
+    (command.to_uppercase(), rest.trim())
+}
+
+/// `[channel] <handle> content` (or `[channel] * content` for notices), with control characters
+/// replaced so one message is one line
+fn format_message(m: &ChannelMessage) -> String {
+    let content: String = m.content.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+
+    match m.system {
+        true => format!("[{}] * {}\r\n", m.channel, content),
+        false => format!("[{}] <{}> {}\r\n", m.channel, m.sender.handle, content),
+    }
+}
+
+#[test]
+fn test_split_command() {
+    assert_eq!(split_command("say general hello there"), ("SAY".to_string(), "general hello there"));
+    assert_eq!(split_command("QUIT"), ("QUIT".to_string(), ""));
+    assert_eq!(split_command("  join  general "), ("JOIN".to_string(), "general"));
+}
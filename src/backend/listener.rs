@@ -0,0 +1,357 @@
+//! Listening sockets shared by every transport
+//!
+//! A `Listener` accepts TCP connections and, when TLS is configured, finishes the handshake before
+//! handing the stream over. The REST server, line protocol and IRC gateway all listen through it,
+//! so they get TLS the same way. Handshakes run in their own tasks so one slow client can't hold
+//! up everyone else's accept. The REST server can also listen on a unix socket, for a reverse
+//! proxy or admin tools on the same host. Those connections never use TLS.
+//!
+//! The certificate lives in a `CertificateStore`, which re-reads the PEM files when they change.
+//! Renewing a certificate (certbot, acme.sh, ...) doesn't need a restart.
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::extract::connect_info::Connected;
+use axum::serve::IncomingStream;
+use log::{info, trace, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{ServerConfig, crypto::aws_lc_rs, server::{ClientHello, ResolvesServerCert}, sign::CertifiedKey};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+
+use crate::config::TlsConfig;
+
+/// how long a client gets to finish the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// connections that finished their handshake but haven't been picked up yet
+const ACCEPT_BACKLOG: usize = 64;
+
+/// a connected client, plain or TLS
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+pub type ReadHalf = tokio::io::ReadHalf<Box<dyn Stream>>;
+pub type WriteHalf = tokio::io::WriteHalf<Box<dyn Stream>>;
+
+/// whoever is on the other end of a connection, for logging and `ConnectInfo`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// unix socket clients don't have an address worth printing
+    Unix,
+}
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(address) => write!(f, "{}", address),
+            Peer::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+impl Connected<IncomingStream<'_, Listener>> for Peer {
+    fn connect_info(stream: IncomingStream<'_, Listener>) -> Self {
+        *stream.remote_addr()
+    }
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+impl Socket {
+    async fn accept(&self) -> io::Result<(Box<dyn Stream>, Peer)> {
+        match self {
+            Socket::Tcp(listener) => {
+                let (stream, address) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Tcp(address)))
+            },
+            #[cfg(unix)]
+            Socket::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Unix))
+            },
+        }
+    }
+}
+impl Drop for Socket {
+    fn drop(&mut self) {
+        // unix sockets leave a file behind that would stop the next bind
+        #[cfg(unix)]
+        if let Socket::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+pub struct Listener {
+    local_addr: Peer,
+    unix_path: Option<PathBuf>,
+    tls: bool,
+    incoming: mpsc::Receiver<(Box<dyn Stream>, Peer)>,
+}
+impl Listener {
+    /// bind `address`, doing a TLS handshake with every client if `tls` is given
+    pub async fn bind(address: SocketAddr, tls: Option<TlsAcceptor>) -> io::Result<Self> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+
+        Ok(Self::spawn(Socket::Tcp(listener), Peer::Tcp(local_addr), None, tls))
+    }
+
+    /// bind a unix socket at `path` and give it `mode` permissions. A socket file left behind by a
+    /// server that didn't shut down cleanly is replaced, one that's still in use is an error.
+    #[cfg(unix)]
+    pub async fn bind_unix(path: &Path, mode: u32) -> io::Result<Self> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+            if tokio::net::UnixStream::connect(path).await.is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "another server is listening on this socket"));
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)?;
+        let socket = Socket::Unix(listener, path.to_path_buf());
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+        Ok(Self::spawn(socket, Peer::Unix, Some(path.to_path_buf()), None))
+    }
+
+    fn spawn(socket: Socket, local_addr: Peer, unix_path: Option<PathBuf>, tls: Option<TlsAcceptor>) -> Self {
+        let (tx, incoming) = mpsc::channel(ACCEPT_BACKLOG);
+        let is_tls = tls.is_some();
+        tokio::spawn(Self::accept_loop(socket, tls, tx));
+
+        Listener {
+            local_addr,
+            unix_path,
+            tls: is_tls,
+            incoming,
+        }
+    }
+
+    pub fn local_addr(&self) -> Peer {
+        self.local_addr
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
+    async fn accept_loop(socket: Socket, tls: Option<TlsAcceptor>, tx: mpsc::Sender<(Box<dyn Stream>, Peer)>) {
+        loop {
+            let accepted = tokio::select! {
+                accepted = socket.accept() => accepted,
+                // the Listener was dropped, stop accepting (and unbind) right away
+                _ = tx.closed() => return,
+            };
+
+            let (stream, peer) = match accepted {
+                Ok(v) => v,
+                Err(e) => {
+                    // usually out of file descriptors, give it a moment instead of spinning
+                    warn!("failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let Some(acceptor) = tls.clone() else {
+                let _ = tx.send((stream, peer)).await;
+                continue;
+            };
+
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => { let _ = tx.send((Box::new(stream), peer)).await; },
+                    // port scanners and plain text clients, not worth more than a trace
+                    Ok(Err(e)) => trace!("TLS handshake with {} failed: {}", peer, e),
+                    Err(_) => trace!("TLS handshake with {} timed out", peer),
+                }
+            });
+        }
+    }
+
+    /// the next client, after its TLS handshake if there is one
+    pub async fn accept(&mut self) -> (Box<dyn Stream>, Peer) {
+        self.incoming.recv().await.expect("accept loop stopped while the listener was still in use")
+    }
+}
+/// `127.0.0.1:3000`, or `unix:/path/to/socket` (the way nginx writes it)
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.unix_path {
+            Some(path) => write!(f, "unix:{}", path.display()),
+            None => write!(f, "{}", self.local_addr),
+        }
+    }
+}
+impl axum::serve::Listener for Listener {
+    type Io = Box<dyn Stream>;
+    type Addr = Peer;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        Self::accept(self).await
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(Self::local_addr(self))
+    }
+}
+
+/// The current certificate and key. Handed to rustls as the certificate resolver, so swapping the
+/// certificate here affects every listener's next handshake.
+#[derive(Debug)]
+pub struct CertificateStore {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().expect("certificate lock poisoned").clone())
+    }
+}
+impl CertificateStore {
+    /// load the certificate and key from the config, None if TLS is off
+    pub fn load(config: &TlsConfig) -> Result<Option<Arc<Self>>, String> {
+        let (Some(cert_path), Some(key_path)) = (&config.cert, &config.key) else { return Ok(None) };
+        let current = Self::read(cert_path, key_path)?;
+
+        Ok(Some(Arc::new(CertificateStore {
+            cert_path: cert_path.clone(),
+            key_path: key_path.clone(),
+            current: RwLock::new(Arc::new(current)),
+        })))
+    }
+
+    fn read(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+        let chain = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("unable to read certificates from {}: {}", cert_path.display(), e))?;
+        if chain.is_empty() {
+            return Err(format!("no certificates found in {}", cert_path.display()));
+        }
+
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| format!("unable to read a private key from {}: {}", key_path.display(), e))?;
+
+        CertifiedKey::from_der(chain, key, &aws_lc_rs::default_provider())
+            .map_err(|e| format!("{} doesn't work with {}: {}", key_path.display(), cert_path.display(), e))
+    }
+
+    /// re-read the files. The current certificate stays in use if they're broken.
+    pub fn reload(&self) -> Result<(), String> {
+        let certified_key = Self::read(&self.cert_path, &self.key_path)?;
+        *self.current.write().expect("certificate lock poisoned") = Arc::new(certified_key);
+        Ok(())
+    }
+
+    /// spawn a task that reloads the certificate whenever either file changes
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut last_modified = store.modified();
+            loop {
+                tokio::time::sleep(interval).await;
+
+                let modified = store.modified();
+                if modified == last_modified { continue; }
+                last_modified = modified;
+
+                // a renewal that's only half written will change the files again once it's done
+                match store.reload() {
+                    Ok(()) => info!("reloaded TLS certificate from {}", store.cert_path.display()),
+                    Err(e) => warn!("keeping the old TLS certificate, {}", e),
+                }
+            }
+        });
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert_path), modified(&self.key_path))
+    }
+
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let config = ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("the default provider supports the default TLS versions")
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+#[test]
+fn test_certificate_reload() {
+    let directory = std::env::temp_dir().join(format!("trcd-test-certificate-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let config = TlsConfig {
+        cert: Some(directory.join("cert.pem")),
+        key: Some(directory.join("key.pem")),
+        ..TlsConfig::default()
+    };
+
+    let write = |name: &str| {
+        let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        std::fs::write(directory.join("cert.pem"), generated.cert.pem()).unwrap();
+        std::fs::write(directory.join("key.pem"), generated.signing_key.serialize_pem()).unwrap();
+        generated
+    };
+
+    let first = write("first.example");
+    let store = CertificateStore::load(&config).unwrap().expect("TLS should be on");
+    let current = || store.current.read().unwrap().cert[0].clone();
+    assert_eq!(current().as_ref(), first.cert.der().as_ref());
+
+    let second = write("second.example");
+    store.reload().unwrap();
+    assert_eq!(current().as_ref(), second.cert.der().as_ref(), "reloading should pick up the new certificate");
+
+    // a key that doesn't belong to the certificate shouldn't replace a working pair
+    let other = rcgen::generate_simple_self_signed(vec!["other.example".to_string()]).unwrap();
+    std::fs::write(directory.join("key.pem"), other.signing_key.serialize_pem()).unwrap();
+    assert!(store.reload().is_err(), "a mismatched key should be rejected");
+    assert_eq!(current().as_ref(), second.cert.der().as_ref());
+
+    std::fs::remove_dir_all(&directory).unwrap();
+    assert!(CertificateStore::load(&TlsConfig::default()).unwrap().is_none(), "TLS should be off without files");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = std::env::temp_dir().join(format!("trcd-test-unix-socket-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("trcd.sock");
+
+    // a socket file nobody listens on anymore, like after a crash
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    let mut listener = Listener::bind_unix(&path, 0o600).await.expect("a stale socket file should be replaced");
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    assert_eq!(listener.to_string(), format!("unix:{}", path.display()));
+    assert!(Listener::bind_unix(&path, 0o600).await.is_err(), "a socket that's in use shouldn't be taken over");
+
+    let _client = tokio::net::UnixStream::connect(&path).await.unwrap();
+    let (_, peer) = listener.accept().await;
+    assert_eq!(peer, Peer::Unix);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -1,6 +1,18 @@
 pub mod socket_server;
 pub mod server;
+pub mod capabilities;
+pub mod encoding;
+pub mod history;
+pub mod resume;
+pub mod line_server;
+pub mod irc_gateway;
+pub mod listener;
+pub mod shutdown;
+pub mod http_client;
 
 pub const MAX_CHANNEL_NAME_LENGTH_BYTES: usize = size_of::<char>() * 30; // 30 basic characters
-                                                                         // long.
+                                                                         // long. (default, see
+                                                                         // config.rs)
 
+pub mod connections;
+pub mod moderation;
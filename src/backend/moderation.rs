@@ -0,0 +1,413 @@
+//! Moderation: kicking, muting (or timing out) and banning users
+//!
+//! Only people of a higher rank can be moderated (see UserPermissions): moderators act on users,
+//! admins on users and moderators, and nobody on admins. Kicking closes every live connection of a
+//! user through the connection registry. Mutes and bans are stored in the database and mirrored in
+//! memory, so they apply to the very next message or request. Mutes stop posting, bans stop
+//! everything (see BanList).
+//!
+//! Every action is available over REST (see Moderation::router) and the socket (`MOD` commands, see
+//! parse_command), and announced with a SYSTEM notice in the channels the user was in.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use axum::{Json, extract::{Path, State}, http::HeaderMap, routing::{get, post, put}};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::authentication::middleware::authenticate;
+use crate::authentication::two_factor;
+use crate::authentication::user::{User, UserPermissions};
+use crate::backend::server::{ApiError, AppState};
+use crate::backend::socket_server::ChannelMessage;
+use crate::database::database::{Ban, DBCalls, Mute};
+
+/// longest kick, mute or ban reason, it's shown to the user and in the channels they were in
+const MAX_REASON_LENGTH: usize = 300;
+/// longest mute or ban with an end, about ten years. Leave the duration out for longer ones.
+const MAX_DURATION_MINUTES: i64 = 60 * 24 * 365 * 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Kick { reason: String },
+    Mute { reason: String, duration_minutes: Option<i64> }, // None until unmuted
+    Unmute,
+    Ban { reason: String, duration_minutes: Option<i64> }, // None for permanent
+    Unban,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ModerationError {
+    BadRequest(String),
+    Forbidden(&'static str),
+    NotFound, // no such user, or nothing to undo
+    Internal, // not the moderator's fault, details are logged
+}
+impl fmt::Display for ModerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModerationError::BadRequest(e) => write!(f, "{}", e),
+            ModerationError::Forbidden(e) => write!(f, "{}", e),
+            ModerationError::NotFound => write!(f, "no such user, or nothing to undo"),
+            ModerationError::Internal => write!(f, "internal server error"),
+        }
+    }
+}
+impl From<ModerationError> for ApiError {
+    fn from(e: ModerationError) -> Self {
+        match e {
+            ModerationError::BadRequest(e) => ApiError::BadRequest(e),
+            ModerationError::Forbidden(e) => ApiError::Forbidden(e.to_string()),
+            ModerationError::NotFound => ApiError::NotFound,
+            ModerationError::Internal => ApiError::InternalServerError,
+        }
+    }
+}
+
+/// Mutes currently in force. The Mutes table is the source of truth, like BanList this keeps a
+/// copy in memory so checking every message doesn't cost a query.
+#[derive(Debug, Clone, Default)]
+pub struct MuteList {
+    mutes: Arc<RwLock<HashMap<String, Mute>>>,
+}
+impl MuteList {
+    pub async fn load(db: &impl DBCalls) -> Self {
+        let list = MuteList::default();
+        match db.fetch_mutes().await {
+            Ok(mutes) => {
+                let mut lock = list.mutes.write().unwrap();
+                for mute in mutes {
+                    lock.insert(mute.handle.clone(), mute);
+                }
+            },
+            Err(e) => warn!("unable to load mutes, nobody is muted until the next restart: {}", e),
+        }
+
+        list
+    }
+
+    /// the user's mute, if they have one that hasn't expired
+    pub fn get(&self, handle: &str) -> Option<Mute> {
+        let mute = self.mutes.read().unwrap().get(handle).cloned()?;
+        if mute.is_active() {
+            return Some(mute);
+        }
+
+        self.mutes.write().unwrap().remove(handle);
+        None
+    }
+
+    /// what to tell a user who tries to post, None if they may
+    pub fn check(&self, handle: &str) -> Option<String> {
+        self.get(handle).map(|mute| format!("you are {}", mute.describe()))
+    }
+
+    fn insert(&self, mute: Mute) {
+        self.mutes.write().unwrap().insert(mute.handle.clone(), mute);
+    }
+
+    fn remove(&self, handle: &str) {
+        self.mutes.write().unwrap().remove(handle);
+    }
+}
+
+/// the reason is written as-is to line protocol and IRC clients, a newline would be a command
+fn check_reason(reason: &str) -> Result<(), ModerationError> {
+    if reason.chars().count() > MAX_REASON_LENGTH || reason.chars().any(char::is_control) {
+        return Err(ModerationError::BadRequest(format!("reason can be at most {} characters, without control characters", MAX_REASON_LENGTH)));
+    }
+
+    Ok(())
+}
+
+fn check_duration(duration_minutes: Option<i64>) -> Result<(), ModerationError> {
+    match duration_minutes {
+        Some(minutes) if !(1..=MAX_DURATION_MINUTES).contains(&minutes) => Err(ModerationError::BadRequest(format!(
+            "the duration has to be between 1 and {} minutes, leave it out for no end", MAX_DURATION_MINUTES
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// ` for 10 minutes` or nothing
+fn describe_duration(duration_minutes: Option<i64>) -> String {
+    match duration_minutes {
+        Some(1) => " for 1 minute".to_string(),
+        Some(minutes) => format!(" for {} minutes", minutes),
+        None => String::new(),
+    }
+}
+
+/// `: reason` or nothing
+fn describe_reason(reason: &str) -> String {
+    match reason.is_empty() {
+        true => String::new(),
+        false => format!(": {}", reason),
+    }
+}
+
+fn internal(what: &str) -> impl FnOnce(Box<dyn std::error::Error>) -> ModerationError + '_ {
+    move |e| {
+        warn!("error {}: {}", what, e);
+        ModerationError::Internal
+    }
+}
+
+/// Have `actor` do `action` to the user with `handle`. `channel` is where the moderator is, it gets
+/// the notice even if the user isn't in it. Returns what the action stored (e.g. the ban), if
+/// anything.
+pub async fn moderate(state: &AppState, actor: &User, handle: &str, action: Action, channel: Option<&str>) -> Result<Value, ModerationError> {
+    if actor.permission_level < UserPermissions::Moderator {
+        return Err(ModerationError::Forbidden("only moderators and admins can moderate people"));
+    }
+    two_factor::require(state, actor).await.map_err(ModerationError::Forbidden)?;
+    match &action {
+        Action::Kick { reason } => check_reason(reason)?,
+        Action::Mute { reason, duration_minutes } | Action::Ban { reason, duration_minutes } => {
+            check_reason(reason)?;
+            check_duration(*duration_minutes)?;
+        },
+        Action::Unmute | Action::Unban => {},
+    }
+    if handle == actor.handle {
+        return Err(ModerationError::BadRequest("you can't moderate yourself".to_string()));
+    }
+
+    let target = state.db.fetch_user(handle).await.map_err(|_| ModerationError::NotFound)?.inner_user;
+    if !actor.outranks(&target) {
+        return Err(ModerationError::Forbidden("you can only moderate people of a lower rank"));
+    }
+
+    // before kicking them out, which forgets where they were
+    let mut channels = state.connections.channels_of(handle);
+    channels.extend(channel.map(str::to_string));
+
+    let (notice, value) = match action {
+        Action::Kick { reason } => {
+            let reason = reason.trim();
+            let closed = state.connections.disconnect(handle, &format!("you were kicked by @{}{}", actor.handle, describe_reason(reason)));
+            info!("@{} kicked @{}, closing {} connections", actor.handle, handle, closed);
+
+            (format!("@{} was kicked by @{}{}", handle, actor.handle, describe_reason(reason)), json!(closed))
+        },
+        Action::Mute { reason, duration_minutes } => {
+            let mute = Mute::new(handle, reason.trim(), &actor.handle, duration_minutes);
+            state.db.mute_user(&mute).await.map_err(internal("muting user"))?;
+            info!("@{} muted @{} ({})", actor.handle, handle, mute.describe());
+            state.mutes.insert(mute.clone());
+
+            let verb = if duration_minutes.is_some() { "timed out" } else { "muted" };
+            (format!("@{} was {}{} by @{}{}", handle, verb, describe_duration(duration_minutes), actor.handle, describe_reason(&mute.reason)), json!(mute))
+        },
+        Action::Unmute => {
+            if !state.db.unmute_user(handle).await.map_err(internal("unmuting user"))? {
+                return Err(ModerationError::NotFound);
+            }
+            state.mutes.remove(handle);
+            info!("@{} unmuted @{}", actor.handle, handle);
+
+            (format!("@{} was unmuted by @{}", handle, actor.handle), Value::Null)
+        },
+        Action::Ban { reason, duration_minutes } => {
+            let ban = Ban::new(handle, reason.trim(), &actor.handle, duration_minutes);
+            state.db.ban_user(&ban).await.map_err(internal("banning user"))?;
+            info!("@{} banned @{} ({})", actor.handle, handle, ban.describe());
+            state.bans.insert(ban.clone());
+            let closed = state.connections.disconnect(handle, &format!("you are {}", ban.describe()));
+            if closed > 0 { info!("closed {} connections of @{}", closed, handle); }
+
+            (format!("@{} was banned{} by @{}{}", handle, describe_duration(duration_minutes), actor.handle, describe_reason(&ban.reason)), json!(ban))
+        },
+        Action::Unban => {
+            if !state.db.unban_user(handle).await.map_err(internal("unbanning user"))? {
+                return Err(ModerationError::NotFound);
+            }
+            state.bans.remove(handle);
+            info!("@{} unbanned @{}", actor.handle, handle);
+
+            (format!("@{} was unbanned by @{}", handle, actor.handle), Value::Null)
+        },
+    };
+
+    for channel in channels {
+        let _ = state.tx.send(ChannelMessage::notice(channel, notice.clone(), actor.clone()));
+    }
+
+    Ok(value)
+}
+
+/// parse a `MOD <KICK|MUTE|UNMUTE|BAN|UNBAN> <handle> [minutes] [reason]` line from the socket.
+/// Minutes are required for MUTE and BAN, 0 means no end. Returns None if the text isn't a `MOD`
+/// line at all.
+pub fn parse_command(text: &str) -> Option<Result<(&str, Action), &'static str>> {
+    let mut words = text.splitn(3, ' ');
+    if words.next() != Some("MOD") {
+        return None;
+    }
+    const USAGE: &str = "expected `MOD KICK <handle> [reason]`, `MOD MUTE|BAN <handle> <minutes, 0 for no end> [reason]` or `MOD UNMUTE|UNBAN <handle>`";
+
+    let (Some(command), Some(rest)) = (words.next(), words.next()) else { return Some(Err(USAGE)) };
+    let (handle, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+    if handle.is_empty() {
+        return Some(Err(USAGE));
+    }
+
+    // MUTE and BAN take a duration before the reason
+    let timed = |rest: &str| -> Result<(String, Option<i64>), &'static str> {
+        let (minutes, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+        match minutes.parse::<i64>() {
+            Ok(0) => Ok((reason.to_string(), None)),
+            Ok(minutes) => Ok((reason.to_string(), Some(minutes))),
+            Err(_) => Err(USAGE),
+        }
+    };
+
+    let action = match command {
+        "KICK" => Ok(Action::Kick { reason: rest.to_string() }),
+        "MUTE" => timed(rest).map(|(reason, duration_minutes)| Action::Mute { reason, duration_minutes }),
+        "BAN" => timed(rest).map(|(reason, duration_minutes)| Action::Ban { reason, duration_minutes }),
+        "UNMUTE" if rest.is_empty() => Ok(Action::Unmute),
+        "UNBAN" if rest.is_empty() => Ok(Action::Unban),
+        _ => Err(USAGE),
+    };
+
+    Some(action.map(|action| (handle, action)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModerationRequest {
+    #[serde(default)]
+    reason: String,
+    duration_minutes: Option<i64>, // mutes and bans, None for no end
+    channel: Option<String>, // where it happened, announced there even if the user isn't in it
+}
+
+pub struct Moderation;
+impl Moderation {
+    pub fn router() -> axum::Router<AppState> {
+        axum::Router::new()
+            .route("/api/users/{handle}/kick", post(Self::kick))
+            .route("/api/users/{handle}/mute", put(Self::mute).delete(Self::unmute))
+            .route("/api/users/{handle}/ban", put(Self::ban).delete(Self::unban))
+            .route("/api/mutes", get(Self::list_mutes))
+            .route("/api/bans", get(Self::list_bans))
+    }
+
+    /// the actor, checking the rank of the target is up to moderate()
+    async fn require_moderator(state: &AppState, headers: HeaderMap) -> Result<User, ApiError> {
+        let user = authenticate(state, headers).await?;
+        if user.permission_level < UserPermissions::Moderator {
+            return Err(ApiError::Forbidden("only moderators and admins can moderate people".to_string()));
+        }
+        two_factor::require(state, &user).await.map_err(|e| ApiError::Forbidden(e.to_string()))?;
+
+        Ok(user)
+    }
+
+    async fn act(state: AppState, headers: HeaderMap, handle: &str, action: Action, channel: Option<&str>) -> Result<String, ApiError> {
+        let actor = Self::require_moderator(&state, headers).await?;
+        let value = moderate(&state, &actor, handle, action, channel).await?;
+
+        Ok(json!({"error": false, "value": value}).to_string())
+    }
+
+    async fn kick(State(state): State<AppState>, Path(handle): Path<String>, headers: HeaderMap, Json(body): Json<ModerationRequest>) -> Result<String, ApiError> {
+        Self::act(state, headers, &handle, Action::Kick { reason: body.reason }, body.channel.as_deref()).await
+    }
+
+    async fn mute(State(state): State<AppState>, Path(handle): Path<String>, headers: HeaderMap, Json(body): Json<ModerationRequest>) -> Result<String, ApiError> {
+        let action = Action::Mute { reason: body.reason, duration_minutes: body.duration_minutes };
+        Self::act(state, headers, &handle, action, body.channel.as_deref()).await
+    }
+
+    async fn unmute(State(state): State<AppState>, Path(handle): Path<String>, headers: HeaderMap) -> Result<String, ApiError> {
+        Self::act(state, headers, &handle, Action::Unmute, None).await
+    }
+
+    async fn ban(State(state): State<AppState>, Path(handle): Path<String>, headers: HeaderMap, Json(body): Json<ModerationRequest>) -> Result<String, ApiError> {
+        let action = Action::Ban { reason: body.reason, duration_minutes: body.duration_minutes };
+        Self::act(state, headers, &handle, action, body.channel.as_deref()).await
+    }
+
+    async fn unban(State(state): State<AppState>, Path(handle): Path<String>, headers: HeaderMap) -> Result<String, ApiError> {
+        Self::act(state, headers, &handle, Action::Unban, None).await
+    }
+
+    async fn list_mutes(State(state): State<AppState>, headers: HeaderMap) -> Result<String, ApiError> {
+        Self::require_moderator(&state, headers).await?;
+
+        let mutes = state.db.fetch_mutes().await.map_err(|e| {
+            warn!("error fetching mutes: {}", e);
+            ApiError::InternalServerError
+        })?;
+
+        Ok(json!({"error": false, "value": mutes}).to_string())
+    }
+
+    async fn list_bans(State(state): State<AppState>, headers: HeaderMap) -> Result<String, ApiError> {
+        Self::require_moderator(&state, headers).await?;
+
+        let bans = state.db.fetch_bans().await.map_err(|e| {
+            warn!("error fetching bans: {}", e);
+            ApiError::InternalServerError
+        })?;
+
+        Ok(json!({"error": false, "value": bans}).to_string())
+    }
+}
+
+#[test]
+fn test_parse_command() {
+    assert_eq!(parse_command("general"), None, "channel names should not be parsed as MOD lines");
+    assert_eq!(parse_command("MOD KICK bob"), Some(Ok(("bob", Action::Kick { reason: String::new() }))));
+    assert_eq!(
+        parse_command("MOD MUTE bob 10 stop flooding"),
+        Some(Ok(("bob", Action::Mute { reason: "stop flooding".to_string(), duration_minutes: Some(10) })))
+    );
+    assert_eq!(parse_command("MOD BAN bob 0"), Some(Ok(("bob", Action::Ban { reason: String::new(), duration_minutes: None }))));
+    assert_eq!(parse_command("MOD UNBAN bob"), Some(Ok(("bob", Action::Unban))));
+    assert!(matches!(parse_command("MOD MUTE bob soon"), Some(Err(_))), "mutes need a duration");
+    assert!(matches!(parse_command("MOD UNMUTE bob please"), Some(Err(_))));
+    assert!(matches!(parse_command("MOD WARN bob"), Some(Err(_))));
+}
+
+#[tokio::test]
+async fn test_rank_hierarchy() {
+    use crate::backend::server::Server;
+    use crate::config::Config;
+    use crate::database::{test_database_config, test_user};
+
+    let state = Server::create_state(Config { database: test_database_config(), ..Config::default() }).await;
+    let (admin, moderator, other_moderator, bob) = (
+        test_user("admin", UserPermissions::Admin).inner_user,
+        test_user("mod", UserPermissions::Moderator).inner_user,
+        test_user("othermod", UserPermissions::Moderator).inner_user,
+        test_user("bob", UserPermissions::User).inner_user,
+    );
+    for u in [&admin, &moderator, &other_moderator, &bob] {
+        state.db.add_user(test_user(&u.handle, u.permission_level.clone())).await.unwrap();
+    }
+    let mute = || Action::Mute { reason: "flooding".to_string(), duration_minutes: Some(10) };
+
+    assert_eq!(moderate(&state, &bob, "bob", mute(), None).await, Err(ModerationError::Forbidden("only moderators and admins can moderate people")));
+    assert!(matches!(moderate(&state, &moderator, "othermod", mute(), None).await, Err(ModerationError::Forbidden(_))), "equal ranks can't moderate each other");
+    assert!(matches!(moderate(&state, &moderator, "admin", mute(), None).await, Err(ModerationError::Forbidden(_))));
+    assert_eq!(moderate(&state, &moderator, "nobody", mute(), None).await, Err(ModerationError::NotFound));
+
+    // the notice goes to where the user is and where the moderator is
+    let mut rx = state.tx.subscribe();
+    let registration = state.connections.register("bob", None);
+    registration.presence().switch(Some("general"));
+    moderate(&state, &moderator, "bob", mute(), Some("random")).await.unwrap();
+    assert!(state.mutes.check("bob").is_some());
+    let notices = [rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+    assert_eq!(notices.iter().map(|m| m.channel.as_str()).collect::<Vec<_>>(), ["general", "random"]);
+    assert!(notices.iter().all(|m| m.system && m.content == "@bob was timed out for 10 minutes by @mod: flooding"));
+
+    moderate(&state, &admin, "othermod", Action::Ban { reason: String::new(), duration_minutes: None }, None).await.unwrap();
+    assert!(state.bans.get("othermod").is_some(), "admins outrank moderators");
+    assert_eq!(moderate(&state, &admin, "bob", Action::Unban, None).await, Err(ModerationError::NotFound), "bob wasn't banned");
+}
@@ -0,0 +1,102 @@
+//! Resumable socket sessions
+//!
+//! Every authenticated socket is handed a resume token. When the socket drops, its state
+//! (active channel and negotiated capabilities) is parked under that token for a while. A client
+//! that reconnects can send `RESUME <token> <last seen message id>` to get that state back along
+//! with the messages it missed (see history.rs).
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::backend::capabilities::Capabilities;
+use crate::backend::socket_server::UserActiveChannel;
+
+/// how long a dropped session can be resumed for by default, see config.rs
+pub const RESUME_WINDOW: Duration = Duration::from_secs(5 * 60);
+pub const RESUME_TOKEN_LENGTH: usize = 32;
+
+#[derive(Debug)]
+pub struct DetachedSession {
+    pub handle: String, // only the same user may resume a session
+    pub active_channel: UserActiveChannel,
+    pub capabilities: Capabilities,
+    detached_at: Instant,
+}
+impl DetachedSession {
+    pub fn new(handle: String, active_channel: UserActiveChannel, capabilities: Capabilities) -> Self {
+        DetachedSession {
+            handle,
+            active_channel,
+            capabilities,
+            detached_at: Instant::now(),
+        }
+    }
+
+    fn expired(&self, window: Duration) -> bool {
+        self.detached_at.elapsed() > window
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResumeStore {
+    window: Duration,
+    sessions: Arc<Mutex<HashMap<String, DetachedSession>>>,
+}
+impl ResumeStore {
+    pub fn new(window: Duration) -> Self {
+        ResumeStore {
+            window,
+            sessions: Arc::default(),
+        }
+    }
+
+    /// park a session so it can be resumed later, forgetting any that have expired
+    pub fn detach(&self, token: String, session: DetachedSession) {
+        let mut sessions = self.sessions.lock().expect("resume store lock poisoned");
+        sessions.retain(|_, session| !session.expired(self.window));
+        sessions.insert(token, session);
+    }
+
+    /// claim a parked session. Tokens are single use, even if they belong to someone else.
+    pub fn take(&self, token: &str, handle: &str) -> Option<DetachedSession> {
+        let session = self.sessions.lock().expect("resume store lock poisoned").remove(token)?;
+        if session.expired(self.window) || session.handle != handle {
+            return None;
+        }
+
+        Some(session)
+    }
+}
+
+/// parse a `RESUME <token> <last seen id>` line. Returns None if the text isn't a `RESUME` line at
+/// all.
+pub fn parse_resume(text: &str) -> Option<Result<(&str, u64), &'static str>> {
+    let mut words = text.split_whitespace();
+    if words.next() != Some("RESUME") {
+        return None;
+    }
+
+    let parsed = match (words.next(), words.next().map(str::parse::<u64>), words.next()) {
+        (Some(token), Some(Ok(last_seen)), None) => Ok((token, last_seen)),
+        _ => Err("expected `RESUME <token> <last seen message id>`"),
+    };
+
+    Some(parsed)
+}
+
+#[test]
+fn test_resume_take() {
+    let store = ResumeStore::new(RESUME_WINDOW);
+    store.detach("token".to_string(), DetachedSession::new("test_user".to_string(), UserActiveChannel::All, Capabilities::default()));
+
+    assert!(store.take("token", "someone_else").is_none(), "only the owner should be able to resume");
+    assert!(store.take("token", "test_user").is_none(), "a failed attempt should burn the token");
+
+    store.detach("token".to_string(), DetachedSession::new("test_user".to_string(), UserActiveChannel::All, Capabilities::default()));
+    assert!(store.take("token", "test_user").is_some());
+
+    assert_eq!(parse_resume("general"), None);
+    assert_eq!(parse_resume("RESUME abc 41"), Some(Ok(("abc", 41))));
+    assert!(matches!(parse_resume("RESUME abc"), Some(Err(_))));
+}
@@ -1,15 +1,36 @@
 //! File containing the API backend 
 
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
 use axum::{
-    Json, extract::{Path, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse}, routing::{get, post}
+    Json, extract::{Path, Query, State}, http::{HeaderMap, StatusCode}, response::{IntoResponse, sse::{Event, KeepAlive, Sse}}, routing::{delete, get, post}
 };
+use futures_util::{Stream, StreamExt, stream};
 use log::{info, warn};
 use serde::{Serialize, Deserialize};
 use serde_json::json;
-use tokio::sync::broadcast::Sender;
-use crate::{authentication::middleware::authenticate, database::database::DBCalls};
-use crate::database::sqlite::db_sqlite::{DB_Sqlite, DB_DEFAULT_URL};
-use crate::backend::socket_server::{ChannelMessage};
+use tokio::sync::broadcast::{self, Sender};
+use tokio::task::JoinSet;
+use crate::{authentication::middleware::authenticate_scoped, database::database::DBCalls};
+use crate::config::Config;
+use crate::database::sqlite::db_sqlite::DB_Sqlite;
+use crate::backend::{history::MessageHistory, resume::ResumeStore};
+use crate::backend::socket_server::{ChannelMessage, SocketMessage, SocketServer, UpdateType};
+use crate::backend::line_server::LineServer;
+use crate::backend::listener::{CertificateStore, Listener, Peer};
+use crate::backend::shutdown::{SHUTDOWN_REASON, Shutdown};
+use crate::backend::irc_gateway::IrcGateway;
+use crate::backend::connections::Connections;
+use crate::backend::moderation::{Moderation, MuteList};
+use crate::authentication::bans::BanList;
+use crate::authentication::oidc::Oidc;
+use crate::authentication::passwords::Passwords;
+use crate::authentication::ssh_keys::SshChallenges;
+use crate::authentication::throttle::LoginThrottle;
+use crate::authentication::two_factor::PendingLogins;
+use crate::authentication::users::UserCache;
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -17,7 +38,8 @@ pub enum ApiError {
     NotFound,
     BadRequest(String),
     InternalServerError,
-    Unauthorized
+    Unauthorized,
+    Forbidden(String)
 }
 
 impl IntoResponse for ApiError {
@@ -38,6 +60,10 @@ impl IntoResponse for ApiError {
             ApiError::Unauthorized => (
                 StatusCode::UNAUTHORIZED,
                 "either missing a token (x-auth-token) or an invalid token.".to_string()
+            ),
+            ApiError::Forbidden(msg) => (
+                StatusCode::FORBIDDEN,
+                msg
             )
         };
 
@@ -66,54 +92,209 @@ impl APIResponse {
     }
 
     pub fn new(error: bool, value: &str) -> Self {
-        APIResponse { error: error, value: value.to_string() }
+        APIResponse { error, value: value.to_string() }
     }
 }
 
+/// state shared by the REST routes, the socket server and the optional listeners
 #[derive(Debug, Clone)]
-pub struct APIState {
+pub struct AppState {
     pub tx: Sender<ChannelMessage>,
-    pub db: DB_Sqlite
+    pub db: DB_Sqlite,
+    pub history: MessageHistory,
+    pub sessions: ResumeStore,
+    pub shutdown: Shutdown,
+    pub bans: BanList,
+    pub users: UserCache,
+    pub login_throttle: LoginThrottle,
+    pub passwords: Passwords,
+    pub pending_logins: PendingLogins,
+    pub ssh_challenges: SshChallenges,
+    pub mutes: MuteList,
+    pub connections: Connections,
+    pub oidc: Option<Oidc>,
+    pub config: Arc<Config>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    channels: String, // comma separated, `ALL` for every channel
 }
 
 pub struct Server {
-    port: usize
+    config: Config
 }
 impl Server {
     
-    pub fn new(port: usize) -> Self {
+    pub fn new(config: Config) -> Self {
         Server {
-            port: port
+            config
         }
     }
 
-    pub async fn run(self, tx: Sender<ChannelMessage>) {
-        let app = Self::create_app(&self, tx).await;       
-        let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", self.port)).await.expect("failed to bind server");
+    /// serve until SIGTERM/SIGINT, then close every connection and the database. Errors are
+    /// startup problems (a port that's taken, a broken certificate).
+    pub async fn run(self) -> Result<(), String> {
+        // TLS is optional and covers every listener, see listener.rs
+        let certificates = CertificateStore::load(&self.config.tls)
+            .map_err(|e| format!("unable to load the TLS certificate: {}", e))?;
+        let tls = certificates.as_ref().map(CertificateStore::acceptor);
 
-        info!("Server Bound on http://0.0.0.0:{}, to see if it is fully up go to http://0.0.0.0:{}/api", self.port, self.port);
+        // bind everything before serving anything, so a port that's taken stops the server early
+        let bind = async |address: SocketAddr, what: &str| Listener::bind(address, tls.clone()).await
+            .map_err(|e| format!("unable to bind the {} to {}: {}", what, address, e));
+        let mut listeners = Vec::new();
+        for address in &self.config.server.bind {
+            listeners.push(bind(*address, "server").await?);
+        }
+        // for a proxy on the same host, never TLS (see listener.rs)
+        #[cfg(unix)]
+        if let Some(path) = &self.config.server.unix_socket {
+            let listener = Listener::bind_unix(path, self.config.server.unix_socket_mode).await
+                .map_err(|e| format!("unable to bind the server to unix socket {}: {}", path.display(), e))?;
+            listeners.push(listener);
+        }
+        // the line protocol and the IRC gateway are optional, see line_server.rs and irc_gateway.rs
+        let line_listener = match self.config.server.line_bind {
+            Some(address) => Some(bind(address, "line server").await?),
+            None => None,
+        };
+        let irc_listener = match self.config.server.irc_bind {
+            Some(address) => Some(bind(address, "IRC gateway").await?),
+            None => None,
+        };
+
+        if let Some(certificates) = &certificates {
+            certificates.watch(self.config.tls.reload_interval());
+        }
+        // optional, see oidc.rs
+        let oidc = Oidc::new(&self.config.oidc)
+            .map_err(|e| format!("unable to set up OpenID Connect: {}", e))?;
+        let mut state = Self::create_state(self.config).await;
+        state.oidc = oidc;
+        let shutdown = state.shutdown.clone();
+        shutdown.on_signal();
 
-        let _instance = axum::serve(listener, app).await.expect("failed to start server.");
+        let mut tasks = JoinSet::new();
+        if let Some(listener) = line_listener {
+            tasks.spawn(LineServer::new(listener).run(state.clone()));
+        }
+        if let Some(listener) = irc_listener {
+            tasks.spawn(IrcGateway::new(listener).run(state.clone()));
+        }
+
+        let app = Self::create_app(state.clone());
+        for listener in listeners {
+            let address = listener.to_string();
+            let (http, ws) = if listener.is_tls() {("https", "wss")} else {("http", "ws")};
+            info!("Server Bound on {}://{}, to see if it is fully up go to {}://{}/api", http, address, http, address);
+            info!("Socket server available at {}://{}/ws", ws, address);
+
+            // stops accepting on shutdown and waits for in-flight requests
+            let server = axum::serve(listener, app.clone().into_make_service_with_connect_info::<Peer>())
+                .with_graceful_shutdown(shutdown.wait_owned());
+            tasks.spawn(async move {
+                if let Err(e) = server.await {
+                    warn!("server on {} stopped: {}", address, e);
+                }
+            });
+        }
+
+        shutdown.wait().await;
+        let deadline = state.config.server.shutdown_timeout();
+        info!("waiting up to {} seconds for connections to close", deadline.as_secs());
+
+        let drained = tokio::time::timeout(deadline, async {
+            tasks.join_all().await;
+            shutdown.drained().await;
+        }).await;
+        if drained.is_err() {
+            warn!("{} connections were still open after {} seconds, closing them anyway", shutdown.open_connections(), deadline.as_secs());
+        }
+
+        state.db.close().await;
+        info!("shutdown complete");
+        Ok(())
     }
 
-    
+    pub(crate) async fn create_state(config: Config) -> AppState {
+        let (tx, _) = broadcast::channel::<ChannelMessage>(config.limits.broadcast_capacity);
 
-    async fn create_app(&self, tx: Sender<ChannelMessage>) -> axum::Router {
-        
-        let db_conn = DB_Sqlite::new(DB_DEFAULT_URL).await;
+        let db_conn = DB_Sqlite::new(&config.database).await;
         db_conn.setup().await;
+        // so logged out tokens stay logged out across restarts, see session.rs
+        crate::authentication::session::load_revocations(&db_conn).await;
+        let bans = BanList::load(&db_conn).await;
+        let mutes = MuteList::load(&db_conn).await;
 
-        let state = APIState {
-            tx: tx,
-            db: db_conn
-        };
+        // remember recent messages so reconnecting clients can catch up
+        let history = MessageHistory::with_capacity(config.limits.history_capacity);
+        history.record(tx.subscribe());
+
+        AppState {
+            tx,
+            db: db_conn,
+            history,
+            sessions: ResumeStore::new(config.limits.resume_window()),
+            shutdown: Shutdown::default(),
+            bans,
+            users: UserCache::new(config.auth.user_cache()),
+            login_throttle: LoginThrottle::new(&config.auth),
+            passwords: Passwords::new(&config.auth),
+            pending_logins: PendingLogins::default(),
+            ssh_challenges: SshChallenges::default(),
+            mutes,
+            connections: Connections::default(),
+            oidc: None,
+            config: Arc::new(config)
+        }
+    }
+
+    fn create_app(state: AppState) -> axum::Router {
         axum::Router::new()
             .route("/api/login", post(crate::authentication::routes::login)) // if I remember right, browsers hate when get requests
+            .route("/api/login/2fa", post(crate::authentication::routes::login_second_factor))
+            .route("/api/login/ssh/challenge", post(crate::authentication::routes::ssh_challenge))
+            .route("/api/login/ssh", post(crate::authentication::routes::login_ssh))
+            .route("/api/login/oidc", get(crate::authentication::routes::oidc_login))
+            .route("/api/login/oidc/callback", get(crate::authentication::routes::oidc_callback))
+            .route("/api/ssh-keys", post(crate::authentication::routes::add_ssh_key).get(crate::authentication::routes::list_ssh_keys))
+            .route("/api/ssh-keys/{id}", delete(crate::authentication::routes::remove_ssh_key))
+            .route("/api/register", post(crate::authentication::routes::register))
+            .route("/api/password-reset", post(crate::authentication::routes::complete_password_reset))
+            .route("/api/me/password", post(crate::authentication::routes::change_password))
+            .route("/api/me/oidc", post(crate::authentication::routes::link_oidc))
+            .route("/api/users/{handle}/password-reset", post(crate::authentication::routes::reset_password))
+            .route("/api/sessions", get(crate::authentication::routes::list_sessions))
+            .route("/api/sessions/{id}", delete(crate::authentication::routes::revoke_session))
+            .route("/api/users/{handle}/sessions", get(crate::authentication::routes::list_user_sessions))
+            .route("/api/users/{handle}/sessions/{id}", delete(crate::authentication::routes::revoke_user_session))
+            .route("/api/2fa", get(crate::authentication::routes::two_factor_status))
+            .route("/api/2fa/totp", post(crate::authentication::routes::start_totp).delete(crate::authentication::routes::disable_totp))
+            .route("/api/2fa/totp/confirm", post(crate::authentication::routes::confirm_totp))
+            .route("/api/2fa/recovery-codes", post(crate::authentication::routes::regenerate_recovery_codes))
+            .route("/.well-known/jwks.json", get(Self::jwks))
+            .route("/api/bots", post(crate::authentication::routes::create_bot))
+            .route("/api/bots/{handle}/keys", post(crate::authentication::routes::create_api_key).get(crate::authentication::routes::list_api_keys))
+            .route("/api/bots/{handle}/keys/{id}", delete(crate::authentication::routes::revoke_api_key))
+            .route("/api/token/refresh", post(crate::authentication::routes::refresh))
+            .route("/api/logout", post(crate::authentication::routes::logout))
+            .route("/api/invites", post(crate::authentication::routes::create_invite).get(crate::authentication::routes::list_invites))
+            .route("/api/invites/{code}", delete(crate::authentication::routes::revoke_invite))
             .route("/api/messages/{channel_name}", post(Self::new_message))
+            .route("/api/stream", get(Self::stream))
             .route("/api", get(Self::health_check))
+            .merge(SocketServer::router())
+            .merge(Moderation::router())
             .with_state(state)
     }
 
+    /// the public keys tokens are signed with, for other services to verify them (see
+    /// signing_keys.rs)
+    async fn jwks() -> impl IntoResponse {
+        Json(crate::authentication::token::jwks())
+    }
+
     async fn health_check() -> impl IntoResponse {
         Json(json!({
             "status": "ok",
@@ -121,24 +302,119 @@ impl Server {
         }))
     }
     
-    async fn new_message(State(state): State<APIState>, Path(channel_name): Path<String>, headers: HeaderMap, body: String) -> Result<&'static str, impl IntoResponse> {
+    async fn new_message(State(state): State<AppState>, Path(channel_name): Path<String>, headers: HeaderMap, body: String) -> Result<&'static str, impl IntoResponse> {
         // authenticate the user
-        let user = match authenticate(headers).await {
-            Ok(user) => user,
-            Err(_e) => return Err(ApiError::Unauthorized)
+        let (user, scope, _) = match authenticate_scoped(&state, headers).await {
+            Ok(authenticated) => authenticated,
+            Err(e) => return Err(ApiError::from(e))
         };
 
+        if !scope.can_post(&channel_name) {return Err(ApiError::Forbidden("this key can't post in that channel".to_string()))}
         if body.is_empty() {return Err(ApiError::BadRequest("body length cannot be 0".to_string()))}
+        if let Some(muted) = state.mutes.check(&user.handle) {return Err(ApiError::Forbidden(muted))}
 
-        let message = ChannelMessage {
-            channel: channel_name, 
-            content: body,
-            sender: user
-        };
+        let message = ChannelMessage::new(channel_name, body, user);
 
         let _ = state.tx.send(message);
 
         Ok("{\"error\": false}")
     }
 
+    /// Server-Sent Events alternative to the socket server, for clients that can't do websockets.
+    /// Sends the same MESSAGE events (always with ids) and replays missed messages from the
+    /// `Last-Event-ID` header.
+    async fn stream(State(state): State<AppState>, Query(query): Query<StreamQuery>, headers: HeaderMap) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+        // authenticate the user
+        let (user, scope, session) = authenticate_scoped(&state, headers.clone()).await?;
+
+        let channels: Vec<String> = query.channels
+            .split(',')
+            .filter(|c| !c.is_empty())
+            .map(str::to_string)
+            .collect();
+        if channels.is_empty() {return Err(ApiError::BadRequest("query \"channels\" cannot be empty".to_string()))}
+        let max_length = state.config.limits.max_channel_name_length_bytes;
+        if channels.iter().any(|c| c.len() > max_length) {
+            return Err(ApiError::BadRequest(format!("Channel name too long in bytes. Max is {}", max_length)))
+        }
+        if let Some(channel) = channels.iter().find(|c| !scope.can_read(c)) {
+            return Err(ApiError::Forbidden(format!("this key can't read {}", channel)))
+        }
+        // so moderation can close the stream and knows where the user is, see connections.rs
+        let registration = state.connections.register(&user.handle, session.as_deref());
+        for channel in channels.iter().filter(|c| *c != "ALL") {
+            registration.presence().join(channel);
+        }
+        let wants = move |channel: &str| channels.iter().any(|c| c == "ALL" || c == channel);
+
+        // subscribe before looking at the history so nothing slips between the two
+        let rx = state.tx.subscribe();
+
+        let last_event_id = headers.get("last-event-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        let (missed, complete) = match last_event_id {
+            Some(last_seen) => state.history.since(last_seen, |m| wants(&m.channel)),
+            None => (Vec::new(), true),
+        };
+        let replayed_until = missed.last().map_or(0, |m| m.id);
+
+        fn message_event(m: ChannelMessage) -> Result<Event, Infallible> {
+            let id = m.id.to_string();
+            let event = Event::default()
+                .id(id)
+                .json_data(SocketMessage::message(m, true))
+                .unwrap_or_else(|e| {
+                    warn!("Serialization Error: {}", e);
+                    Event::default().comment("unable to serialize message")
+                });
+            Ok(event)
+        }
+
+        fn system_event(content: &str) -> Result<Event, Infallible> {
+            let event = Event::default().json_data(json!({
+                "message_type": UpdateType::SYSTEM,
+                "error": false,
+                "content": content,
+                "value": Option::<()>::None
+            })).expect("static json always serializes");
+            Ok(event)
+        }
+
+        let notice = (!complete).then(|| system_event("some missed messages were already forgotten"));
+
+        // the shutdown and registration are taken out of the state after saying goodbye, which ends
+        // the stream so graceful shutdown isn't kept waiting on it
+        let live_state = Some((state.shutdown.clone(), registration));
+        let live = stream::unfold((rx, wants, live_state), move |(mut rx, wants, live_state)| async move {
+            let (shutdown, mut registration) = live_state?;
+            loop {
+                let received = tokio::select! {
+                    received = rx.recv() => received,
+                    _ = shutdown.wait() => return Some((system_event(SHUTDOWN_REASON), (rx, wants, None))),
+                    // kicked or banned, see connections.rs
+                    reason = registration.disconnected() => return Some((system_event(&reason), (rx, wants, None))),
+                };
+
+                match received {
+                    Ok(m) => {
+                        if !wants(&m.channel) || m.id <= replayed_until { continue; }
+                        return Some((message_event(m), (rx, wants, Some((shutdown, registration)))));
+                    },
+                    Err(_) => {
+                        // same as the socket server, the client can reconnect with Last-Event-ID
+                        warn!("caught a channel recv error. Closing stream to reduce load.");
+                        return None;
+                    }
+                }
+            }
+        });
+
+        let events = stream::iter(notice)
+            .chain(stream::iter(missed.into_iter().map(message_event)))
+            .chain(live);
+
+        Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+    }
+
 }
@@ -0,0 +1,126 @@
+//! Graceful shutdown
+//!
+//! SIGTERM or SIGINT (ctrl+c) triggers a `Shutdown`. Listeners stop accepting, every open
+//! connection is told the server is restarting and closed, in-flight REST requests get to finish.
+//! Connections register themselves with `Shutdown::connection()` so the server knows when it's
+//! safe to close the database and exit.
+
+use std::sync::Arc;
+
+use log::{info, warn};
+use tokio::sync::watch;
+
+/// what clients are told when they're disconnected by a shutdown
+pub const SHUTDOWN_REASON: &str = "server restarting";
+
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    triggered: Arc<watch::Sender<bool>>,
+    connections: Arc<watch::Sender<usize>>,
+}
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            triggered: Arc::new(watch::Sender::new(false)),
+            connections: Arc::new(watch::Sender::new(0)),
+        }
+    }
+}
+impl Shutdown {
+    pub fn trigger(&self) {
+        self.triggered.send_replace(true);
+    }
+
+    /// resolves once the shutdown has been triggered (straight away if it already has been)
+    pub async fn wait(&self) {
+        // the sender lives in self, so this can't fail
+        let _ = self.triggered.subscribe().wait_for(|triggered| *triggered).await;
+    }
+
+    /// an owned version of wait(), for APIs that need a 'static future
+    pub fn wait_owned(&self) -> impl Future<Output = ()> + Send + 'static {
+        let shutdown = self.clone();
+        async move { shutdown.wait().await }
+    }
+
+    /// keep the server from exiting until the returned guard is dropped
+    pub fn connection(&self) -> ConnectionGuard {
+        self.connections.send_modify(|count| *count += 1);
+        ConnectionGuard {
+            connections: self.connections.clone(),
+        }
+    }
+
+    pub fn open_connections(&self) -> usize {
+        *self.connections.borrow()
+    }
+
+    /// resolves once every connection guard has been dropped
+    pub async fn drained(&self) {
+        let _ = self.connections.subscribe().wait_for(|count| *count == 0).await;
+    }
+
+    /// spawn a task that triggers the shutdown on SIGTERM or SIGINT
+    pub fn on_signal(&self) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            let signal = wait_for_signal().await;
+            info!("received {}, shutting down", signal);
+            shutdown.trigger();
+        });
+    }
+}
+
+/// counts towards `Shutdown::open_connections()` while it's alive
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    connections: Arc<watch::Sender<usize>>,
+}
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.send_modify(|count| *count -= 1);
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("unable to listen for SIGTERM, only ctrl+c will shut down gracefully: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "ctrl+c"
+}
+
+#[test]
+fn test_shutdown_drain() {
+    let shutdown = Shutdown::default();
+    let first = shutdown.connection();
+    let second = shutdown.clone().connection();
+    assert_eq!(shutdown.open_connections(), 2, "clones should share the connection count");
+
+    drop(first);
+    assert_eq!(shutdown.open_connections(), 1);
+    assert!(!*shutdown.triggered.borrow());
+
+    shutdown.trigger();
+    assert!(*shutdown.clone().triggered.borrow(), "clones should see the trigger");
+
+    drop(second);
+    assert_eq!(shutdown.open_connections(), 0);
+}
@@ -1,137 +1,235 @@
 //! The Socket server for TRCd is what publishes updates to clients
 
-use std::{error::Error, net::SocketAddr};
+use std::error::Error;
 
 use futures_util::{StreamExt, stream::SplitStream};
 use serde::{Serialize};
-use axum::{extract::{ConnectInfo, State, WebSocketUpgrade, ws::{CloseFrame, WebSocket, close_code::UNSUPPORTED}}, response::IntoResponse, routing::any};
+use axum::{extract::{ConnectInfo, State, WebSocketUpgrade, ws::{CloseFrame, WebSocket, close_code::{POLICY, RESTART, UNSUPPORTED}}}, response::IntoResponse, routing::any};
 use axum::extract::ws::Message;
 use log::{info, warn, trace};
 use std::sync::Arc;
-use tokio::sync::{broadcast::{self, Receiver}};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::broadcast::Receiver;
 use futures_util::stream::SplitSink;
 use futures_util::SinkExt;
 use serde_json::json;
 
-use crate::backend::{self, server};
+use crate::backend::listener::Peer;
+use crate::backend::server::AppState;
+use crate::backend::shutdown::SHUTDOWN_REASON;
+use crate::backend::capabilities::{CapCommand, Capabilities, Capability, ServerLimits};
+use crate::backend::encoding::Encoding;
+use crate::backend::resume::{self, DetachedSession};
+use crate::backend::connections::Presence;
+use crate::backend::moderation;
 use crate::authentication::user::User;
-use crate::authentication::token::validate_token;
+use crate::authentication::api_keys::Scope;
+use crate::authentication::middleware::authenticate_scoped_token;
+use crate::authentication::random::random_token;
 
-const MAX_STUPID_MESSAGE: u8 = 10; // to prevent useless data abuse
+pub const MAX_STUPID_MESSAGE: u8 = 10; // to prevent useless data abuse (default, see config.rs)
 
 #[derive(Debug, Serialize, PartialEq)]
 #[allow(dead_code)]
-enum UpdateType {
+#[allow(clippy::upper_case_acronyms)] // these names are part of the wire format
+pub enum UpdateType {
     MESSAGE,
     SYSTEM, // SYSTEM is for commands or responses to requests from a client
     ERROR,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone)]
 #[allow(dead_code)]
-enum UserActiveChannel {
+pub enum UserActiveChannel {
     String(String),
     None,
     All
 }
+impl UserActiveChannel {
+    /// whether a message sent to `channel` should be delivered
+    pub fn wants(&self, channel: &str) -> bool {
+        match self {
+            UserActiveChannel::String(target) => target == channel,
+            UserActiveChannel::None => false,
+            UserActiveChannel::All => true,
+        }
+    }
+
+    /// whether a token or API key with `scope` may listen here
+    pub fn readable(&self, scope: &Scope) -> bool {
+        match self {
+            UserActiveChannel::String(channel) => scope.can_read(channel),
+            UserActiveChannel::None => true,
+            UserActiveChannel::All => scope.can_read("ALL"),
+        }
+    }
+
+    /// the channel this is, if it's a single one
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            UserActiveChannel::String(channel) => Some(channel),
+            UserActiveChannel::None | UserActiveChannel::All => None,
+        }
+    }
+}
 
 #[derive(Serialize, Debug)]
-struct SocketMessage {
+pub struct SocketMessage {
     pub message_type: UpdateType,
     pub content: String,
-    pub sender: Option<User>
+    pub sender: Option<User>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>, // only sent to clients with the `message-ids` capability
 }
+impl SocketMessage {
+    /// the MESSAGE (or SYSTEM, for notices) event clients get for a channel message
+    pub fn message(m: ChannelMessage, with_id: bool) -> Self {
+        SocketMessage {
+            message_type: if m.system { UpdateType::SYSTEM } else { UpdateType::MESSAGE },
+            content: m.content,
+            sender: Some(m.sender),
+            id: with_id.then_some(m.id)
+        }
+    }
+}
+
+/// ids are only unique for the lifetime of the process
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct ChannelMessage {
+    pub id: u64,
     pub channel: String,
     pub content: String,
-    pub sender: User 
+    pub sender: User,
+    pub system: bool, // a notice from the server (e.g. moderation) rather than something sender said
 }
+impl ChannelMessage {
+    /// create a new message with the next available id
+    pub fn new(channel: String, content: String, sender: User) -> Self {
+        ChannelMessage {
+            id: NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed),
+            channel,
+            content,
+            sender,
+            system: false
+        }
+    }
 
-#[derive(Debug, Clone)]
-pub struct AppState {
-    tx: broadcast::Sender<ChannelMessage>,
+    /// a SYSTEM notice in a channel, `sender` is whoever caused it
+    pub fn notice(channel: String, content: String, sender: User) -> Self {
+        ChannelMessage { system: true, ..Self::new(channel, content, sender) }
+    }
 }
 
-pub struct SocketServer {
-    port: usize,
+/// per connection state, shared between the send and recv halves of a socket
+#[derive(Debug, Clone)]
+struct ConnectionState {
+    /// active channel filters messages server side
+    active_channel: Arc<tokio::sync::Mutex<UserActiveChannel>>,
+    /// features negotiated with `CAP REQ`, nothing is enabled for legacy clients
+    capabilities: Arc<tokio::sync::Mutex<Capabilities>>,
+    /// highest message id already replayed after a `RESUME`, so the live feed doesn't repeat it
+    replayed_until: Arc<AtomicU64>,
 }
-impl SocketServer {
-    pub fn new(port: usize) -> Self {
-        SocketServer {
-            port: port,
+impl Default for ConnectionState {
+    fn default() -> Self {
+        ConnectionState {
+            active_channel: Arc::new(tokio::sync::Mutex::new(UserActiveChannel::None)),
+            capabilities: Arc::new(tokio::sync::Mutex::new(Capabilities::default())),
+            replayed_until: Arc::new(AtomicU64::new(0)),
         }
-    } 
-    
-    fn create_app() -> axum::Router {
-        let (tx, _) = broadcast::channel::<ChannelMessage>(1024);
-        let shared_tx = tx.clone(); // for the API (server), moved below
-
-        let state = AppState { 
-            tx: tx
-        };
+    }
+}
 
-        let server = server::Server::new(3000);
-        // start the server (yes, this is cursed.) with a broadcast element
-        tokio::spawn(async {server.run(shared_tx).await; panic!("API failed. See logs")});
+/// encode an event with a connection's negotiated encoding and send it
+async fn send_event<T: Serialize>(
+    ws_tx: &tokio::sync::Mutex<SplitSink<WebSocket, Message>>,
+    encoding: Encoding,
+    event: &T
+) -> Result<(), Box<dyn Error>> {
+    let frame = encoding.encode(event)?;
+    ws_tx.lock().await.send(frame).await?;
+    Ok(())
+}
 
-        axum::Router::new()
-            .route("/", any(Self::ws_handler))
-            .with_state(state)
+/// close a socket that keeps sending frames the server can't use
+async fn close_unsupported(ws_tx: &tokio::sync::Mutex<SplitSink<WebSocket, Message>>) -> Result<(), Box<dyn Error>> {
+    ws_tx.lock().await.send(Message::Close(Some(CloseFrame {
+        code: UNSUPPORTED,
+        reason: "This server supports pings, text (utf-8 formatted) and negotiated binary encodings.".into()
+    }))).await?;
 
-    }
+    Ok(())
+}
 
-    pub async fn run(self) {
-        let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", self.port))
-            .await
-            .expect("unable to bind websocket");
-        let app = Self::create_app();
-        info!("Socket server bound to ws://0.0.0.0:{}", self.port);
-        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.expect("Error starting the websocket service");
-        panic!("Socket Server Died");
+/// tell the client why the server is closing the socket, then close it. `code` is 1012 (service
+/// restart) for shutdowns and 1008 (policy violation) for moderation.
+async fn close_with_notice(ws_tx: &tokio::sync::Mutex<SplitSink<WebSocket, Message>>, encoding: Encoding, reason: &str, code: u16) -> Result<(), Box<dyn Error>> {
+    let notice = json!({
+        "message_type": UpdateType::SYSTEM,
+        "error": false,
+        "content": reason,
+        "value": Option::<()>::None
+    });
+    send_event(ws_tx, encoding, &notice).await?;
+
+    // close frames only have room for 123 bytes of reason, the notice has all of it
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) { end -= 1; }
+    ws_tx.lock().await.send(Message::Close(Some(CloseFrame {
+        code,
+        reason: reason[..end].into()
+    }))).await?;
+
+    Ok(())
+}
+
+/// The websocket half of the server, see server.rs for where it's mounted
+pub struct SocketServer;
+impl SocketServer {
+    pub fn router() -> axum::Router<AppState> {
+        axum::Router::new()
+            .route("/ws", any(Self::ws_handler))
     }
 
-    async fn ws_handler(ws: WebSocketUpgrade, ConnectInfo(address): ConnectInfo<SocketAddr>, State(state): State<AppState>) -> impl IntoResponse {
-        ws.on_upgrade(move |socket| Self::handle_socket(socket, address, State(state)))
+    async fn ws_handler(ws: WebSocketUpgrade, ConnectInfo(peer): ConnectInfo<Peer>, State(state): State<AppState>) -> impl IntoResponse {
+        ws.on_upgrade(move |socket| Self::handle_socket(socket, peer, State(state)))
     }
 
-    async fn handle_socket(mut sock: WebSocket, ip: SocketAddr, State(state): State<AppState>) {
+    async fn handle_socket(mut sock: WebSocket, ip: Peer, State(state): State<AppState>) {
         info!("Client connected from ip: {}", ip);
         use tokio::sync::Mutex;
         
 
         // get the User object from the initial handshake
-        let user: Result<User, ()> = {
-            let mut result: Result<User, ()> = Err(()); // default to error
+        let user: Result<(User, Scope, Option<String>), String> = {
+            let mut result: Result<(User, Scope, Option<String>), String> = Err("invalid token".to_string()); // default to error
 
-            // first message is assumed to be a jwt challenge
+            // first message is assumed to be a jwt (or a bot's API key) challenge
             let challenge = match sock.recv().await {
                 Some(v) => v,
                 None => return,
             };
             
-            // if it is a valid token return the token, otherwise break out of the socket.
-            if let Ok(message) = challenge {
-                if let Message::Text(token) = message {
-                    if let Ok(token) = validate_token(token.to_string()) {
-                        result = Ok(token);
-                    };
-
-                } 
-            } 
+            // if it is a valid token (of someone who isn't banned) return the user, otherwise
+            // break out of the socket.
+            if let Ok(Message::Text(token)) = challenge {
+                result = authenticate_scoped_token(&state, token.as_str()).await.map_err(|e| e.to_string());
+            }
             
             result
         };
         
         // finalize the user, otherwise send an error message and disconnect.
-        let _user = match user {
-            Ok(user) => user,
-            Err(_) => {
+        // the login session (see session.rs) isn't the resume session (see resume.rs)
+        let (user, scope, login_session) = match user {
+            Ok(authenticated) => authenticated,
+            Err(e) => {
                 let _ = sock.send(Message::Text(json!({
                     "error": true,
-                    "value": "invalid token"
+                    "value": e
                 }).to_string().into())).await;
 
                 let _ = sock.send(Message::Close(None)).await;
@@ -139,22 +237,37 @@ impl SocketServer {
             }
         };
 
-        let _ = sock.send(Message::Text("{\"error\": false, \"value\": \"welcome\"}".to_string().into())).await;
+        // hold the server open until this socket has been told about a shutdown
+        let _connection = state.shutdown.connection();
+        // so moderation can close it, see connections.rs
+        let mut registration = state.connections.register(&user.handle, login_session.as_deref());
+
+        // the resume token lets this client pick up where it left off if the socket drops
+        let resume_token = random_token(resume::RESUME_TOKEN_LENGTH);
+        let _ = sock.send(Message::Text(json!({
+            "error": false,
+            "value": "welcome",
+            "resume_token": resume_token
+        }).to_string().into())).await;
 
 
         // subscribe to the broadcast channel
         let rx = state.tx.subscribe();
 
-        // active channel filters messages server side
-        let active_channel = Arc::new(Mutex::new(UserActiveChannel::None));
+        let connection = ConnectionState::default();
         
         /// function to handle incoming messages from a websocket. See handle_sock_send() for the
         /// broadcasting to websocket
+        #[allow(clippy::too_many_arguments)]
         async fn handle_sock_recv(
             ws_rx: Arc<Mutex<SplitStream<WebSocket>>>,
             ws_tx: Arc<Mutex<SplitSink<WebSocket, Message>>>,
-            ip: &SocketAddr,
-            active_channel: Arc<Mutex<UserActiveChannel>>
+            ip: &Peer,
+            state: &AppState,
+            user: &User,
+            scope: &Scope,
+            connection: ConnectionState,
+            presence: Presence
         ) -> Result<(), Box<dyn Error>> {
             let mut stupid_message_counter: u8 = 0; // prevent useless message abuse
             
@@ -169,7 +282,11 @@ impl SocketServer {
                     Ok(m) => m,
                     Err(e) => {return Err(Box::new(e))},
                 };
-                match message {
+                let encoding = connection.capabilities.lock().await.encoding();
+
+                // every command is a string, either a text frame or a binary frame in the
+                // negotiated encoding
+                let t: String = match message {
                     Message::Close(_) => {
                         // don't send a close response because the client is already closed.
                         trace!("client sent close");
@@ -177,64 +294,223 @@ impl SocketServer {
                     },
                     Message::Ping(payload) => {
                         ws_tx.lock().await.send(Message::Pong(payload)).await?;
+                        continue;
+                    },
+                    Message::Text(t) => t.to_string(),
+                    Message::Binary(payload) if encoding.is_binary() => {
+                        // stringify the error right away, boxed errors aren't Send
+                        match encoding.decode_command(&payload).map_err(|e| e.to_string()) {
+                            Ok(t) => t,
+                            Err(e) => {
+                                let error_response = serde_json::json!({
+                                    "message_type": UpdateType::ERROR,
+                                    "error": true,
+                                    "content": format!("unable to decode command: {}", e),
+                                    "value": Option::<()>::None
+                                });
+                                send_event(&ws_tx, encoding, &error_response).await?;
+
+                                stupid_message_counter += 1;
+                                if stupid_message_counter > state.config.limits.max_unsupported_frames {
+                                    warn!("Undecodable data exceeded threshold from ip: {}", *ip);
+                                    return close_unsupported(&ws_tx).await;
+                                }
+                                continue;
+                            }
+                        }
                     },
-                    Message::Text(t) => {
-                        // any message from the client is expected to be to switch channels
-                        trace!("client switched channels");
+                    _ => {
+                        stupid_message_counter += 1;
+                        if stupid_message_counter > state.config.limits.max_unsupported_frames {
+                            warn!("Weird data exceeded threshold from ip: {}", *ip);
+                            return close_unsupported(&ws_tx).await;
+                        }
+                        continue;
+                    }
+                };
 
-                        // make sure the message isn't bigger than the max channel name length
-                        // (measured in bytes) [to prevent lag and dos]
-                        if t.len() > backend::MAX_CHANNEL_NAME_LENGTH_BYTES {
+                // capability negotiation, see capabilities.rs
+                if let Some(command) = CapCommand::parse(t.as_str()) {
+                    let response = match command {
+                        Ok(CapCommand::List) => serde_json::json!({
+                            "message_type": UpdateType::SYSTEM,
+                            "error": false,
+                            "content": "available capabilities",
+                            "value": {
+                                "available": Capability::ALL,
+                                "limits": ServerLimits::from(&state.config.limits)
+                            }
+                        }),
+                        Ok(CapCommand::Request(names)) => {
+                            let mut lock = connection.capabilities.lock().await;
+                            lock.request(names.into_iter());
+                            trace!("client negotiated capabilities: {:?}", lock.enabled());
+
+                            serde_json::json!({
+                                "message_type": UpdateType::SYSTEM,
+                                "error": false,
+                                "content": "capabilities enabled",
+                                "value": {
+                                    "enabled": lock.enabled(),
+                                    "limits": ServerLimits::from(&state.config.limits)
+                                }
+                            })
+                        },
+                        Err(e) => serde_json::json!({
+                            "message_type": UpdateType::ERROR,
+                            "error": true,
+                            "content": e,
+                            "value": Option::<()>::None
+                        }),
+                    };
+                    // the acknowledgement uses the encoding the request arrived in, everything
+                    // after it uses the new one.
+                    send_event(&ws_tx, encoding, &response).await?;
+
+                    continue;
+                }
+
+                // picking up a dropped session, see resume.rs
+                if let Some(command) = resume::parse_resume(t.as_str()) {
+                    let session = match command {
+                        Ok((token, _)) => state.sessions.take(token, &user.handle)
+                            .ok_or("unknown or expired resume token"),
+                        Err(e) => Err(e),
+                    };
+                    // the session may have been parked by a connection with a wider scope
+                    let session = session.and_then(|session| match session.active_channel.readable(scope) {
+                        true => Ok(session),
+                        false => Err("this key can't read the resumed channel"),
+                    });
+                    let (session, last_seen) = match (session, command) {
+                        (Ok(session), Ok((_, last_seen))) => (session, last_seen),
+                        (Err(e), _) | (_, Err(e)) => {
                             let error_response = serde_json::json!({
+                                "message_type": UpdateType::ERROR,
                                 "error": true,
-                                "content": format!(
-                                    "Channel name too long in bytes. Max is {}", 
-                                    backend::MAX_CHANNEL_NAME_LENGTH_BYTES
-                                    ),
-                                "value": Option::<UserActiveChannel>::None
+                                "content": e,
+                                "value": Option::<()>::None
                             });
-                            ws_tx.lock().await
-                                .send(error_response.to_string().into())
-                                .await?;
-
+                            send_event(&ws_tx, encoding, &error_response).await?;
                             continue;
                         }
-                        
-                        // change the channel based on input
-                        let mut lock = active_channel.lock().await;
-                        *lock = match t.as_str() {
-                            "ALL" => { UserActiveChannel::All },
-                            "NONE" => { UserActiveChannel::None },
-                            _ => {
-                                UserActiveChannel::String(t.to_string())
-                            }
-                        };
+                    };
+                    trace!("client resumed a session");
 
-                        // send a response to the user
-                        let success_response = serde_json::json!({
-                            "message_type": UpdateType::SYSTEM,
-                            "error": false,
-                            "content": "successfully changed channel",
-                            "value": Some(UserActiveChannel::String(t.to_string()))
-                        });
-                        ws_tx.lock().await
-                                .send(success_response.to_string().into())
-                                .await?;
-                    },
-                    _ => {
-                        stupid_message_counter += 1;
-                        if stupid_message_counter > MAX_STUPID_MESSAGE {
-                            warn!("Weird data exceeded threshold from ip: {}", *ip);
-                            ws_tx.lock().await.send(Message::Close(Some(CloseFrame {
-                                code: UNSUPPORTED,
-                                reason: "This server supports pings and text (utf-8 formatted).".into()
-                            }))).await?;
+                    let (encoding, message_ids) = {
+                        let mut lock = connection.capabilities.lock().await;
+                        *lock = session.capabilities;
+                        (lock.encoding(), lock.has(Capability::MessageIds))
+                    };
 
-                            return Ok(());
+                    // hold the channel lock while replaying so the live feed waits for us
+                    let mut channel_lock = connection.active_channel.lock().await;
+                    *channel_lock = session.active_channel;
+                    presence.switch(channel_lock.name());
+                    let (missed, complete) = state.history.since(last_seen, |m| channel_lock.wants(&m.channel));
+
+                    let success_response = serde_json::json!({
+                        "message_type": UpdateType::SYSTEM,
+                        "error": false,
+                        "content": "session resumed",
+                        "value": {
+                            "channel": *channel_lock,
+                            "replayed": missed.len(),
+                            "complete": complete
                         }
+                    });
+                    send_event(&ws_tx, encoding, &success_response).await?;
+
+                    for m in missed {
+                        connection.replayed_until.fetch_max(m.id, Ordering::Relaxed);
+                        let update = SocketMessage::message(m, message_ids);
+                        send_event(&ws_tx, encoding, &update).await?;
                     }
 
+                    continue;
+                }
+
+                // kicking, muting and banning, see moderation.rs
+                if let Some(command) = moderation::parse_command(t.as_str()) {
+                    let result = match command {
+                        // API keys only read and post
+                        Ok(_) if !scope.is_full() => Err("API keys can't moderate".to_string()),
+                        Ok((handle, action)) => {
+                            let channel = connection.active_channel.lock().await.name().map(str::to_string);
+                            moderation::moderate(state, user, handle, action, channel.as_deref()).await.map_err(|e| e.to_string())
+                        },
+                        Err(e) => Err(e.to_string()),
+                    };
+                    let response = match result {
+                        Ok(value) => serde_json::json!({
+                            "message_type": UpdateType::SYSTEM,
+                            "error": false,
+                            "content": "done",
+                            "value": value
+                        }),
+                        Err(e) => serde_json::json!({
+                            "message_type": UpdateType::ERROR,
+                            "error": true,
+                            "content": e,
+                            "value": Option::<()>::None
+                        }),
+                    };
+                    send_event(&ws_tx, encoding, &response).await?;
+
+                    continue;
+                }
+
+                // any other message from the client is expected to be to switch channels
+                trace!("client switched channels");
+
+                // make sure the message isn't bigger than the max channel name length
+                // (measured in bytes) [to prevent lag and dos]
+                let max_length = state.config.limits.max_channel_name_length_bytes;
+                if t.len() > max_length {
+                    let error_response = serde_json::json!({
+                        "error": true,
+                        "content": format!(
+                            "Channel name too long in bytes. Max is {}", 
+                            max_length
+                            ),
+                        "value": Option::<UserActiveChannel>::None
+                    });
+                    send_event(&ws_tx, encoding, &error_response).await?;
+
+                    continue;
                 }
+                
+                // change the channel based on input
+                let channel = match t.as_str() {
+                    "ALL" => { UserActiveChannel::All },
+                    "NONE" => { UserActiveChannel::None },
+                    _ => {
+                        UserActiveChannel::String(t.to_string())
+                    }
+                };
+                if !channel.readable(scope) {
+                    let error_response = serde_json::json!({
+                        "message_type": UpdateType::ERROR,
+                        "error": true,
+                        "content": format!("this key can't read {}", t),
+                        "value": Option::<UserActiveChannel>::None
+                    });
+                    send_event(&ws_tx, encoding, &error_response).await?;
+
+                    continue;
+                }
+                let mut lock = connection.active_channel.lock().await;
+                *lock = channel;
+                presence.switch(lock.name());
+
+                // send a response to the user
+                let success_response = serde_json::json!({
+                    "message_type": UpdateType::SYSTEM,
+                    "error": false,
+                    "content": "successfully changed channel",
+                    "value": Some(UserActiveChannel::String(t.to_string()))
+                });
+                send_event(&ws_tx, encoding, &success_response).await?;
             }
         }
         
@@ -242,7 +518,7 @@ impl SocketServer {
         async fn handle_sock_send(
             ws_tx: Arc<Mutex<SplitSink<WebSocket, Message>>>,
             mut rx: Receiver<ChannelMessage>,
-            active_channel: Arc<Mutex<UserActiveChannel>>
+            connection: ConnectionState
         ) -> Result<(), Box<dyn std::error::Error>> {
             loop {
                 // wait for new channel messages (NOT SOCKET ONES, see handle_sock_recv() above for
@@ -252,21 +528,18 @@ impl SocketServer {
                     Ok(m) => {
                         // see if the message is from a relevant channel, if it isn't: continue to
                         // the next iteration of the loop
-                        match &*active_channel.lock().await {
-                            UserActiveChannel::String(target) => {
-                                if *target != m.channel { continue; }
-                            },
-                            UserActiveChannel::None => { continue;},
-                            UserActiveChannel::All => {/* do nothing to filter */},
-                        }
+                        if !connection.active_channel.lock().await.wants(&m.channel) { continue; }
+
+                        // already sent while replaying a resumed session
+                        if m.id <= connection.replayed_until.load(Ordering::Relaxed) { continue; }
+
                         // if the message is relevant send it to the user
-                        let update = SocketMessage {
-                            message_type: UpdateType::MESSAGE,
-                            content: m.content,
-                            sender: Some(m.sender)
+                        let (encoding, message_ids) = {
+                            let lock = connection.capabilities.lock().await;
+                            (lock.encoding(), lock.has(Capability::MessageIds))
                         };
-                        let update = serde_json::to_string(&update)?;
-                        ws_tx.lock().await.send(Message::Text(update.into())).await?;
+                        let update = SocketMessage::message(m, message_ids);
+                        send_event(&ws_tx, encoding, &update).await?;
                     },
                     Err(_) => {
                         warn!("caught a channel recv error. Closing connection to reduce load.");
@@ -283,18 +556,37 @@ impl SocketServer {
         let ws_tx = Arc::new(Mutex::new(ws_tx));
 
         // handle messages from the socket and updates from the broadcast group
-        tokio::select! {
-            res = handle_sock_recv(ws_rx.clone(), ws_tx.clone(), &ip, active_channel.clone()) => {
+        let closed_by_server = tokio::select! {
+            res = handle_sock_recv(ws_rx.clone(), ws_tx.clone(), &ip, &state, &user, &scope, connection.clone(), registration.presence()) => {
                 if let Err(e) = res {
                     warn!("{:?}", e);
                 }
+                None
             },
-            res = handle_sock_send(ws_tx.clone(), rx, active_channel.clone()) => {
+            res = handle_sock_send(ws_tx.clone(), rx, connection.clone()) => {
                 if let Err(e) = res {
                     warn!("{:?}", e)
                 }
+                None
+            },
+            _ = state.shutdown.wait() => Some((SHUTDOWN_REASON.to_string(), RESTART)),
+            reason = registration.disconnected() => Some((reason, POLICY))
+        };
+        // outside the select, the other branches' (non Send) errors can't be held across an await
+        if let Some((reason, code)) = &closed_by_server {
+            let encoding = connection.capabilities.lock().await.encoding();
+            if let Err(e) = close_with_notice(&ws_tx, encoding, reason, *code).await.map_err(|e| e.to_string()) {
+                warn!("{:?}", e)
             }
         }
+        // park the session in case the client comes back, unless moderation threw it out
+        if !matches!(closed_by_server, Some((_, POLICY))) {
+            state.sessions.detach(resume_token, DetachedSession::new(
+                user.handle,
+                connection.active_channel.lock().await.clone(),
+                connection.capabilities.lock().await.clone()
+            ));
+        }
         info!("client disconnected (ip: {})", ip);
     }
 
@@ -0,0 +1,546 @@
+//! Runtime configuration
+//!
+//! Settings come from (in order of precedence) environment variables, a TOML file, and the
+//! defaults below. The file is `trcd.toml` in the working directory unless `--config <path>` or
+//! `TRCD_CONFIG` says otherwise, and it's fine for it not to exist. See `trcd.example.toml` for
+//! every option.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::authentication::oidc::{OIDC_CA_FILE, OIDC_CLAIM, OIDC_SCOPES};
+use crate::authentication::passwords::{PASSWORD_ITERATIONS, PASSWORD_MEMORY_KIB, PASSWORD_PARALLELISM};
+use crate::authentication::signing_keys::SigningAlgorithm;
+use crate::authentication::user::UserPermissions;
+use crate::authentication::throttle::{LOGIN_FREE_ATTEMPTS, LOGIN_LOCKOUT_MINUTES};
+use crate::authentication::token::{JWT_LIFE_MINUTES, REFRESH_LIFE_DAYS};
+use crate::authentication::users::USER_CACHE_SECONDS;
+use crate::backend::MAX_CHANNEL_NAME_LENGTH_BYTES;
+use crate::backend::history::HISTORY_CAPACITY;
+use crate::backend::http_client::is_loopback;
+use crate::backend::resume::RESUME_WINDOW;
+use crate::backend::socket_server::MAX_STUPID_MESSAGE;
+use crate::database::sqlite::db_sqlite::DB_DEFAULT_URL;
+
+pub const DEFAULT_CONFIG_PATH: &str = "trcd.toml";
+pub const CONFIG_PATH_ENV: &str = "TRCD_CONFIG";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(&'static str, String), // variable name, what's wrong with it
+    Invalid(&'static str, String), // option name, what's wrong with it
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "unable to read config file {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config file {}: {}", path.display(), e),
+            ConfigError::Env(name, e) => write!(f, "invalid environment variable {}: {}", name, e),
+            ConfigError::Invalid(name, e) => write!(f, "invalid value for `{}`: {}", name, e),
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub database: DatabaseConfig,
+    pub auth: AuthConfig,
+    pub oidc: OidcConfig,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// addresses the REST api and websockets are served on. Use `[::]:3000` for IPv6, or `[]` to
+    /// only serve on `unix_socket`.
+    pub bind: Vec<SocketAddr>,
+    /// also serve the REST api and websockets on this unix socket, e.g. for nginx on the same host
+    pub unix_socket: Option<PathBuf>,
+    /// permissions of `unix_socket`, write it in octal (`0o660`)
+    pub unix_socket_mode: u32,
+    /// the line protocol (see line_server.rs) is off unless this is set
+    pub line_bind: Option<SocketAddr>,
+    /// the IRC gateway (see irc_gateway.rs) is off unless this is set
+    pub irc_bind: Option<SocketAddr>,
+    /// how long connections get to close after SIGTERM/SIGINT before the server exits anyway
+    pub shutdown_timeout_seconds: u64,
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: vec![SocketAddr::from(([0, 0, 0, 0], 3000))],
+            unix_socket: None,
+            // owner and group, so a proxy can be let in by adding it to the group
+            unix_socket_mode: 0o660,
+            line_bind: None,
+            irc_bind: None,
+            // docker kills containers 10 seconds after SIGTERM
+            shutdown_timeout_seconds: 8,
+        }
+    }
+}
+
+/// TLS for every listener (REST, websockets, line protocol, IRC). Off unless both `cert` and `key`
+/// are set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first (e.g. Let's Encrypt's fullchain.pem)
+    pub cert: Option<PathBuf>,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1)
+    pub key: Option<PathBuf>,
+    /// how often the files are checked for changes, so renewed certificates are picked up
+    pub reload_interval_seconds: u64,
+}
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            cert: None,
+            key: None,
+            reload_interval_seconds: 60,
+        }
+    }
+}
+impl ServerConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_seconds)
+    }
+}
+
+impl TlsConfig {
+    pub fn reload_interval(&self) -> Duration {
+        Duration::from_secs(self.reload_interval_seconds)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    pub idle_timeout_seconds: u64,
+    pub acquire_timeout_seconds: u64,
+}
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: DB_DEFAULT_URL.to_string(),
+            max_connections: 20,
+            idle_timeout_seconds: 60,
+            acquire_timeout_seconds: 5,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// falls back to the `JWT_SECRET` env variable
+    pub jwt_secret: Option<String>,
+    /// EdDSA/ES256 keys, used instead of `jwt_secret` when set (see signing_keys.rs)
+    pub signing_keys: Vec<SigningKeyConfig>,
+    pub token_lifetime_minutes: i64,
+    /// how long a refresh token lasts, which is how long someone stays logged in without using it
+    pub refresh_token_lifetime_days: i64,
+    /// how long a user looked up for a token is remembered, changes made outside the server (e.g.
+    /// `trcd reset-password`) take this long to apply. 0 looks the user up on every request.
+    pub user_cache_seconds: u64,
+    /// failed logins (per IP and per account) before each further attempt has to wait, see
+    /// throttle.rs
+    pub login_free_attempts: u32,
+    /// longest wait between attempts, and how long a locked out IP or account stays locked out
+    pub login_lockout_minutes: u64,
+    /// users of this rank and up have to set up two-factor authentication before they can use it,
+    /// see two_factor.rs
+    pub require_two_factor: Option<UserPermissions>,
+    /// argon2id cost of new password hashes, see passwords.rs. Changing them rehashes each
+    /// password on its next login.
+    pub password_memory_kib: u32,
+    pub password_iterations: u32,
+    pub password_parallelism: u32,
+}
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            jwt_secret: None,
+            signing_keys: Vec::new(),
+            token_lifetime_minutes: JWT_LIFE_MINUTES,
+            refresh_token_lifetime_days: REFRESH_LIFE_DAYS,
+            user_cache_seconds: USER_CACHE_SECONDS,
+            login_free_attempts: LOGIN_FREE_ATTEMPTS,
+            login_lockout_minutes: LOGIN_LOCKOUT_MINUTES,
+            require_two_factor: None,
+            password_memory_kib: PASSWORD_MEMORY_KIB,
+            password_iterations: PASSWORD_ITERATIONS,
+            password_parallelism: PASSWORD_PARALLELISM,
+        }
+    }
+}
+impl AuthConfig {
+    pub fn user_cache(&self) -> Duration {
+        Duration::from_secs(self.user_cache_seconds)
+    }
+}
+impl fmt::Debug for AuthConfig {
+    // never print the secret
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("jwt_secret", &self.jwt_secret.as_ref().map(|_| "<redacted>"))
+            .field("signing_keys", &self.signing_keys)
+            .field("token_lifetime_minutes", &self.token_lifetime_minutes)
+            .field("refresh_token_lifetime_days", &self.refresh_token_lifetime_days)
+            .field("user_cache_seconds", &self.user_cache_seconds)
+            .field("login_free_attempts", &self.login_free_attempts)
+            .field("login_lockout_minutes", &self.login_lockout_minutes)
+            .field("require_two_factor", &self.require_two_factor)
+            .field("password_memory_kib", &self.password_memory_kib)
+            .field("password_iterations", &self.password_iterations)
+            .field("password_parallelism", &self.password_parallelism)
+            .finish()
+    }
+}
+
+/// a key tokens are signed or verified with
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningKeyConfig {
+    /// the `kid` of the tokens it signs
+    pub id: String,
+    pub algorithm: SigningAlgorithm,
+    /// PEM PKCS#8 private key to sign with, or a public key to only verify with
+    pub file: PathBuf,
+}
+
+/// logging in through an OpenID Connect issuer (see oidc.rs). Off unless `issuer` is set.
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    /// e.g. `https://sso.example.com/realms/staff`, exactly as the issuer names itself
+    pub issuer: Option<String>,
+    pub client_id: String,
+    /// for confidential clients, public ones only have PKCE
+    pub client_secret: Option<String>,
+    /// where the issuer sends the browser back to, as registered with the issuer
+    pub redirect_uri: String,
+    /// space separated, `openid` is required
+    pub scopes: String,
+    /// the claim whose value is a new user's handle. It decides which handle someone gets (with
+    /// `provision`) or which account they may take (with `link_existing`), so the issuer has to
+    /// guarantee it's unique and that users can't set it themselves. `preferred_username` is
+    /// neither by the spec (OpenID Connect Core 1.0, 5.7), only trust it if the issuer's admins
+    /// control it.
+    pub claim: String,
+    /// give users without an account one, instead of turning them away
+    pub provision: bool,
+    /// tie someone to the existing account their claim names the first time they log in, instead
+    /// of asking them to log in to it and link it at `/api/me/oidc`. Never for moderators and
+    /// admins. Only safe if the claim is trustworthy (see `claim`).
+    pub link_existing: bool,
+    /// CA certificates (PEM) trusted for https to the issuer
+    pub ca_file: PathBuf,
+}
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            issuer: None,
+            client_id: String::new(),
+            client_secret: None,
+            redirect_uri: String::new(),
+            scopes: OIDC_SCOPES.to_string(),
+            claim: OIDC_CLAIM.to_string(),
+            provision: false,
+            link_existing: false,
+            ca_file: PathBuf::from(OIDC_CA_FILE),
+        }
+    }
+}
+impl fmt::Debug for OidcConfig {
+    // never print the secret
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OidcConfig")
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &self.client_secret.as_ref().map(|_| "<redacted>"))
+            .field("redirect_uri", &self.redirect_uri)
+            .field("scopes", &self.scopes)
+            .field("claim", &self.claim)
+            .field("provision", &self.provision)
+            .field("link_existing", &self.link_existing)
+            .field("ca_file", &self.ca_file)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// unsupported frames/unknown commands a connection may send before being closed
+    pub max_unsupported_frames: u8,
+    pub max_channel_name_length_bytes: usize,
+    /// messages waiting on the broadcast channel before slow clients get dropped
+    pub broadcast_capacity: usize,
+    /// messages remembered for resuming sessions and SSE `Last-Event-ID`
+    pub history_capacity: usize,
+    pub resume_window_seconds: u64,
+}
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_unsupported_frames: MAX_STUPID_MESSAGE,
+            max_channel_name_length_bytes: MAX_CHANNEL_NAME_LENGTH_BYTES,
+            broadcast_capacity: 1024,
+            history_capacity: HISTORY_CAPACITY,
+            resume_window_seconds: RESUME_WINDOW.as_secs(),
+        }
+    }
+}
+impl LimitsConfig {
+    pub fn resume_window(&self) -> Duration {
+        Duration::from_secs(self.resume_window_seconds)
+    }
+}
+
+impl Config {
+    /// load the config file (if there is one), apply env overrides and validate the result
+    pub fn load(path: Option<PathBuf>) -> Result<Self, ConfigError> {
+        let explicit = path.is_some() || std::env::var_os(CONFIG_PATH_ENV).is_some();
+        let path = path
+            .or_else(|| std::env::var_os(CONFIG_PATH_ENV).map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&contents).map_err(|e| ConfigError::Parse(path.clone(), e))?,
+            // only the default file is allowed to be missing
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => Config::default(),
+            Err(e) => return Err(ConfigError::Read(path, e)),
+        };
+
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    /// override settings from `TRCD_<SECTION>_<OPTION>` env variables (and `JWT_SECRET`)
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        fn parse<T: FromStr>(name: &'static str, value: &str) -> Result<T, ConfigError> where T::Err: fmt::Display {
+            value.trim().parse().map_err(|e: T::Err| ConfigError::Env(name, e.to_string()))
+        }
+        macro_rules! env_override {
+            ($name:literal, $field:expr) => {
+                if let Some(value) = var($name) { $field = parse($name, &value)?; }
+            };
+            ($name:literal, optional $field:expr) => {
+                if let Some(value) = var($name) {
+                    $field = if value.trim().is_empty() { None } else { Some(parse($name, &value)?) };
+                }
+            };
+        }
+
+        if let Some(value) = var("TRCD_SERVER_BIND") {
+            // an empty value turns TCP off, for serving on the unix socket only
+            self.server.bind = value.split(',')
+                .filter(|address| !address.trim().is_empty())
+                .map(|address| parse("TRCD_SERVER_BIND", address))
+                .collect::<Result<_, _>>()?;
+        }
+        env_override!("TRCD_SERVER_UNIX_SOCKET", optional self.server.unix_socket);
+        if let Some(value) = var("TRCD_SERVER_UNIX_SOCKET_MODE") {
+            // octal like chmod, with or without the 0o
+            let digits = value.trim().trim_start_matches("0o");
+            self.server.unix_socket_mode = u32::from_str_radix(digits, 8)
+                .map_err(|e| ConfigError::Env("TRCD_SERVER_UNIX_SOCKET_MODE", format!("expected an octal mode like 660: {}", e)))?;
+        }
+        env_override!("TRCD_SERVER_LINE_BIND", optional self.server.line_bind);
+        env_override!("TRCD_SERVER_IRC_BIND", optional self.server.irc_bind);
+        env_override!("TRCD_SERVER_SHUTDOWN_TIMEOUT_SECONDS", self.server.shutdown_timeout_seconds);
+
+        env_override!("TRCD_TLS_CERT", optional self.tls.cert);
+        env_override!("TRCD_TLS_KEY", optional self.tls.key);
+        env_override!("TRCD_TLS_RELOAD_INTERVAL_SECONDS", self.tls.reload_interval_seconds);
+
+        env_override!("TRCD_DATABASE_URL", self.database.url);
+        env_override!("TRCD_DATABASE_MAX_CONNECTIONS", self.database.max_connections);
+        env_override!("TRCD_DATABASE_IDLE_TIMEOUT_SECONDS", self.database.idle_timeout_seconds);
+        env_override!("TRCD_DATABASE_ACQUIRE_TIMEOUT_SECONDS", self.database.acquire_timeout_seconds);
+
+        env_override!("JWT_SECRET", optional self.auth.jwt_secret);
+        env_override!("TRCD_AUTH_JWT_SECRET", optional self.auth.jwt_secret);
+        env_override!("TRCD_AUTH_TOKEN_LIFETIME_MINUTES", self.auth.token_lifetime_minutes);
+        env_override!("TRCD_AUTH_REFRESH_TOKEN_LIFETIME_DAYS", self.auth.refresh_token_lifetime_days);
+        env_override!("TRCD_AUTH_USER_CACHE_SECONDS", self.auth.user_cache_seconds);
+        env_override!("TRCD_AUTH_LOGIN_FREE_ATTEMPTS", self.auth.login_free_attempts);
+        env_override!("TRCD_AUTH_LOGIN_LOCKOUT_MINUTES", self.auth.login_lockout_minutes);
+        env_override!("TRCD_AUTH_REQUIRE_TWO_FACTOR", optional self.auth.require_two_factor);
+        env_override!("TRCD_AUTH_PASSWORD_MEMORY_KIB", self.auth.password_memory_kib);
+        env_override!("TRCD_AUTH_PASSWORD_ITERATIONS", self.auth.password_iterations);
+        env_override!("TRCD_AUTH_PASSWORD_PARALLELISM", self.auth.password_parallelism);
+
+        env_override!("TRCD_OIDC_ISSUER", optional self.oidc.issuer);
+        env_override!("TRCD_OIDC_CLIENT_ID", self.oidc.client_id);
+        env_override!("TRCD_OIDC_CLIENT_SECRET", optional self.oidc.client_secret);
+        env_override!("TRCD_OIDC_REDIRECT_URI", self.oidc.redirect_uri);
+        env_override!("TRCD_OIDC_SCOPES", self.oidc.scopes);
+        env_override!("TRCD_OIDC_CLAIM", self.oidc.claim);
+        env_override!("TRCD_OIDC_PROVISION", self.oidc.provision);
+        env_override!("TRCD_OIDC_LINK_EXISTING", self.oidc.link_existing);
+        env_override!("TRCD_OIDC_CA_FILE", self.oidc.ca_file);
+
+        env_override!("TRCD_LIMITS_MAX_UNSUPPORTED_FRAMES", self.limits.max_unsupported_frames);
+        env_override!("TRCD_LIMITS_MAX_CHANNEL_NAME_LENGTH_BYTES", self.limits.max_channel_name_length_bytes);
+        env_override!("TRCD_LIMITS_BROADCAST_CAPACITY", self.limits.broadcast_capacity);
+        env_override!("TRCD_LIMITS_HISTORY_CAPACITY", self.limits.history_capacity);
+        env_override!("TRCD_LIMITS_RESUME_WINDOW_SECONDS", self.limits.resume_window_seconds);
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        fn check(ok: bool, name: &'static str, problem: &str) -> Result<(), ConfigError> {
+            if ok { Ok(()) } else { Err(ConfigError::Invalid(name, problem.to_string())) }
+        }
+
+        check(!self.server.bind.is_empty() || self.server.unix_socket.is_some(), "server.bind", "at least one address is required unless `unix_socket` is set")?;
+        check(cfg!(unix) || self.server.unix_socket.is_none(), "server.unix_socket", "unix sockets aren't supported on this platform")?;
+        // 660 in decimal is 0o1224, a mistake that would give out odd permissions without complaint
+        check(self.server.unix_socket_mode <= 0o777, "server.unix_socket_mode", "must be at most 0o777, write it in octal (`0o660`)")?;
+        check(self.tls.cert.is_some() == self.tls.key.is_some(), "tls", "`cert` and `key` have to be set together")?;
+        check(self.tls.reload_interval_seconds > 0, "tls.reload_interval_seconds", "must be at least 1")?;
+        check(!self.database.url.trim().is_empty(), "database.url", "cannot be empty")?;
+        check(self.database.max_connections > 0, "database.max_connections", "must be at least 1")?;
+        check(self.database.acquire_timeout_seconds > 0, "database.acquire_timeout_seconds", "must be at least 1")?;
+        check(self.auth.jwt_secret.as_ref().is_none_or(|s| !s.is_empty()), "auth.jwt_secret", "cannot be empty")?;
+        check(self.auth.signing_keys.iter().all(|key| !key.id.is_empty()), "auth.signing_keys", "every key needs an `id`")?;
+        check(
+            self.auth.signing_keys.iter().enumerate().all(|(i, key)| self.auth.signing_keys[..i].iter().all(|other| other.id != key.id)),
+            "auth.signing_keys", "key ids have to be unique"
+        )?;
+        // anything longer than a year is almost certainly a typo
+        check((1..=525_600).contains(&self.auth.token_lifetime_minutes), "auth.token_lifetime_minutes", "must be between 1 and 525600 (a year)")?;
+        check((1..=365).contains(&self.auth.refresh_token_lifetime_days), "auth.refresh_token_lifetime_days", "must be between 1 and 365")?;
+        check(self.auth.user_cache_seconds <= 3600, "auth.user_cache_seconds", "must be at most 3600 (an hour)")?;
+        check((1..=100).contains(&self.auth.login_free_attempts), "auth.login_free_attempts", "must be between 1 and 100")?;
+        check((1..=1440).contains(&self.auth.login_lockout_minutes), "auth.login_lockout_minutes", "must be between 1 and 1440 (a day)")?;
+        // it's for staff accounts, everyone else opts in
+        check(self.auth.require_two_factor != Some(UserPermissions::User), "auth.require_two_factor", "can only be Moderator or Admin")?;
+        // every login allocates the memory, so keep it to something a server has to spare
+        check((1..=16).contains(&self.auth.password_parallelism), "auth.password_parallelism", "must be between 1 and 16")?;
+        check(
+            (8 * self.auth.password_parallelism..=1_048_576).contains(&self.auth.password_memory_kib),
+            "auth.password_memory_kib", "must be between 8 times password_parallelism and 1048576 (1 GiB)"
+        )?;
+        check((1..=20).contains(&self.auth.password_iterations), "auth.password_iterations", "must be between 1 and 20")?;
+        if let Some(issuer) = &self.oidc.issuer {
+            check(url::Url::parse(issuer).is_ok_and(|url| match url.scheme() {
+                "https" => true,
+                "http" => url.host().is_some_and(|host| is_loopback(&host)),
+                _ => false,
+            }), "oidc.issuer", "must be an https:// URL (or http:// on localhost)")?;
+            check(!self.oidc.client_id.is_empty(), "oidc.client_id", "is required with an issuer")?;
+            check(url::Url::parse(&self.oidc.redirect_uri).is_ok(), "oidc.redirect_uri", "is required with an issuer, as registered with it")?;
+            check(self.oidc.scopes.split_whitespace().any(|scope| scope == "openid"), "oidc.scopes", "must include `openid`")?;
+            check(!self.oidc.claim.is_empty(), "oidc.claim", "cannot be empty")?;
+        }
+        check(self.limits.max_channel_name_length_bytes > 0, "limits.max_channel_name_length_bytes", "must be at least 1")?;
+        check(self.limits.broadcast_capacity > 0, "limits.broadcast_capacity", "must be at least 1")?;
+        check(self.limits.history_capacity > 0, "limits.history_capacity", "must be at least 1")?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_config_parse_and_override() {
+    let mut config = Config::parse("
+        [server]
+        bind = [\"[::]:4000\"]
+        irc_bind = \"127.0.0.1:6667\"
+
+        [limits]
+        history_capacity = 10
+    ").expect("valid config should parse");
+
+    assert_eq!(config.server.bind, vec!["[::]:4000".parse().unwrap()], "IPv6 addresses should be accepted");
+    assert_eq!(config.limits.history_capacity, 10);
+    assert_eq!(config.database.url, DB_DEFAULT_URL, "missing options should use the defaults");
+
+    config.apply_env(|name| match name {
+        "TRCD_SERVER_BIND" => Some("0.0.0.0:1,[::1]:2".to_string()),
+        "TRCD_SERVER_IRC_BIND" => Some("".to_string()),
+        "TRCD_SERVER_UNIX_SOCKET_MODE" => Some("0o600".to_string()),
+        _ => None,
+    }).unwrap();
+    assert_eq!(config.server.bind.len(), 2);
+    assert_eq!(config.server.irc_bind, None, "an empty env variable should turn an optional listener off");
+    assert_eq!(config.server.unix_socket_mode, 0o600);
+
+    config.apply_env(|name| match name {
+        "TRCD_SERVER_BIND" => Some("".to_string()),
+        "TRCD_SERVER_UNIX_SOCKET_MODE" => Some("640".to_string()),
+        _ => None,
+    }).unwrap();
+    assert!(config.server.bind.is_empty(), "an empty TRCD_SERVER_BIND should turn TCP off");
+    assert_eq!(config.server.unix_socket_mode, 0o640, "modes should be read as octal without the 0o too");
+
+    assert!(Config::parse("[server]\nprot = 1").is_err(), "typos should be rejected, not ignored");
+}
+
+#[test]
+fn test_config_validation() {
+    let mut config = Config::default();
+    assert!(config.validate().is_ok());
+
+    let example = Config::parse(include_str!("../trcd.example.toml")).expect("the example config should parse");
+    assert_eq!(format!("{:?}", example), format!("{:?}", Config::default()), "the example config should show the defaults");
+
+    config.tls.cert = Some(PathBuf::from("cert.pem"));
+    assert!(matches!(config.validate(), Err(ConfigError::Invalid("tls", _))), "a certificate without a key should be rejected");
+    config.tls.key = Some(PathBuf::from("key.pem"));
+    assert!(config.validate().is_ok());
+
+    config.server.bind.clear();
+    assert!(matches!(config.validate(), Err(ConfigError::Invalid("server.bind", _))), "there should be something to listen on");
+    config.server.unix_socket = Some(PathBuf::from("trcd.sock"));
+    assert!(config.validate().is_ok());
+    config.server.unix_socket_mode = 660;
+    assert!(matches!(config.validate(), Err(ConfigError::Invalid("server.unix_socket_mode", _))), "decimal modes should be caught");
+    config.server.unix_socket_mode = 0o660;
+
+    let duplicate = SigningKeyConfig { id: "2026".to_string(), algorithm: SigningAlgorithm::EdDSA, file: PathBuf::from("key.pem") };
+    config.auth.signing_keys = vec![duplicate.clone(), duplicate];
+    assert!(matches!(config.validate(), Err(ConfigError::Invalid("auth.signing_keys", _))), "a kid should name one key");
+    config.auth.signing_keys.pop();
+    assert!(config.validate().is_ok());
+
+    config.auth.token_lifetime_minutes = 0;
+    assert!(matches!(config.validate(), Err(ConfigError::Invalid("auth.token_lifetime_minutes", _))));
+    config.auth.token_lifetime_minutes = 15;
+
+    config.oidc.issuer = Some("http://sso.example".to_string());
+    assert!(matches!(config.validate(), Err(ConfigError::Invalid("oidc.issuer", _))), "plain http is only for issuers on localhost");
+    config.oidc.issuer = Some("http://127.0.0.1:8080".to_string());
+    assert!(matches!(config.validate(), Err(ConfigError::Invalid("oidc.client_id", _))));
+    config.oidc.issuer = Some("https://sso.example".to_string());
+    config.oidc.client_id = "trcd".to_string();
+    config.oidc.redirect_uri = "https://chat.example/api/login/oidc/callback".to_string();
+    config.oidc.scopes = "profile email".to_string();
+    assert!(matches!(config.validate(), Err(ConfigError::Invalid("oidc.scopes", _))), "ID tokens need the openid scope");
+
+    let result = Config::default().apply_env(|name| (name == "TRCD_DATABASE_MAX_CONNECTIONS").then(|| "lots".to_string()));
+    assert!(matches!(result, Err(ConfigError::Env("TRCD_DATABASE_MAX_CONNECTIONS", _))));
+}
@@ -1,21 +1,285 @@
 //! Traits and template for any database
 
+use serde::Serialize;
+
+use crate::authentication::api_keys::Scope;
+use crate::authentication::random::random_token;
 use crate::authentication::user::User;
 
+const INVITE_CODE_LENGTH: usize = 24;
+/// how long invites last unless the admin says otherwise
+pub const INVITE_LIFETIME_MINUTES: i64 = 7 * 24 * 60;
+/// longest lifetime an invite can be given, past it the expiry date would overflow
+pub const MAX_INVITE_LIFETIME_MINUTES: i64 = 10 * 365 * 24 * 60;
+
 /// WARNING: this struct contains secure fields. Don't use in insecure contexts
 pub struct UserDBEntry {
     pub password_hash: String,
     pub username: String,
     pub inner_user: User,
+    pub token_version: u32, // tokens carry this, bumping it invalidates every token of the user
+}
+
+/// an invite code that lets someone register through `/api/register`
+#[derive(Debug, Clone, Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub created_by: String, // handle of the admin that minted it
+    pub max_uses: Option<u32>, // None for unlimited
+    pub uses: u32,
+    pub expires_at: Option<i64>, // unix timestamp, None for never
+    pub revoked: bool,
+}
+
+impl Invite {
+    /// a fresh invite. `max_uses` of None never runs out, `lifetime_minutes` of None never expires.
+    /// `lifetime_minutes` mustn't be over MAX_INVITE_LIFETIME_MINUTES.
+    pub fn new(created_by: &str, max_uses: Option<u32>, lifetime_minutes: Option<i64>) -> Self {
+        Invite {
+            code: random_token(INVITE_CODE_LENGTH),
+            created_by: created_by.to_string(),
+            max_uses,
+            uses: 0,
+            expires_at: lifetime_minutes.map(|minutes| (chrono::Utc::now() + chrono::Duration::minutes(minutes)).timestamp()),
+            revoked: false,
+        }
+    }
+}
+
+/// WARNING: this struct contains secure fields. A refresh token, by the hash of the token itself
+/// (see session.rs)
+#[derive(Debug, Clone)]
+pub struct RefreshToken {
+    pub token_hash: String,
+    pub session: String, // every token rotated from the same login shares this
+    pub handle: String,
+    pub expires_at: i64, // unix timestamp
+    pub used: bool, // already swapped for a new one, seeing it again means it was stolen
+    pub revoked: bool,
+}
+
+/// what's known about a login session, for listing them (see session.rs). Sessions from before
+/// they were recorded have nothing but their id and handle until they're refreshed.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Session {
+    pub id: String, // the `sid` of its access tokens
+    pub handle: String,
+    pub created_at: Option<i64>, // unix timestamp
+    pub last_used_at: Option<i64>, // when it last logged in or refreshed, unix timestamp
+    pub last_ip: Option<String>,
+    pub client: Option<String>, // the User-Agent it last logged in or refreshed with
+}
+
+/// WARNING: this struct contains secure fields. A bot's API key, by the hash of the key itself (see
+/// api_keys.rs)
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ApiKey {
+    pub id: String, // for listing and revoking, the key is never stored
+    #[serde(skip)]
+    pub key_hash: String,
+    pub handle: String, // the bot's
+    pub name: String,
+    #[serde(flatten)]
+    pub scope: Scope,
+    pub created_by: String,
+    pub created_at: i64, // unix timestamp
+}
+
+/// a public SSH key a user logs in with (see ssh_keys.rs)
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SshKey {
+    pub id: String, // for listing and removing
+    pub handle: String,
+    pub key: String, // `ssh-ed25519 AAAA...`, without the comment
+    pub fingerprint: String, // `SHA256:...`, like `ssh-keygen -l` shows
+    pub comment: String, // from the key's line, to tell keys apart
+    pub created_at: i64, // unix timestamp
+}
+
+/// WARNING: this struct contains secure fields. A user's TOTP secret (see two_factor.rs), which
+/// isn't `enabled` until they've confirmed it with a code
+#[derive(Debug, Clone, PartialEq)]
+pub struct TotpEntry {
+    pub secret: String, // base32
+    pub enabled: bool,
+    pub last_step: i64, // the last time step a code was accepted for, so codes can't be replayed
+}
+
+/// a ban, permanent or until `expires_at`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Ban {
+    pub handle: String,
+    pub reason: String,
+    pub banned_by: String,
+    pub created_at: i64, // unix timestamp
+    pub expires_at: Option<i64>, // unix timestamp, None for permanent
 }
+impl Ban {
+    pub fn new(handle: &str, reason: &str, banned_by: &str, duration_minutes: Option<i64>) -> Self {
+        let now = chrono::Utc::now();
+        Ban {
+            handle: handle.to_string(),
+            reason: reason.to_string(),
+            banned_by: banned_by.to_string(),
+            created_at: now.timestamp(),
+            expires_at: duration_minutes.map(|minutes| (now + chrono::Duration::minutes(minutes)).timestamp()),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.expires_at.is_none_or(|at| at > chrono::Utc::now().timestamp())
+    }
+
+    /// what the banned user is told, e.g. `banned until 2026-01-01 12:00 UTC: spam`
+    pub fn describe(&self) -> String {
+        let until = match self.expires_at.and_then(|at| chrono::DateTime::from_timestamp(at, 0)) {
+            Some(at) => format!("banned until {}", at.format("%Y-%m-%d %H:%M UTC")),
+            None => "banned permanently".to_string(),
+        };
+        match self.reason.is_empty() {
+            true => until,
+            false => format!("{}: {}", until, self.reason),
+        }
+    }
+}
+
+/// a mute: the user can still read, but not post. Temporary ones are timeouts.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Mute {
+    pub handle: String,
+    pub reason: String,
+    pub muted_by: String,
+    pub created_at: i64, // unix timestamp
+    pub expires_at: Option<i64>, // unix timestamp, None until unmuted
+}
+impl Mute {
+    pub fn new(handle: &str, reason: &str, muted_by: &str, duration_minutes: Option<i64>) -> Self {
+        let now = chrono::Utc::now();
+        Mute {
+            handle: handle.to_string(),
+            reason: reason.to_string(),
+            muted_by: muted_by.to_string(),
+            created_at: now.timestamp(),
+            expires_at: duration_minutes.map(|minutes| (now + chrono::Duration::minutes(minutes)).timestamp()),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.expires_at.is_none_or(|at| at > chrono::Utc::now().timestamp())
+    }
+
+    /// what the muted user is told when they try to post, e.g. `muted until 2026-01-01 12:00 UTC`
+    pub fn describe(&self) -> String {
+        let until = match self.expires_at.and_then(|at| chrono::DateTime::from_timestamp(at, 0)) {
+            Some(at) => format!("muted until {}", at.format("%Y-%m-%d %H:%M UTC")),
+            None => "muted until a moderator unmutes you".to_string(),
+        };
+        match self.reason.is_empty() {
+            true => until,
+            false => format!("{}: {}", until, self.reason),
+        }
+    }
+}
+
+/// returned by `add_user` when the handle already belongs to someone
+#[derive(Debug)]
+pub struct HandleTaken;
+impl std::fmt::Display for HandleTaken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "that handle is already taken")
+    }
+}
+impl std::error::Error for HandleTaken {}
 
 /// Basic calls for a given database, things like adding and checking users
 pub trait DBCalls {
     fn fetch_user(&self, username: &str) -> impl Future<Output = Result<UserDBEntry, Box<dyn std::error::Error>>>;
+    /// fails with `HandleTaken` if the handle is in use
     fn add_user(&self, new_user: UserDBEntry) -> impl Future<Output = Result<User, Box<dyn std::error::Error>>>;
+    fn set_password_hash(&self, username: &str, password_hash: &str) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    /// take the user's password away and store a reset code (replacing any earlier one) that sets a
+    /// new one until `expires_at`
+    fn start_password_reset(&self, username: &str, code_hash: &str, created_by: &str, expires_at: i64) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    /// use up a reset code, false if it's wrong or expired
+    fn take_password_reset(&self, username: &str, code_hash: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+
+    fn add_invite(&self, invite: &Invite) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    fn fetch_invites(&self) -> impl Future<Output = Result<Vec<Invite>, Box<dyn std::error::Error>>>;
+    /// use up one use of an invite, false if it's unknown, revoked, expired or used up
+    fn redeem_invite(&self, code: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+    /// give back a use taken by `redeem_invite`, for when the registration fails after all
+    fn release_invite(&self, code: &str) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    /// false if there is no such invite
+    fn revoke_invite(&self, code: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+
+    fn add_refresh_token(&self, token: &RefreshToken) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    fn fetch_refresh_token(&self, token_hash: &str) -> impl Future<Output = Result<Option<RefreshToken>, Box<dyn std::error::Error>>>;
+    /// mark a refresh token as used, false if it already was
+    fn use_refresh_token(&self, token_hash: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+    /// revoke every refresh token of a session and remember the session as revoked until `until`
+    /// (unix timestamp), when the last access token it handed out expires
+    fn revoke_session(&self, session: &str, until: i64) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    /// sessions of a user that still have a usable refresh token
+    fn fetch_sessions(&self, handle: &str) -> impl Future<Output = Result<Vec<String>, Box<dyn std::error::Error>>>;
+    /// store a new session, or update when and where an existing one was last used (keeping its
+    /// created_at, and its client if `session.client` is None)
+    fn record_session(&self, session: &Session) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    /// fetch_sessions(), with what's known about each, the most recently used first
+    fn fetch_session_details(&self, handle: &str) -> impl Future<Output = Result<Vec<Session>, Box<dyn std::error::Error>>>;
+    /// revoked sessions that haven't run out yet, forgetting the ones that have
+    fn fetch_revoked_sessions(&self) -> impl Future<Output = Result<Vec<(String, i64)>, Box<dyn std::error::Error>>>;
+
+    /// invalidate every token of a user, returning the new token version
+    fn bump_token_version(&self, username: &str) -> impl Future<Output = Result<u32, Box<dyn std::error::Error>>>;
+
+    fn add_api_key(&self, key: &ApiKey) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    /// the key with this hash, if it hasn't been revoked
+    fn fetch_api_key(&self, key_hash: &str) -> impl Future<Output = Result<Option<ApiKey>, Box<dyn std::error::Error>>>;
+    /// every key of a bot
+    fn fetch_api_keys(&self, handle: &str) -> impl Future<Output = Result<Vec<ApiKey>, Box<dyn std::error::Error>>>;
+    /// false if the bot has no key with that id
+    fn revoke_api_key(&self, handle: &str, id: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+
+    /// start (or restart) setting up TOTP, false if the user already has it enabled
+    fn set_totp_secret(&self, handle: &str, secret: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+    fn fetch_totp(&self, handle: &str) -> impl Future<Output = Result<Option<TotpEntry>, Box<dyn std::error::Error>>>;
+    /// turn TOTP on, replacing the user's recovery codes
+    fn enable_totp(&self, handle: &str, recovery_code_hashes: &[String]) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    /// remember a code's time step as used, false if it (or a later one) already was
+    fn use_totp_step(&self, handle: &str, step: i64) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+    /// turn TOTP off and forget the recovery codes, false if it wasn't set up
+    fn disable_totp(&self, handle: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+    fn replace_recovery_codes(&self, handle: &str, recovery_code_hashes: &[String]) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    /// use up a recovery code, false if the user doesn't have it
+    fn use_recovery_code(&self, handle: &str, code_hash: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+    fn count_recovery_codes(&self, handle: &str) -> impl Future<Output = Result<u32, Box<dyn std::error::Error>>>;
+
+    /// false if the user already has this key
+    fn add_ssh_key(&self, key: &SshKey) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+    fn fetch_ssh_keys(&self, handle: &str) -> impl Future<Output = Result<Vec<SshKey>, Box<dyn std::error::Error>>>;
+    /// false if the user has no key with that id
+    fn remove_ssh_key(&self, handle: &str, id: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+
+    /// the handle an OpenID Connect issuer's user (their `sub`) logs in as, see oidc.rs
+    fn fetch_oidc_link(&self, issuer: &str, subject: &str) -> impl Future<Output = Result<Option<String>, Box<dyn std::error::Error>>>;
+    /// tie a handle to an issuer's user, false if the handle is already tied to another of its users
+    fn add_oidc_link(&self, issuer: &str, subject: &str, handle: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+
+    /// store a ban (replacing any earlier one) and return the banned user
+    fn ban_user(&self, ban: &Ban) -> impl Future<Output = Result<User, Box<dyn std::error::Error>>>;
+    /// false if the user wasn't banned
+    fn unban_user(&self, username: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+    /// the user's ban, if it hasn't expired
+    fn fetch_ban(&self, username: &str) -> impl Future<Output = Result<Option<Ban>, Box<dyn std::error::Error>>>;
+    /// every ban that hasn't expired
+    fn fetch_bans(&self) -> impl Future<Output = Result<Vec<Ban>, Box<dyn std::error::Error>>>;
 
-    #[allow(dead_code)] //TODO
-    fn ban_user(&self, username: &str) -> Result<User, &'static str>;
+    /// store a mute (replacing any earlier one), fails for unknown users
+    fn mute_user(&self, mute: &Mute) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>>;
+    /// false if the user wasn't muted
+    fn unmute_user(&self, username: &str) -> impl Future<Output = Result<bool, Box<dyn std::error::Error>>>;
+    /// every mute that hasn't expired
+    fn fetch_mutes(&self) -> impl Future<Output = Result<Vec<Mute>, Box<dyn std::error::Error>>>;
 
     /// method to set up a given database, the "proper" way to do this would be migrations, but 
     /// this is a more simple aproach.
@@ -6,4 +6,41 @@
 //! team.
 
 pub mod sqlite;
+#[allow(clippy::module_inception)]
 pub mod database;
+
+/// an empty in-memory database for the tests. One connection, every in-memory connection is a
+/// database of its own.
+#[cfg(test)]
+pub fn test_database_config() -> crate::config::DatabaseConfig {
+    crate::config::DatabaseConfig { url: "sqlite::memory:".to_string(), max_connections: 1, ..Default::default() }
+}
+
+#[cfg(test)]
+pub async fn test_db() -> sqlite::db_sqlite::DB_Sqlite {
+    use database::DBCalls;
+
+    let db = sqlite::db_sqlite::DB_Sqlite::new(&test_database_config()).await;
+    db.setup().await;
+    db
+}
+
+/// a user to add to test_db(), with a password hash that never matches
+#[cfg(test)]
+pub fn test_user(handle: &str, permission_level: crate::authentication::user::UserPermissions) -> database::UserDBEntry {
+    use crate::authentication::user::{User, UserMode};
+
+    database::UserDBEntry {
+        password_hash: "hash".to_string(),
+        username: handle.to_string(),
+        inner_user: User {
+            user_type: UserMode::User,
+            permission_level,
+            username: handle.to_string(),
+            handle: handle.to_string(),
+            provider_site: None,
+            banned: false,
+        },
+        token_version: 0,
+    }
+}
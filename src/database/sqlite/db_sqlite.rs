@@ -2,10 +2,12 @@ use std::{str::FromStr, time::Duration};
 
 use super::super::database::DBCalls;
 use sqlx::{Pool, Sqlite, sqlite::{SqliteConnectOptions, SqlitePoolOptions}, Row};
-use crate::database::database::UserDBEntry;
+use crate::database::database::{ApiKey, Ban, HandleTaken, Invite, Mute, RefreshToken, Session, SshKey, TotpEntry, UserDBEntry};
+use crate::authentication::api_keys::Scope;
 use crate::authentication::user::User;
+use crate::config::DatabaseConfig;
 
-pub const DB_DEFAULT_URL: &'static str = "sqlite://database/TRCd.db";
+pub const DB_DEFAULT_URL: &str = "sqlite://database/TRCd.db";
 
 #[allow(non_camel_case_types)] // just because it makes more sense for this struct
 #[derive(Debug, Clone)]
@@ -15,35 +17,127 @@ pub struct DB_Sqlite {
 impl DBCalls for DB_Sqlite {
     async fn add_user(&self, new_user: crate::database::database::UserDBEntry) -> Result<crate::authentication::user::User, Box<dyn std::error::Error>> {
         let user_json = serde_json::to_string(&new_user.inner_user)?;
-        let _result = sqlx::query("INSERT INTO Users (password_hash, username, user_json) VALUES (?, ?, ?)")
+        // checking and inserting in one statement, so two registrations can't both get a handle
+        let result = sqlx::query(
+                "INSERT INTO Users (password_hash, username, user_json, token_version)
+                SELECT ?, ?, ?, ? WHERE NOT EXISTS (SELECT 1 FROM Users WHERE username = ?)"
+            )
             .bind(new_user.password_hash)
-            .bind(new_user.username)
+            .bind(&new_user.username)
             .bind(user_json)
+            .bind(new_user.token_version)
+            .bind(&new_user.username)
             .execute(&self.conn)
             .await?;
+        if result.rows_affected() == 0 {
+            return Err(HandleTaken.into());
+        }
         
         Ok(new_user.inner_user)
     }
     
-    fn ban_user(&self, username: &str) -> Result<crate::authentication::user::User, &'static str> {
-        let _ = username; // to get the lint to shut up
-        todo!()
+    async fn ban_user(&self, ban: &Ban) -> Result<User, Box<dyn std::error::Error>> {
+        let mut user = self.fetch_user(&ban.handle).await?.inner_user;
+
+        sqlx::query("INSERT OR REPLACE INTO Bans (handle, reason, banned_by, created_at, expires_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(&ban.handle)
+            .bind(&ban.reason)
+            .bind(&ban.banned_by)
+            .bind(ban.created_at)
+            .bind(ban.expires_at)
+            .execute(&self.conn)
+            .await?;
+
+        user.banned = ban.is_active();
+        Ok(user)
+    }
+
+    async fn unban_user(&self, username: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("DELETE FROM Bans WHERE handle = ?")
+            .bind(username)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn fetch_ban(&self, username: &str) -> Result<Option<Ban>, Box<dyn std::error::Error>> {
+        let row = sqlx::query("SELECT * FROM Bans WHERE handle = ? AND (expires_at IS NULL OR expires_at > ?)")
+            .bind(username)
+            .bind(chrono::Utc::now().timestamp())
+            .fetch_optional(&self.conn)
+            .await?;
+
+        Ok(row.as_ref().map(ban_from_row))
+    }
+
+    async fn fetch_bans(&self) -> Result<Vec<Ban>, Box<dyn std::error::Error>> {
+        let rows = sqlx::query("SELECT * FROM Bans WHERE expires_at IS NULL OR expires_at > ? ORDER BY created_at")
+            .bind(chrono::Utc::now().timestamp())
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(rows.iter().map(ban_from_row).collect())
+    }
+
+    async fn mute_user(&self, mute: &Mute) -> Result<(), Box<dyn std::error::Error>> {
+        self.fetch_user(&mute.handle).await?;
+
+        sqlx::query("INSERT OR REPLACE INTO Mutes (handle, reason, muted_by, created_at, expires_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(&mute.handle)
+            .bind(&mute.reason)
+            .bind(&mute.muted_by)
+            .bind(mute.created_at)
+            .bind(mute.expires_at)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn unmute_user(&self, username: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("DELETE FROM Mutes WHERE handle = ?")
+            .bind(username)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn fetch_mutes(&self) -> Result<Vec<Mute>, Box<dyn std::error::Error>> {
+        let rows = sqlx::query("SELECT * FROM Mutes WHERE expires_at IS NULL OR expires_at > ? ORDER BY created_at")
+            .bind(chrono::Utc::now().timestamp())
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(rows.iter().map(|row| Mute {
+            handle: row.get("handle"),
+            reason: row.get("reason"),
+            muted_by: row.get("muted_by"),
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+        }).collect())
     }
 
     async fn fetch_user(&self, username: &str) -> Result<UserDBEntry, Box<dyn std::error::Error>> {
         let row = sqlx::query(
-            "SELECT * FROM Users WHERE username = $1",
+            "SELECT *, EXISTS (
+                SELECT 1 FROM Bans WHERE handle = username AND (expires_at IS NULL OR expires_at > $2)
+            ) AS is_banned FROM Users WHERE username = $1",
         )
             .bind(username)
+            .bind(chrono::Utc::now().timestamp())
             .fetch_one(&self.conn)
             .await?;
         
         // extract the user from the entry
-        let user_value: User;
+        let mut user_value: User;
         let user_raw: Option<String> = row.get("user_json");
 
         if let Some(inner_user) = user_raw {
             user_value = serde_json::from_str(&inner_user)?;
+            // the Bans table is what counts, see ban_user()
+            user_value.banned = row.get("is_banned");
         } else {
             return Err("Bad Database Entry, Value \"user_json\" missing. Please contact an admin.".to_string().into())
         }
@@ -56,40 +150,705 @@ impl DBCalls for DB_Sqlite {
 
         // combine elements to make a user DB entry
         let result = UserDBEntry { 
-                password_hash: password_hash,
+                password_hash,
                 username: username.to_string(),
-                inner_user: user_value 
+                inner_user: user_value,
+                token_version: row.get("token_version"),
         };
 
         Ok(result)
     }
 
+    async fn bump_token_version(&self, username: &str) -> Result<u32, Box<dyn std::error::Error>> {
+        let version: u32 = sqlx::query_scalar("UPDATE Users SET token_version = token_version + 1 WHERE username = ? RETURNING token_version")
+            .bind(username)
+            .fetch_one(&self.conn)
+            .await?;
+
+        Ok(version)
+    }
+
+    async fn add_api_key(&self, key: &ApiKey) -> Result<(), Box<dyn std::error::Error>> {
+        let channels = key.scope.channels.as_ref().map(serde_json::to_string).transpose()?;
+        sqlx::query("INSERT INTO ApiKeys (id, key_hash, handle, name, channels, can_post, can_read, created_by, created_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(&key.id)
+            .bind(&key.key_hash)
+            .bind(&key.handle)
+            .bind(&key.name)
+            .bind(channels)
+            .bind(key.scope.post)
+            .bind(key.scope.read)
+            .bind(&key.created_by)
+            .bind(key.created_at)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn fetch_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>, Box<dyn std::error::Error>> {
+        let row = sqlx::query("SELECT * FROM ApiKeys WHERE key_hash = ?")
+            .bind(key_hash)
+            .fetch_optional(&self.conn)
+            .await?;
+
+        row.as_ref().map(api_key_from_row).transpose()
+    }
+
+    async fn fetch_api_keys(&self, handle: &str) -> Result<Vec<ApiKey>, Box<dyn std::error::Error>> {
+        let rows = sqlx::query("SELECT * FROM ApiKeys WHERE handle = ? ORDER BY created_at")
+            .bind(handle)
+            .fetch_all(&self.conn)
+            .await?;
+
+        rows.iter().map(api_key_from_row).collect()
+    }
+
+    async fn revoke_api_key(&self, handle: &str, id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("DELETE FROM ApiKeys WHERE handle = ? AND id = ?")
+            .bind(handle)
+            .bind(id)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn set_totp_secret(&self, handle: &str, secret: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query(
+                "INSERT INTO TwoFactor (handle, secret, enabled, last_step) VALUES (?, ?, 0, 0)
+                ON CONFLICT (handle) DO UPDATE SET secret = excluded.secret, last_step = 0 WHERE enabled = 0"
+            )
+            .bind(handle)
+            .bind(secret)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn fetch_totp(&self, handle: &str) -> Result<Option<TotpEntry>, Box<dyn std::error::Error>> {
+        let row = sqlx::query("SELECT * FROM TwoFactor WHERE handle = ?")
+            .bind(handle)
+            .fetch_optional(&self.conn)
+            .await?;
+
+        Ok(row.map(|row| TotpEntry {
+            secret: row.get("secret"),
+            enabled: row.get("enabled"),
+            last_step: row.get("last_step"),
+        }))
+    }
+
+    async fn enable_totp(&self, handle: &str, recovery_code_hashes: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction = self.conn.begin().await?;
+        sqlx::query("UPDATE TwoFactor SET enabled = 1 WHERE handle = ?")
+            .bind(handle)
+            .execute(&mut *transaction)
+            .await?;
+        insert_recovery_codes(&mut transaction, handle, recovery_code_hashes).await?;
+
+        Ok(transaction.commit().await?)
+    }
+
+    async fn use_totp_step(&self, handle: &str, step: i64) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("UPDATE TwoFactor SET last_step = ? WHERE handle = ? AND last_step < ?")
+            .bind(step)
+            .bind(handle)
+            .bind(step)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn disable_totp(&self, handle: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut transaction = self.conn.begin().await?;
+        let result = sqlx::query("DELETE FROM TwoFactor WHERE handle = ?")
+            .bind(handle)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query("DELETE FROM RecoveryCodes WHERE handle = ?")
+            .bind(handle)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn replace_recovery_codes(&self, handle: &str, recovery_code_hashes: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction = self.conn.begin().await?;
+        insert_recovery_codes(&mut transaction, handle, recovery_code_hashes).await?;
+
+        Ok(transaction.commit().await?)
+    }
+
+    async fn use_recovery_code(&self, handle: &str, code_hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("DELETE FROM RecoveryCodes WHERE handle = ? AND code_hash = ?")
+            .bind(handle)
+            .bind(code_hash)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn count_recovery_codes(&self, handle: &str) -> Result<u32, Box<dyn std::error::Error>> {
+        let count: u32 = sqlx::query_scalar("SELECT COUNT(*) FROM RecoveryCodes WHERE handle = ?")
+            .bind(handle)
+            .fetch_one(&self.conn)
+            .await?;
+
+        Ok(count)
+    }
+
+    async fn add_ssh_key(&self, key: &SshKey) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("INSERT OR IGNORE INTO SshKeys (id, handle, key, fingerprint, comment, created_at) VALUES (?, ?, ?, ?, ?, ?)")
+            .bind(&key.id)
+            .bind(&key.handle)
+            .bind(&key.key)
+            .bind(&key.fingerprint)
+            .bind(&key.comment)
+            .bind(key.created_at)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn fetch_oidc_link(&self, issuer: &str, subject: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let row = sqlx::query("SELECT handle FROM OidcLinks WHERE issuer = ? AND subject = ?")
+            .bind(issuer)
+            .bind(subject)
+            .fetch_optional(&self.conn)
+            .await?;
+
+        Ok(row.map(|row| row.get("handle")))
+    }
+
+    async fn add_oidc_link(&self, issuer: &str, subject: &str, handle: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("INSERT OR IGNORE INTO OidcLinks (issuer, subject, handle, created_at) VALUES (?, ?, ?, ?)")
+            .bind(issuer)
+            .bind(subject)
+            .bind(handle)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn fetch_ssh_keys(&self, handle: &str) -> Result<Vec<SshKey>, Box<dyn std::error::Error>> {
+        let rows = sqlx::query("SELECT * FROM SshKeys WHERE handle = ? ORDER BY created_at")
+            .bind(handle)
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(rows.iter().map(|row| SshKey {
+            id: row.get("id"),
+            handle: row.get("handle"),
+            key: row.get("key"),
+            fingerprint: row.get("fingerprint"),
+            comment: row.get("comment"),
+            created_at: row.get("created_at"),
+        }).collect())
+    }
+
+    async fn remove_ssh_key(&self, handle: &str, id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("DELETE FROM SshKeys WHERE handle = ? AND id = ?")
+            .bind(handle)
+            .bind(id)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+        sqlx::query("UPDATE Users SET password_hash = ? WHERE username = ?")
+            .bind(password_hash)
+            .bind(username)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn start_password_reset(&self, username: &str, code_hash: &str, created_by: &str, expires_at: i64) -> Result<(), Box<dyn std::error::Error>> {
+        let mut transaction = self.conn.begin().await?;
+        sqlx::query("UPDATE Users SET password_hash = '' WHERE username = ?")
+            .bind(username)
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query("INSERT OR REPLACE INTO PasswordResets (handle, code_hash, created_by, expires_at) VALUES (?, ?, ?, ?)")
+            .bind(username)
+            .bind(code_hash)
+            .bind(created_by)
+            .bind(expires_at)
+            .execute(&mut *transaction)
+            .await?;
+
+        Ok(transaction.commit().await?)
+    }
+
+    async fn take_password_reset(&self, username: &str, code_hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("DELETE FROM PasswordResets WHERE handle = ? AND code_hash = ? AND expires_at > ?")
+            .bind(username)
+            .bind(code_hash)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn add_invite(&self, invite: &Invite) -> Result<(), Box<dyn std::error::Error>> {
+        sqlx::query("INSERT INTO Invites (code, created_by, max_uses, uses, expires_at, revoked) VALUES (?, ?, ?, ?, ?, ?)")
+            .bind(&invite.code)
+            .bind(&invite.created_by)
+            .bind(invite.max_uses)
+            .bind(invite.uses)
+            .bind(invite.expires_at)
+            .bind(invite.revoked)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn fetch_invites(&self) -> Result<Vec<Invite>, Box<dyn std::error::Error>> {
+        let rows = sqlx::query("SELECT * FROM Invites ORDER BY rowid")
+            .fetch_all(&self.conn)
+            .await?;
+
+        let invites = rows.iter()
+            .map(|row| Invite {
+                code: row.get("code"),
+                created_by: row.get("created_by"),
+                max_uses: row.get("max_uses"),
+                uses: row.get("uses"),
+                expires_at: row.get("expires_at"),
+                revoked: row.get("revoked"),
+            })
+            .collect();
+
+        Ok(invites)
+    }
+
+    async fn redeem_invite(&self, code: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        // one statement, so the last use of an invite can't be taken twice
+        let result = sqlx::query(
+                "UPDATE Invites SET uses = uses + 1
+                WHERE code = ? AND revoked = 0
+                AND (expires_at IS NULL OR expires_at > ?)
+                AND (max_uses IS NULL OR uses < max_uses)"
+            )
+            .bind(code)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn release_invite(&self, code: &str) -> Result<(), Box<dyn std::error::Error>> {
+        sqlx::query("UPDATE Invites SET uses = uses - 1 WHERE code = ? AND uses > 0")
+            .bind(code)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn revoke_invite(&self, code: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let result = sqlx::query("UPDATE Invites SET revoked = 1 WHERE code = ?")
+            .bind(code)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn add_refresh_token(&self, token: &RefreshToken) -> Result<(), Box<dyn std::error::Error>> {
+        sqlx::query("INSERT INTO RefreshTokens (token_hash, session, handle, expires_at, used, revoked) VALUES (?, ?, ?, ?, ?, ?)")
+            .bind(&token.token_hash)
+            .bind(&token.session)
+            .bind(&token.handle)
+            .bind(token.expires_at)
+            .bind(token.used)
+            .bind(token.revoked)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn fetch_refresh_token(&self, token_hash: &str) -> Result<Option<RefreshToken>, Box<dyn std::error::Error>> {
+        let row = sqlx::query("SELECT * FROM RefreshTokens WHERE token_hash = ?")
+            .bind(token_hash)
+            .fetch_optional(&self.conn)
+            .await?;
+
+        Ok(row.map(|row| RefreshToken {
+            token_hash: row.get("token_hash"),
+            session: row.get("session"),
+            handle: row.get("handle"),
+            expires_at: row.get("expires_at"),
+            used: row.get("used"),
+            revoked: row.get("revoked"),
+        }))
+    }
+
+    async fn use_refresh_token(&self, token_hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        // one statement, so two requests racing with the same token can't both get through
+        let result = sqlx::query("UPDATE RefreshTokens SET used = 1 WHERE token_hash = ? AND used = 0")
+            .bind(token_hash)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn revoke_session(&self, session: &str, until: i64) -> Result<(), Box<dyn std::error::Error>> {
+        sqlx::query("UPDATE RefreshTokens SET revoked = 1 WHERE session = ?")
+            .bind(session)
+            .execute(&self.conn)
+            .await?;
+        sqlx::query("INSERT OR REPLACE INTO RevokedSessions (session, expires_at) VALUES (?, ?)")
+            .bind(session)
+            .bind(until)
+            .execute(&self.conn)
+            .await?;
+        // nothing to list anymore
+        sqlx::query("DELETE FROM Sessions WHERE session = ?")
+            .bind(session)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn fetch_sessions(&self, handle: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let rows = sqlx::query("SELECT DISTINCT session FROM RefreshTokens WHERE handle = ? AND used = 0 AND revoked = 0 AND expires_at > ?")
+            .bind(handle)
+            .bind(chrono::Utc::now().timestamp())
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(rows.iter().map(|row| row.get("session")).collect())
+    }
+
+    async fn record_session(&self, session: &Session) -> Result<(), Box<dyn std::error::Error>> {
+        sqlx::query(
+                "INSERT INTO Sessions (session, handle, created_at, last_used_at, last_ip, client) VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT (session) DO UPDATE SET
+                    last_used_at = excluded.last_used_at,
+                    last_ip = excluded.last_ip,
+                    client = COALESCE(excluded.client, client)",
+            )
+            .bind(&session.id)
+            .bind(&session.handle)
+            .bind(session.created_at)
+            .bind(session.last_used_at)
+            .bind(&session.last_ip)
+            .bind(&session.client)
+            .execute(&self.conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn fetch_session_details(&self, handle: &str) -> Result<Vec<Session>, Box<dyn std::error::Error>> {
+        let rows = sqlx::query(
+                "SELECT RefreshTokens.session, RefreshTokens.handle, created_at, last_used_at, last_ip, client
+                FROM RefreshTokens LEFT JOIN Sessions ON Sessions.session = RefreshTokens.session
+                WHERE RefreshTokens.handle = ? AND used = 0 AND revoked = 0 AND expires_at > ?
+                GROUP BY RefreshTokens.session
+                ORDER BY last_used_at DESC",
+            )
+            .bind(handle)
+            .bind(chrono::Utc::now().timestamp())
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(rows.iter().map(|row| Session {
+            id: row.get("session"),
+            handle: row.get("handle"),
+            created_at: row.get("created_at"),
+            last_used_at: row.get("last_used_at"),
+            last_ip: row.get("last_ip"),
+            client: row.get("client"),
+        }).collect())
+    }
+
+    async fn fetch_revoked_sessions(&self) -> Result<Vec<(String, i64)>, Box<dyn std::error::Error>> {
+        let now = chrono::Utc::now().timestamp();
+        sqlx::query("DELETE FROM RevokedSessions WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&self.conn)
+            .await?;
+        // refresh tokens that can't be used anymore are only dead weight
+        sqlx::query("DELETE FROM RefreshTokens WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&self.conn)
+            .await?;
+        sqlx::query("DELETE FROM Sessions WHERE session NOT IN (SELECT session FROM RefreshTokens)")
+            .execute(&self.conn)
+            .await?;
+
+        let rows = sqlx::query("SELECT session, expires_at FROM RevokedSessions")
+            .fetch_all(&self.conn)
+            .await?;
+
+        Ok(rows.iter().map(|row| (row.get("session"), row.get("expires_at"))).collect())
+    }
+
     async fn setup(&self) {
         sqlx::query(
                 "CREATE TABLE IF NOT EXISTS Users (
                     id INTEGER PRIMARY KEY,
                     password_hash TEXT NOT NULL,
                     username TEXT NOT NULL,
-                    user_json TEXT NOT NULL
+                    user_json TEXT NOT NULL,
+                    token_version INTEGER NOT NULL DEFAULT 0
                 )",
             )
             .execute(&self.conn)
             .await
             .unwrap(); // safe to call unwrap because we need this program to crash if the function fails
+        // databases from before token versions
+        let has_token_version: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pragma_table_info('Users') WHERE name = 'token_version')")
+            .fetch_one(&self.conn)
+            .await
+            .unwrap();
+        if !has_token_version {
+            sqlx::query("ALTER TABLE Users ADD COLUMN token_version INTEGER NOT NULL DEFAULT 0")
+                .execute(&self.conn)
+                .await
+                .unwrap();
+        }
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS Invites (
+                    code TEXT PRIMARY KEY,
+                    created_by TEXT NOT NULL,
+                    max_uses INTEGER,
+                    uses INTEGER NOT NULL DEFAULT 0,
+                    expires_at INTEGER,
+                    revoked INTEGER NOT NULL DEFAULT 0
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS RefreshTokens (
+                    token_hash TEXT PRIMARY KEY,
+                    session TEXT NOT NULL,
+                    handle TEXT NOT NULL,
+                    expires_at INTEGER NOT NULL,
+                    used INTEGER NOT NULL DEFAULT 0,
+                    revoked INTEGER NOT NULL DEFAULT 0
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+        sqlx::query("CREATE INDEX IF NOT EXISTS RefreshTokensBySession ON RefreshTokens (session)")
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS ApiKeys (
+                    id TEXT PRIMARY KEY,
+                    key_hash TEXT NOT NULL UNIQUE,
+                    handle TEXT NOT NULL,
+                    name TEXT NOT NULL,
+                    channels TEXT,
+                    can_post INTEGER NOT NULL,
+                    can_read INTEGER NOT NULL,
+                    created_by TEXT NOT NULL,
+                    created_at INTEGER NOT NULL
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS Bans (
+                    handle TEXT PRIMARY KEY,
+                    reason TEXT NOT NULL,
+                    banned_by TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    expires_at INTEGER
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS Mutes (
+                    handle TEXT PRIMARY KEY,
+                    reason TEXT NOT NULL,
+                    muted_by TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    expires_at INTEGER
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS TwoFactor (
+                    handle TEXT PRIMARY KEY,
+                    secret TEXT NOT NULL,
+                    enabled INTEGER NOT NULL DEFAULT 0,
+                    last_step INTEGER NOT NULL DEFAULT 0
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS RecoveryCodes (
+                    handle TEXT NOT NULL,
+                    code_hash TEXT NOT NULL,
+                    PRIMARY KEY (handle, code_hash)
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS PasswordResets (
+                    handle TEXT PRIMARY KEY,
+                    code_hash TEXT NOT NULL,
+                    created_by TEXT NOT NULL,
+                    expires_at INTEGER NOT NULL
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS SshKeys (
+                    id TEXT PRIMARY KEY,
+                    handle TEXT NOT NULL,
+                    key TEXT NOT NULL,
+                    fingerprint TEXT NOT NULL,
+                    comment TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    UNIQUE (handle, key)
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS OidcLinks (
+                    issuer TEXT NOT NULL,
+                    subject TEXT NOT NULL,
+                    handle TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    PRIMARY KEY (issuer, subject),
+                    UNIQUE (issuer, handle)
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS Sessions (
+                    session TEXT PRIMARY KEY,
+                    handle TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    last_used_at INTEGER NOT NULL,
+                    last_ip TEXT,
+                    client TEXT
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+
+        sqlx::query(
+                "CREATE TABLE IF NOT EXISTS RevokedSessions (
+                    session TEXT PRIMARY KEY,
+                    expires_at INTEGER NOT NULL
+                )",
+            )
+            .execute(&self.conn)
+            .await
+            .unwrap();
+    }
+}
+
+/// replace a user's recovery codes, inside a transaction
+async fn insert_recovery_codes(transaction: &mut sqlx::Transaction<'_, Sqlite>, handle: &str, code_hashes: &[String]) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM RecoveryCodes WHERE handle = ?")
+        .bind(handle)
+        .execute(&mut **transaction)
+        .await?;
+    for code_hash in code_hashes {
+        sqlx::query("INSERT INTO RecoveryCodes (handle, code_hash) VALUES (?, ?)")
+            .bind(handle)
+            .bind(code_hash)
+            .execute(&mut **transaction)
+            .await?;
+    }
+
+    Ok(())
+}
+
+fn api_key_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<ApiKey, Box<dyn std::error::Error>> {
+    let channels: Option<String> = row.get("channels");
+    Ok(ApiKey {
+        id: row.get("id"),
+        key_hash: row.get("key_hash"),
+        handle: row.get("handle"),
+        name: row.get("name"),
+        scope: Scope {
+            channels: channels.as_deref().map(serde_json::from_str).transpose()?,
+            post: row.get("can_post"),
+            read: row.get("can_read"),
+        },
+        created_by: row.get("created_by"),
+        created_at: row.get("created_at"),
+    })
+}
+
+fn ban_from_row(row: &sqlx::sqlite::SqliteRow) -> Ban {
+    Ban {
+        handle: row.get("handle"),
+        reason: row.get("reason"),
+        banned_by: row.get("banned_by"),
+        created_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
     }
 }
 
 impl DB_Sqlite {
-    pub async fn new(db_url: &str) -> Self {
+    pub async fn new(config: &DatabaseConfig) -> Self {
         // this function will call unwrap() a few times, this is safe because we want the app to
         // fail if anything here doesn't do what is expected.
         let conn = SqlitePoolOptions::new()
-            .max_connections(20)
-            .idle_timeout(Duration::from_secs(60))
-            .acquire_timeout(Duration::from_secs(5))
+            .max_connections(config.max_connections)
+            .idle_timeout(Duration::from_secs(config.idle_timeout_seconds))
+            .acquire_timeout(Duration::from_secs(config.acquire_timeout_seconds))
             .connect_with(
-                SqliteConnectOptions::from_str(db_url)
-                    .unwrap()
+                SqliteConnectOptions::from_str(&config.url)
+                    .unwrap_or_else(|e| panic!("invalid database url \"{}\": {}", config.url, e))
                     .create_if_missing(true)
                     .journal_mode(sqlx::sqlite::SqliteJournalMode::Delete)
             )
@@ -98,7 +857,98 @@ impl DB_Sqlite {
         
 
         DB_Sqlite {  
-            conn: conn
+            conn
         }
     } 
+
+    /// wait for running queries and close every connection, so nothing is left half written
+    pub async fn close(&self) {
+        self.conn.close().await;
+    }
+}
+
+#[tokio::test]
+async fn test_invites_and_handles() {
+    use crate::authentication::user::UserPermissions;
+    use crate::database::{test_db, test_user};
+
+    let db = test_db().await;
+
+    let invite = Invite::new("admin", Some(2), Some(60));
+    db.add_invite(&invite).await.unwrap();
+    assert!(db.redeem_invite(&invite.code).await.unwrap());
+    assert!(db.redeem_invite(&invite.code).await.unwrap());
+    assert!(!db.redeem_invite(&invite.code).await.unwrap(), "a used up invite shouldn't work");
+    db.release_invite(&invite.code).await.unwrap();
+    assert!(db.redeem_invite(&invite.code).await.unwrap(), "a released use should be usable again");
+
+    let unlimited = Invite::new("admin", None, None);
+    db.add_invite(&unlimited).await.unwrap();
+    assert!(db.revoke_invite(&unlimited.code).await.unwrap());
+    assert!(!db.redeem_invite(&unlimited.code).await.unwrap(), "a revoked invite shouldn't work");
+
+    let expired = Invite { expires_at: Some(chrono::Utc::now().timestamp() - 1), ..Invite::new("admin", None, None) };
+    db.add_invite(&expired).await.unwrap();
+    assert!(!db.redeem_invite(&expired.code).await.unwrap(), "an expired invite shouldn't work");
+    assert!(!db.redeem_invite("not an invite").await.unwrap());
+    assert_eq!(db.fetch_invites().await.unwrap().len(), 3);
+
+    db.add_user(test_user("alice", UserPermissions::User)).await.unwrap();
+    assert!(db.add_user(test_user("alice", UserPermissions::User)).await.unwrap_err().is::<HandleTaken>(), "handles should be unique");
+}
+
+#[tokio::test]
+async fn test_bans() {
+    use crate::authentication::user::UserPermissions;
+    use crate::database::{test_db, test_user};
+
+    let db = test_db().await;
+    db.add_user(test_user("bob", UserPermissions::User)).await.unwrap();
+
+    assert!(db.ban_user(&Ban::new("nobody", "", "admin", None)).await.is_err(), "unknown users can't be banned");
+
+    let ban = Ban::new("bob", "spam", "admin", Some(60));
+    assert!(db.ban_user(&ban).await.unwrap().banned);
+    assert!(db.fetch_user("bob").await.unwrap().inner_user.banned, "fetch_user should see the ban");
+    assert_eq!(db.fetch_ban("bob").await.unwrap(), Some(ban));
+
+    // a ban that ran out replaces the active one
+    let expired = Ban { expires_at: Some(chrono::Utc::now().timestamp() - 1), ..Ban::new("bob", "", "admin", None) };
+    db.ban_user(&expired).await.unwrap();
+    assert!(!db.fetch_user("bob").await.unwrap().inner_user.banned, "expired bans shouldn't count");
+    assert_eq!(db.fetch_ban("bob").await.unwrap(), None);
+    assert!(db.fetch_bans().await.unwrap().is_empty());
+
+    assert!(db.unban_user("bob").await.unwrap());
+    assert!(!db.unban_user("bob").await.unwrap());
+
+    // mutes work the same way, without touching the user
+    assert!(db.mute_user(&Mute::new("nobody", "", "admin", None)).await.is_err(), "unknown users can't be muted");
+    let mute = Mute::new("bob", "flooding", "admin", Some(10));
+    db.mute_user(&mute).await.unwrap();
+    assert_eq!(db.fetch_mutes().await.unwrap(), vec![mute]);
+    assert!(db.unmute_user("bob").await.unwrap());
+    assert!(db.fetch_mutes().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_password_resets() {
+    use crate::authentication::user::UserPermissions;
+    use crate::database::{test_db, test_user};
+
+    let db = test_db().await;
+    db.add_user(test_user("carol", UserPermissions::User)).await.unwrap();
+
+    let expires_at = chrono::Utc::now().timestamp() + 60;
+    db.start_password_reset("carol", "code hash", "admin", expires_at).await.unwrap();
+    assert!(db.fetch_user("carol").await.unwrap().password_hash.is_empty(), "the old password should stop working");
+    assert!(!db.take_password_reset("carol", "another hash").await.unwrap());
+    assert!(db.take_password_reset("carol", "code hash").await.unwrap());
+    assert!(!db.take_password_reset("carol", "code hash").await.unwrap(), "codes should only work once");
+
+    db.start_password_reset("carol", "old code", "admin", expires_at).await.unwrap();
+    db.start_password_reset("carol", "new code", "admin", expires_at).await.unwrap();
+    assert!(!db.take_password_reset("carol", "old code").await.unwrap(), "a new reset should replace the old code");
+    db.start_password_reset("carol", "expired", "admin", chrono::Utc::now().timestamp() - 1).await.unwrap();
+    assert!(!db.take_password_reset("carol", "expired").await.unwrap(), "expired codes shouldn't work");
 }
@@ -1,29 +1,129 @@
-use tokio;
-
-#[forbid(unsafe_code)]
+#![forbid(unsafe_code)]
 
 mod backend;
 mod authentication;
 mod database;
+mod config;
 
-use backend::socket_server; // backend server instantiated by socket becuse shared state
+use backend::server;
+use config::Config;
 
 use crate::database::database::DBCalls; 
                                        
 
 #[tokio::main]
 async fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() <= 1 {serve().await}
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    // `--config <path>` can go anywhere, see config.rs
+    let config_path = match args.iter().position(|a| a == "--config") {
+        Some(i) if i + 1 < args.len() => Some(std::path::PathBuf::from(args.drain(i..=i + 1).nth(1).unwrap())),
+        Some(_) => {
+            eprintln!("--config needs a path");
+            std::process::exit(1);
+        },
+        None => None,
+    };
+    let config = Config::load(config_path).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+
+    if args.is_empty() {return serve(config).await}
     // otherwise the bin is being run to manipulate entries
     
-    new_user().await;
+    match args[0].as_str() {
+        "invite" => invite(&config, &args[1..]).await,
+        "reset-password" => reset_password(&config, &args[1..]).await,
+        _ => new_user(&config).await,
+    }
 }
 
-async fn new_user() {
-    use crate::database::sqlite::db_sqlite::{DB_Sqlite, DB_DEFAULT_URL};
+/// `trcd invite [uses] [expires in minutes]`, `trcd invite list` and `trcd invite revoke <code>`,
+/// for minting invites before there's an admin to do it over the api
+async fn invite(config: &Config, args: &[String]) {
+    use crate::database::sqlite::db_sqlite::DB_Sqlite;
+    use crate::database::database::{Invite, INVITE_LIFETIME_MINUTES, MAX_INVITE_LIFETIME_MINUTES};
+
+    fn fail(message: &str) -> ! {
+        eprintln!("{}", message);
+        eprintln!("usage: trcd invite [uses (0 for unlimited)] [expires in minutes (0 for never)] | trcd invite list | trcd invite revoke <code>");
+        std::process::exit(1);
+    }
+
+    let connection = DB_Sqlite::new(&config.database).await;
+    connection.setup().await;
+
+    match args.first().map(String::as_str) {
+        Some("list") => {
+            let invites = connection.fetch_invites().await.unwrap_or_else(|e| fail(&e.to_string()));
+            for invite in invites {
+                let uses = invite.max_uses.map_or("unlimited".to_string(), |max| max.to_string());
+                let expires = invite.expires_at
+                    .and_then(|at| chrono::DateTime::from_timestamp(at, 0))
+                    .map_or("never".to_string(), |at| at.to_rfc3339());
+                let revoked = if invite.revoked {" (revoked)"} else {""};
+                println!("{}  used {}/{}  expires {}  by @{}{}", invite.code, invite.uses, uses, expires, invite.created_by, revoked);
+            }
+        },
+        Some("revoke") => {
+            let code = args.get(1).unwrap_or_else(|| fail("revoke needs a code"));
+            match connection.revoke_invite(code).await {
+                Ok(true) => println!("revoked {}", code),
+                Ok(false) => fail("no invite with that code"),
+                Err(e) => fail(&e.to_string()),
+            }
+        },
+        _ => {
+            let uses: u32 = args.first().map_or(Ok(1), |v| v.parse()).unwrap_or_else(|_| fail("uses has to be a number"));
+            let minutes: i64 = args.get(1).map_or(Ok(INVITE_LIFETIME_MINUTES), |v| v.parse())
+                .ok().filter(|m| (0..=MAX_INVITE_LIFETIME_MINUTES).contains(m))
+                .unwrap_or_else(|| fail(&format!("expiry has to be a number of minutes, at most {}", MAX_INVITE_LIFETIME_MINUTES)));
+
+            let invite = Invite::new("console", (uses > 0).then_some(uses), (minutes > 0).then_some(minutes));
+            connection.add_invite(&invite).await.unwrap_or_else(|e| fail(&e.to_string()));
+            println!("{}", invite.code);
+        },
+    }
+}
+
+/// `trcd reset-password <handle>`, for when there's no admin to do it over the api (or it's the
+/// admin who forgot theirs). Prints the one-time code to set a new password with.
+async fn reset_password(config: &Config, args: &[String]) {
+    use crate::database::sqlite::db_sqlite::DB_Sqlite;
+    use crate::authentication::passwords::{self, RESET_CODE_LIFETIME_HOURS};
+
+    let Some(handle) = args.first() else {
+        eprintln!("usage: trcd reset-password <handle>");
+        std::process::exit(1);
+    };
+
+    // logging the user out revokes their tokens, which needs the token settings
+    if let Err(e) = authentication::token::configure(&config.auth) {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    }
+    let connection = DB_Sqlite::new(&config.database).await;
+    connection.setup().await;
+
+    match passwords::reset(&connection, handle, "console").await {
+        Ok(code) => {
+            println!("{}", code);
+            eprintln!("@{} can set a new password with this code at /api/password-reset within {} hours", handle, RESET_CODE_LIFETIME_HOURS);
+        },
+        Err(e) => {
+            eprintln!("unable to reset the password: {}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+async fn new_user(config: &Config) {
+    use crate::database::sqlite::db_sqlite::DB_Sqlite;
     use crate::database::database::UserDBEntry;
     use crate::authentication::user::{User, UserPermissions, UserMode};
+    use crate::authentication::credentials::{validate_handle, validate_password};
+    use crate::authentication::passwords::Passwords;
     
     println!("Creating a new user, enter details below:");
 
@@ -38,13 +138,22 @@ async fn new_user() {
         input.trim().to_string()
     }
 
-    let connection = DB_Sqlite::new(DB_DEFAULT_URL).await;
+    let connection = DB_Sqlite::new(&config.database).await;
     connection.setup().await;
     
     let username = user_input("Enter a dank handle (@`your_handle_here`) >");
-    let password = bcrypt::hash(user_input("Enter a secure password >"), bcrypt::DEFAULT_COST).unwrap();
+    if let Err(e) = validate_handle(&username) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let password = user_input("Enter a secure password >");
+    if let Err(e) = validate_password(&password, &username) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let password = Passwords::new(&config.auth).hash(&password).unwrap();
     let new_user = UserDBEntry {
-        password_hash: password, //TODO: hash passwords for security
+        password_hash: password,
         username: username.clone(),
         inner_user: User {
             user_type: UserMode::User,
@@ -53,21 +162,30 @@ async fn new_user() {
             permission_level: UserPermissions::User,
             banned: false,
             provider_site: Some(user_input("Link a website? >"))
-        }
+        },
+        token_version: 0,
     };
     
-    connection.add_user(new_user).await.unwrap();
+    if let Err(e) = connection.add_user(new_user).await {
+        eprintln!("unable to create the user: {}", e);
+        std::process::exit(1);
+    }
     println!("successfully created a new user.")
 }
 
-async fn serve() {
+async fn serve(config: Config) {
     env_logger::builder()
         .filter_level(log::LevelFilter::Info)
         .init();
+
+    if let Err(e) = authentication::token::configure(&config.auth) {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    }
     
-    let socks = socket_server::SocketServer::new(3001);
-    // bizzarly, I have to start the api from the socket server because of some shared state. It's
-    // weird. (see create_app()). This is to share a broadcast tx instance to the API so that users
-    // can send new messages in a more "secure" fassion.
-    socks.run().await;
+    // the API and the socket server share one state (see create_state()) on every bound address
+    if let Err(e) = server::Server::new(config).run().await {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
 }